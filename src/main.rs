@@ -1,22 +1,36 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::error::Error;
 use std::env;
 use std::io::{self, Write};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+use std::thread;
+use std::sync::OnceLock;
+use std::process;
 use std::cmp;
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use chrono::{DateTime, Local};
-use term_size;
-use atty;
 
 #[derive(Debug)]
 struct Config {
     max_depth: Option<usize>,
+    // --filelimit: directories holding more entries than this are not opened.
+    file_limit: Option<usize>,
     show_hidden: bool,
     root_path: String,
     sort_by: SortBy,
     pattern: Option<Regex>,
+    glob: Option<GlobSet>,
+    // Every -P wildcard given so far, rebuilt into `glob` as more are added.
+    glob_patterns: Vec<String>,
+    ignore: Option<GlobSet>,
+    // Every -I wildcard given so far, rebuilt into `ignore` as more are added.
+    ignore_patterns: Vec<String>,
+    // --ignore-dir: names of directories never listed nor walked.
+    ignore_dirs: Vec<String>,
     show_size: bool,
     display_mode: DisplayMode,
     classify: Classify,
@@ -31,6 +45,92 @@ struct Config {
     screen_width: Option<usize>,
     sort_across: bool,
     recurse: bool,
+    threads: Option<usize>,
+    io_concurrency: Option<usize>,
+    one_file_system: bool,
+    show_mounts: bool,
+    follow: bool,
+    dereference_roots: bool,
+    resolve_links: bool,
+    files_only: bool,
+    dirs_only: bool,
+    entry_counts: bool,
+    show_empty: bool,
+    empty_badge: String,
+    hidden_only: bool,
+    show_dot_entries: bool,
+    raw_sizes: bool,
+    // Set by GNU tree's -h, which wins over -s regardless of order.
+    human_sizes: bool,
+    reverse_sort: bool,
+    dir_order: DirOrder,
+    format: Option<Vec<FormatPiece<FormatField>>>,
+    template: Option<Template>,
+    where_expr: Option<Expr>,
+    // --type: entries of any of these kinds are listed.
+    types: Vec<TypeFilter>,
+    // --ext: files with any of these extensions are listed, compared in lowercase.
+    extensions: Vec<String>,
+    // --user and --group, resolved to ids.
+    user: Option<u32>,
+    group: Option<u32>,
+    columns: Vec<CommandColumn>,
+    exec: Option<ExecAction>,
+    dry_run: bool,
+    group_by: Option<GroupBy>,
+    recent: Option<usize>,
+    disk_usage: bool,
+    percent: bool,
+    bars: Option<BarScale>,
+    biggest: Option<usize>,
+    ncdu_export: Option<String>,
+    du_format: bool,
+    html: bool,
+    base_href: Option<String>,
+    xml: bool,
+    ndjson: bool,
+    yaml: bool,
+    print0: bool,
+    dot: bool,
+    mermaid: bool,
+    // Longest --mermaid label before it is shortened; 0 keeps labels whole.
+    mermaid_label_width: usize,
+    output_format: Option<OutputFormat>,
+    svg: Option<String>,
+    org: bool,
+    plantuml: Option<PlantUmlStyle>,
+    export_script: bool,
+    export_perms: bool,
+    from_json: Option<String>,
+    from_file: bool,
+    json: Option<JsonSchema>,
+    verify_manifest: Option<String>,
+    hash_manifest: bool,
+    check: Option<String>,
+    show_compression: bool,
+    reflinks: bool,
+    show_volume: bool,
+    show_subvolumes: bool,
+    show_flags: bool,
+    show_owner: bool,
+    show_acl: bool,
+    acl_summary: bool,
+    show_capabilities: bool,
+    show_filesystem: bool,
+    // Filled in once the tree is scanned.
+    scale_range: Option<ScaleRange>,
+    treeignore: bool,
+    gitignore: bool,
+    prune_unmatched: bool,
+    prune: bool,
+    full_path: bool,
+    match_dirs: bool,
+    ignore_case: bool,
+    color_scale_time: ScaleTime,
+    trace: bool,
+    timeout: Option<std::time::Duration>,
+    virtual_fs: VirtualFs,
+    charset: Charset,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -38,6 +138,18 @@ enum SortBy {
     Name,
     Size,
     ModTime,
+    Version,
+    Extension,
+    // Directory order as read, with no sorting at all.
+    None,
+}
+
+/// Where directories go among the entries of a directory, whatever the sort key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DirOrder {
+    Mixed,
+    First,
+    Last,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -46,6 +158,45 @@ enum DisplayMode {
     Long,
     Grid,
     Tree,
+    Flat,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum GroupBy {
+    Extension,
+    Type,
+    Owner,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BarScale {
+    Directory,
+    Global,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum JsonSchema {
+    // This tree's own `--json` document, which `--from-json` reads back.
+    Native,
+    // GNU tree's `-J` output, field for field.
+    Tree,
+}
+
+/// Formats for `--output-format`.
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Csv,
+    Tsv,
+    // Nested bullet lists.
+    Markdown,
+    // The tree drawn in a fenced code block.
+    MarkdownTree,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PlantUmlStyle {
+    Wbs,
+    MindMap,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -73,6 +224,59 @@ enum ColorScale {
 enum ColorScaleMode {
     Fixed,
     Gradient,
+    // A gradient stretched over the ages and sizes present in the listing.
+    Relative,
+}
+
+/// How directories on pseudo filesystems like /proc and /sys are read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VirtualFs {
+    Include,
+    Skip,
+    // Listed from the directory alone, without reading any metadata.
+    NamesOnly,
+}
+
+/// Characters the tree's branches are drawn with.
+#[derive(Debug, Clone, Copy)]
+enum Charset {
+    Utf8,
+    // GNU tree's `|--` and `` `-- ``, for terminals and logs without box drawing characters.
+    Ascii,
+}
+
+impl Charset {
+    fn connector(self, is_last: bool) -> &'static str {
+        match (self, is_last) {
+            (Charset::Utf8, false) => "├── ",
+            (Charset::Utf8, true) => "└── ",
+            (Charset::Ascii, false) => "|-- ",
+            (Charset::Ascii, true) => "`-- ",
+        }
+    }
+
+    fn indent(self, is_last: bool) -> &'static str {
+        match (self, is_last) {
+            (_, true) => "    ",
+            (Charset::Utf8, false) => "│   ",
+            (Charset::Ascii, false) => "|   ",
+        }
+    }
+}
+
+/// Which timestamp the age color scale reads.
+#[derive(Debug, Clone, Copy)]
+enum ScaleTime {
+    Modified,
+    Accessed,
+    Created,
+}
+
+/// The range of ages (in seconds) and sizes in the listing, for `--color-scale-mode relative`.
+#[derive(Debug, Clone, Copy)]
+struct ScaleRange {
+    age: (u64, u64),
+    size: (u64, u64),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -89,728 +293,7690 @@ enum AbsolutePathOption {
     Off,
 }
 
+#[derive(Default)]
 struct TreeStats {
     directories: usize,
+    // Every entry that is not a directory, as GNU tree counts them; the kinds below are
+    // included in it.
     files: usize,
     total_size: u64,
+    symlinks: usize,
+    broken_links: usize,
+    sockets: usize,
+    fifos: usize,
+    devices: usize,
+    // Directories that could not be read.
+    errors: usize,
 }
 
 struct FileInfo {
     path: PathBuf,
     size: u64,
     mod_time: SystemTime,
-    file_type: fs::FileType,
+    accessed: Option<SystemTime>,
+    // Birth time, which not every platform and filesystem records.
+    created: Option<SystemTime>,
+    file_type: FileKind,
+    // True for directories and, with --follow, symlinks to directories.
+    is_dir: bool,
+    mode: u32,
+    // Bytes allocated on disk, which differs from `size` for sparse or compressed files.
+    disk_size: u64,
+    // (uid, gid) where the platform has them.
+    owner: Option<(u32, u32)>,
+    // BSD file flags set with chflags(1), zero elsewhere.
+    flags: u32,
+    // Whether an ACL grants more than the permission bits show, with --acl.
+    has_acl: bool,
+    // Whether Linux file capabilities are attached, with --capabilities.
+    has_capabilities: bool,
+    // A symlink whose target does not exist.
+    broken_link: bool,
+    // A directory whose contents could not be read.
+    unreadable: bool,
+    // Listed by name only from a pseudo filesystem, so its size, times and mode are unknown.
+    virtual_fs: bool,
+    // Distance from the root, whose entries are at depth 1.
+    depth: usize,
+    link_target: Option<String>,
+    notes: Vec<String>,
+    // Name shown instead of the file name, used for the `.` and `..` entries of `-a`.
+    label: Option<&'static str>,
+    // Fraction of the parent directory's size, with --percent.
+    share: Option<f64>,
+    // Filled fraction of the --bars graph.
+    bar: Option<f64>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-    
-    let config = parse_args(&args)?;
-
-    let path = Path::new(&config.root_path);
-    let mut stats = TreeStats { directories: 0, files: 0, total_size: 0 };
-    
-    match config.display_mode {
-        DisplayMode::OneLine => {
-            let entries = collect_entries(path, &config, &mut stats)?;
-            print_entries_oneline(&entries, &config)?;
-        },
-        DisplayMode::Long => {
-            let entries = collect_entries(path, &config, &mut stats)?;
-            print_entries_long(&entries, &config)?;
-        },
-        DisplayMode::Grid => {
-            let entries = collect_entries(path, &config, &mut stats)?;
-            print_entries_grid(&entries, &config)?;
-        },
-        DisplayMode::Tree => {
-            println!("{}", path.display());
-            print_tree(path, 0, &config, &mut stats)?;
-        },
-    }
-
-    // Print summary
-    let summary = format!("\n{} directories, {} files", stats.directories, stats.files);
-    let total_size = format!("Total size: {}", format_size(stats.total_size));
-    
-    // Apply color to summary if enabled
-    let (summary, total_size) = if matches!(config.color, ColorOption::Always | ColorOption::Auto) && atty::is(atty::Stream::Stdout) {
-        (
-            format!("\x1B[1;34m{}\x1B[0m", summary),
-            format!("\x1B[1;32m{}\x1B[0m", total_size)
-        )
-    } else {
-        (summary, total_size)
-    };
-
-    println!("{}", summary);
-    println!("{}", total_size);
+fn file_info(path: PathBuf, metadata: &fs::Metadata, depth: usize) -> io::Result<FileInfo> {
+    Ok(FileInfo {
+        path,
+        size: metadata.len(),
+        mod_time: metadata.modified()?,
+        accessed: metadata.accessed().ok(),
+        created: metadata.created().ok(),
+        file_type: FileKind::of(metadata.file_type()),
+        is_dir: metadata.is_dir(),
+        mode: file_mode(metadata),
+        disk_size: disk_usage(metadata),
+        owner: file_owner(metadata),
+        flags: file_flags(metadata),
+        has_acl: false,
+        has_capabilities: false,
+        broken_link: false,
+        unreadable: false,
+        virtual_fs: false,
+        depth,
+        link_target: None,
+        notes: Vec::new(),
+        label: None,
+        share: None,
+        bar: None,
+    })
+}
 
-    Ok(())
+/// What kind of filesystem object an entry is. Unlike `fs::FileType` it can also be
+/// built for entries loaded from a stored scan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+    Other,
 }
 
-fn parse_args(args: &[String]) -> Result<Config, Box<dyn Error>> {
-    let mut config = Config {
-        max_depth: None,
-        show_hidden: false,
-        root_path: String::from("."),
-        sort_by: SortBy::Name,
-        pattern: None,
-        show_size: false,
-        display_mode: DisplayMode::Tree, // Changed default to Tree
-        classify: Classify::Auto,
-        dereference: false,
-        color: ColorOption::Auto,
-        color_scale: None,
-        color_scale_mode: ColorScaleMode::Fixed,
-        icons: IconOption::Auto,
-        quote_names: true,
-        hyperlink: false,
-        absolute_path: AbsolutePathOption::Off,
-        screen_width: None,
-        sort_across: false,
-        recurse: false,
-    };
+impl FileKind {
+    fn of(file_type: fs::FileType) -> FileKind {
+        if file_type.is_dir() {
+            return FileKind::Directory;
+        } else if file_type.is_symlink() {
+            return FileKind::Symlink;
+        } else if file_type.is_file() {
+            return FileKind::File;
+        }
 
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--max-depth" => {
-                i += 1;
-                if i < args.len() {
-                    config.max_depth = Some(args[i].parse()?);
-                } else {
-                    return Err("--max-depth requires a value".into());
-                }
-            }
-            "--show-hidden" => config.show_hidden = true,
-            "--sort" => {
-                i += 1;
-                if i < args.len() {
-                    config.sort_by = match args[i].as_str() {
-                        "name" => SortBy::Name,
-                        "size" => SortBy::Size,
-                        "time" => SortBy::ModTime,
-                        _ => return Err("Invalid sort option".into()),
-                    };
-                } else {
-                    return Err("--sort requires a value".into());
-                }
-            }
-            "--pattern" => {
-                i += 1;
-                if i < args.len() {
-                    config.pattern = Some(Regex::new(&args[i])?);
-                } else {
-                    return Err("--pattern requires a value".into());
-                }
-            }
-            "--show-size" => config.show_size = true,
-            "-1" | "--oneline" => config.display_mode = DisplayMode::OneLine,
-            "-l" | "--long" => config.display_mode = DisplayMode::Long,
-            "-G" | "--grid" => config.display_mode = DisplayMode::Grid,
-            "-T" | "--tree" => config.display_mode = DisplayMode::Tree,
-            "-X" | "--dereference" => config.dereference = true,
-            "-F" | "--classify" => {
-                i += 1;
-                if i < args.len() {
-                    config.classify = match args[i].as_str() {
-                        "always" => Classify::Always,
-                        "auto" => Classify::Auto,
-                        "never" => Classify::Never,
-                        _ => return Err("Invalid classify option".into()),
-                    };
-                } else {
-                    return Err("--classify requires a value".into());
-                }
-            }
-            "--color" | "--colour" => {
-                i += 1;
-                if i < args.len() {
-                    config.color = match args[i].as_str() {
-                        "always" => ColorOption::Always,
-                        "auto" => ColorOption::Auto,
-                        "never" => ColorOption::Never,
-                        _ => return Err("Invalid color option".into()),
-                    };
-                } else {
-                    return Err("--color requires a value".into());
-                }
-            }
-            "--color-scale" | "--colour-scale" => {
-                i += 1;
-                if i < args.len() {
-                    config.color_scale = Some(match args[i].as_str() {
-                        "all" => ColorScale::All,
-                        "age" => ColorScale::Age,
-                        "size" => ColorScale::Size,
-                        _ => return Err("Invalid color scale option".into()),
-                    });
-                } else {
-                    return Err("--color-scale requires a value".into());
-                }
-            }
-            "--color-scale-mode" | "--colour-scale-mode" => {
-                i += 1;
-                if i < args.len() {
-                    config.color_scale_mode = match args[i].as_str() {
-                        "fixed" => ColorScaleMode::Fixed,
-                        "gradient" => ColorScaleMode::Gradient,
-                        _ => return Err("Invalid color scale mode".into()),
-                    };
-                } else {
-                    return Err("--color-scale-mode requires a value".into());
-                }
-            }
-            "--icons" => {
-                i += 1;
-                if i < args.len() {
-                    config.icons = match args[i].as_str() {
-                        "always" => IconOption::Always,
-                        "auto" => IconOption::Auto,
-                        "never" => IconOption::Never,
-                        _ => return Err("Invalid icons option".into()),
-                    };
-                } else {
-                    return Err("--icons requires a value".into());
-                }
-            }
-            "--no-quotes" => config.quote_names = false,
-            "--hyperlink" => config.hyperlink = true,
-            "--absolute" => {
-                i += 1;
-                if i < args.len() {
-                    config.absolute_path = match args[i].as_str() {
-                        "on" => AbsolutePathOption::On,
-                        "follow" => AbsolutePathOption::Follow,
-                        "off" => AbsolutePathOption::Off,
-                        _ => return Err("Invalid absolute path option".into()),
-                    };
-                } else {
-                    return Err("--absolute requires a value".into());
-                }
-            }
-            "-w" | "--width" => {
-                i += 1;
-                if i < args.len() {
-                    config.screen_width = Some(args[i].parse()?);
-                } else {
-                    return Err("--width requires a value".into());
-                }
-            }
-            "-x" | "--across" => config.sort_across = true,
-            "-R" | "--recurse" => config.recurse = true,
-            _ => {
-                config.root_path = args[i].clone();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return FileKind::Fifo;
+            } else if file_type.is_socket() {
+                return FileKind::Socket;
+            } else if file_type.is_char_device() {
+                return FileKind::CharDevice;
+            } else if file_type.is_block_device() {
+                return FileKind::BlockDevice;
             }
         }
-        i += 1;
+        FileKind::Other
     }
 
-    Ok(config)
-}
-
-
-fn collect_entries(path: &Path, config: &Config, stats: &mut TreeStats) -> io::Result<Vec<FileInfo>> {
-    let mut entries = Vec::new();
-
-    if path.is_dir() {
-        stats.directories += 1;
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if !config.show_hidden && is_hidden(&path) {
-                continue;
-            }
-
-            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
-            if let Some(pattern) = &config.pattern {
-                if !pattern.is_match(&file_name) && !path.is_dir() {
-                    continue;
-                }
-            }
-
-            let metadata = if config.dereference {
-                fs::metadata(&path)?
-            } else {
-                entry.metadata()?
-            };
-
-            let file_info = FileInfo {
-                path: get_display_path(&path, config),
-                size: metadata.len(),
-                mod_time: metadata.modified()?,
-                file_type: metadata.file_type(),
-            };
+    fn is_dir(self) -> bool {
+        self == FileKind::Directory
+    }
 
-            stats.total_size += file_info.size;
+    fn is_file(self) -> bool {
+        self == FileKind::File
+    }
 
-            if path.is_file() {
-                stats.files += 1;
-            }
+    fn is_symlink(self) -> bool {
+        self == FileKind::Symlink
+    }
+}
 
-            entries.push(file_info);
+/// What an entry is for display purposes, shared by every renderer that styles entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryClass {
+    Directory,
+    Symlink,
+    // A binary with file capabilities, which grant privileges like setuid does.
+    Capability,
+    Executable,
+    Archive,
+    Image,
+    Plain,
+}
 
-            if config.recurse && path.is_dir() {
-                let mut sub_entries = collect_entries(&path, config, stats)?;
-                entries.append(&mut sub_entries);
-            }
+impl EntryClass {
+    fn of(entry: &FileInfo) -> EntryClass {
+        if entry.is_dir {
+            return EntryClass::Directory;
+        }
+        if entry.file_type.is_symlink() {
+            return EntryClass::Symlink;
+        }
+        if entry.has_capabilities {
+            return EntryClass::Capability;
+        }
+        let extension = entry.path.extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "zip" | "tar" | "gz" | "tgz" | "bz2" | "tbz2" | "xz" | "txz" | "zst" | "lz4" | "lzma" | "7z"
+            | "rar" | "jar" | "deb" | "rpm" => EntryClass::Archive,
+            "png" | "jpg" | "jpeg" | "gif" | "bmp" | "svg" | "webp" | "tif" | "tiff" | "ico" | "heic"
+            | "avif" => EntryClass::Image,
+            "exe" | "bat" | "cmd" | "com" if cfg!(windows) => EntryClass::Executable,
+            _ if entry.file_type.is_file() && entry.mode & 0o111 != 0 => EntryClass::Executable,
+            _ => EntryClass::Plain,
         }
     }
 
-    sort_entries(&mut entries, config.sort_by);
+    /// The default color for entries of this class when no `--color-scale` is chosen.
+    fn color(self) -> &'static str {
+        match self {
+            EntryClass::Directory => "\x1B[1;34m",
+            EntryClass::Symlink => "\x1B[36m",
+            // Black on red, as `ls` shows them.
+            EntryClass::Capability => "\x1B[30;41m",
+            EntryClass::Executable => "\x1B[32m",
+            EntryClass::Archive => "\x1B[31m",
+            EntryClass::Image => "\x1B[35m",
+            EntryClass::Plain => "",
+        }
+    }
 
-    Ok(entries)
+    /// The class `--html` marks entries of this class with, styled like `color`.
+    fn css_class(self) -> &'static str {
+        match self {
+            EntryClass::Directory => "dir",
+            EntryClass::Symlink => "symlink",
+            EntryClass::Capability => "capability",
+            EntryClass::Executable => "exec",
+            EntryClass::Archive => "archive",
+            EntryClass::Image => "image",
+            EntryClass::Plain => "file",
+        }
+    }
 }
 
-fn print_entries_oneline(entries: &[FileInfo], config: &Config) -> io::Result<()> {
-    for entry in entries {
-        print_entry_oneline(entry, config)?;
-    }
-    Ok(())
+/// Traversal state shared across the recursive walk.
+struct WalkState {
+    root_device: Option<u64>,
+    mounts: HashMap<PathBuf, MountEntry>,
+    // (device, inode) of every directory between the root and the one being read.
+    ancestors: Vec<(u64, u64)>,
+    // First path each directory was walked through, when links may lead back to it.
+    visited: HashMap<(u64, u64), PathBuf>,
+    // The `.treeignore` files of the directories being walked, outermost first.
+    treeignores: Vec<Gitignore>,
+    // Git's ignore rules for the directories being walked, outermost first, loaded from
+    // absolute paths, and whether the walk is inside a repository for them to apply.
+    gitignores: Vec<Gitignore>,
+    in_git_repo: bool,
+    // Below a directory matched by the name patterns with --matchdirs, where every file is kept.
+    in_matched_dir: bool,
+    // When --timeout stops the walk, and whether it did.
+    deadline: Option<Instant>,
+    timed_out: bool,
+    root: PathBuf,
+    // Entries that were listed by their directory but could not be read, and were skipped.
+    unreadable_entries: usize,
+    // With --ndjson, entries are written out as they are read instead of being kept, and
+    // only counted here.
+    streamed: Option<TreeStats>,
 }
 
-fn print_entry_oneline(entry: &FileInfo, config: &Config) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
-    let file_name = entry.path.file_name().unwrap_or_default().to_string_lossy();
-    let formatted_name = format_file_name(&file_name, config);
-    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
-    let icon = get_icon(&entry.path, config);
-    let color = get_color_for_scale(&entry.path, config);
-    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
-    
-    write!(stdout, "{}{}{}{}", color, icon, hyperlinked_name, type_indicator)?;
-    
-    if config.show_size {
-        write!(stdout, " [{}]", format_size(entry.size))?;
+impl WalkState {
+    fn new(root: &Path, metadata: &fs::Metadata, config: &Config) -> WalkState {
+        let enclosing_repo = if config.gitignore { enclosing_gitignores(root) } else { None };
+        WalkState {
+            root_device: if config.one_file_system { device_id(metadata) } else { None },
+            mounts: if config.show_mounts { read_mount_table() } else { HashMap::new() },
+            ancestors: file_id(metadata).into_iter().collect(),
+            visited: HashMap::new(),
+            treeignores: Vec::new(),
+            in_git_repo: enclosing_repo.is_some(),
+            gitignores: enclosing_repo.unwrap_or_default(),
+            in_matched_dir: false,
+            deadline: config.timeout.map(|timeout| Instant::now() + timeout),
+            timed_out: false,
+            unreadable_entries: 0,
+            root: root.to_path_buf(),
+            streamed: None,
+        }
     }
-    
-    writeln!(stdout, "\x1B[0m")
 }
 
-fn print_entries_long(entries: &[FileInfo], config: &Config) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
-    
-    // Calculate column widths
-    let max_size_width = entries.iter().map(|e| format_size(e.size).len()).max().unwrap_or(0);
-    let max_name_width = entries.iter().map(|e| e.path.file_name().unwrap_or_default().len()).max().unwrap_or(0);
+/// A filesystem from the system mount table.
+struct MountEntry {
+    source: String,
+    fs_type: String,
+}
 
-    // Print header
-    writeln!(stdout, "{:<10} {:>width$} {:<20} {}",
-        "Type",
-        "Size",
-        "Modified",
-        "Name",
-        width = max_size_width
-    )?;
-    writeln!(stdout, "{}", "-".repeat(10 + 1 + max_size_width + 1 + 20 + 1 + max_name_width))?;
+/// A long-view column filled by running an external command per entry.
+#[derive(Debug)]
+struct CommandColumn {
+    name: String,
+    command: String,
+}
 
-    for entry in entries {
-        print_entry_long(entry, config, max_size_width)?;
-    }
+/// A command run for the matched entries instead of printing them.
+#[derive(Debug)]
+struct ExecAction {
+    command: Vec<String>,
+    // Run once with every path instead of once per entry.
+    batch: bool,
+}
 
-    Ok(())
+struct TreeNode {
+    info: FileInfo,
+    children: Vec<TreeNode>,
 }
 
-fn print_entry_long(entry: &FileInfo, config: &Config, size_width: usize) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
-    let file_name = entry.path.file_name().unwrap_or_default().to_string_lossy();
-    let formatted_name = format_file_name(&file_name, config);
-    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
-    let icon = get_icon(&entry.path, config);
-    let color = get_color_for_scale(&entry.path, config);
-    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
-    let size = format_size(entry.size);
-    let mod_time: DateTime<Local> = entry.mod_time.into();
+/// Size and usage of the filesystem holding the root path.
+struct VolumeSpace {
+    total: u64,
+    used: u64,
+    // Free space usable by the current user, which excludes blocks reserved for root.
+    available: u64,
+}
 
-    writeln!(stdout, "{}{:<10} {:>width$} {:<20} {}{}{}{}{}",
-        color,
-        get_file_type_str(&entry.file_type),
-        size,
-        mod_time.format("%Y-%m-%d %H:%M:%S"),
-        icon,
-        hyperlinked_name,
-        type_indicator,
-        if config.show_size { format!(" [{}]", size) } else { String::new() },
-        "\x1B[0m",
-        width = size_width
-    )
+/// Outcome of `--check`.
+struct CheckCounts {
+    ok: usize,
+    failed: usize,
+    missing: usize,
 }
 
-fn print_entries_grid(entries: &[FileInfo], config: &Config) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
-    let term_width = config.screen_width.unwrap_or_else(|| term_size::dimensions().map(|(w, _)| w).unwrap_or(80));
-    
-    let max_entry_width = entries.iter()
-        .map(|e| {
-            let file_name = e.path.file_name().unwrap_or_default().to_string_lossy();
-            let formatted_name = format_file_name(&file_name, config);
-            let icon = get_icon(&e.path, config);
-            let type_indicator = get_type_indicator(&e.file_type, config.classify);
-            let size_str = if config.show_size { format!(" [{}]", format_size(e.size)) } else { String::new() };
-            icon.len() + formatted_name.len() + type_indicator.len() + size_str.len()
-        })
-        .max()
-        .unwrap_or(0) + 2;  // +2 for spacing between entries
+fn main() -> Result<(), Box<dyn Error>> {
+    let command_line: Vec<String> = env::args().collect();
+    if let Some(script) = completion_script(&command_line) {
+        print!("{}", script);
+        return Ok(());
+    }
 
-    let columns = term_width / max_entry_width;
-    let rows = (entries.len() + columns - 1) / columns;
+    let config_file = if command_line.iter().any(|arg| arg == "--no-config") {
+        ConfigFile::default()
+    } else {
+        load_config_file()?
+    };
+    let env_options = env_args(|variable| env::var(variable).ok())?;
+    let args = layered_args(&config_file, env_options, &command_line)?;
 
-    for row in 0..rows {
-        for col in 0..columns {
-            let index = if config.sort_across {
-                row * columns + col
-            } else {
-                col * rows + row
-            };
+    let config = parse_args(&args)?;
+    let timeout = config.timeout;
 
-            if index < entries.len() {
-                let entry = &entries[index];
-                print_entry_grid(entry, config, max_entry_width)?;
-            }
-        }
-        writeln!(stdout)?;
+    if run(config)? {
+        eprintln!("tree: timed out after {:?}, the listing is incomplete", timeout.unwrap_or_default());
+        io::stdout().flush()?;
+        // Matches timeout(1), so scripts can tell a partial listing from a failure.
+        process::exit(124);
     }
-
     Ok(())
 }
 
-fn print_entry_grid(entry: &FileInfo, config: &Config, width: usize) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
-    let file_name = entry.path.file_name().unwrap_or_default().to_string_lossy();
-    let formatted_name = format_file_name(&file_name, config);
-    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
-    let icon = get_icon(&entry.path, config);
-    let color = get_color_for_scale(&entry.path, config);
-    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
-    
-    let size_str = if config.show_size { 
-        format!(" [{}]", format_size(entry.size)) 
-    } else { 
-        String::new() 
+/// Lists the tree as configured, returning whether `--timeout` cut the traversal short.
+fn run(mut config: Config) -> Result<bool, Box<dyn Error>> {
+    let path = PathBuf::from(&config.root_path);
+    if config.ndjson {
+        return Ok(stream_ndjson(&path, &config)?);
+    }
+    let (mut root, timed_out, unreadable_entries) = match &config.from_json {
+        Some(file) => (load_json_tree(file, &config)?, false, 0),
+        None if config.from_file => {
+            let root = load_path_list(&config)?;
+            // PATH named the list; what it lists is relative to the current directory.
+            config.root_path = root.info.path.to_string_lossy().into_owned();
+            (root, false, 0)
+        }
+        None => build_tree(&path, &config)?,
     };
-    
-    let entry_str = format!("{}{}{}{}{}", icon, hyperlinked_name, type_indicator, size_str, "\x1B[0m");
-    
-    write!(stdout, "{}{:<width$}", color, entry_str, width = width)
-}
 
-fn print_tree(path: &Path, level: usize, config: &Config, stats: &mut TreeStats) -> io::Result<()> {
-    if let Some(max_depth) = config.max_depth {
-        if level >= max_depth {
-            return Ok(());
-        }
+    let scanned = if config.trace { tree_entries(&root.children).len() } else { 0 };
+    // Name patterns only ever filter files, so what's left below a directory are its matches.
+    let name_filtered = config.pattern.is_some() || config.glob.is_some();
+    if config.files_only || (config.prune_unmatched && name_filtered) {
+        prune_to_files(&mut root.children);
     }
+    if has_entry_filters(&config) {
+        prune_unmatched(&mut root.children, &root.info.path, &config);
+    }
+    // Last, so directories emptied by any of the filters above go too.
+    if config.prune {
+        prune_to_files(&mut root.children);
+    }
+    if config.entry_counts {
+        count_entries(&mut root);
+    }
+    if config.dirs_only {
+        keep_dirs(&mut root.children);
+    }
+    if config.show_empty {
+        mark_empty(&mut root.children, &config.empty_badge);
+    }
+    if config.trace {
+        eprintln!("trace stage=filters scanned={} kept={}", scanned, tree_entries(&root.children).len());
+    }
+    let unique_data = if config.reflinks { Some(account_shared_extents(&mut root, &config)) } else { None };
+    let checked = match &config.check {
+        Some(sums) => Some(check_checksums(&mut root, sums, &config)?),
+        None => None,
+    };
 
-    let display_path = get_display_path(path, config);
-
-    if level > 0 {
-        let prefix = if level == 1 {
-            "├── ".to_string()
-        } else {
-            format!("{}├── ", "│   ".repeat(level - 1))
-        };
+    if let Some(export_path) = &config.ncdu_export {
+        write_ncdu_export(&root, export_path)?;
+        return Ok(timed_out);
+    }
 
-        print_tree_entry(&display_path, &prefix, config)?;
+    if config.hash_manifest {
+        let failures = print_hash_manifest(&root, &config)?;
+        if failures > 0 {
+            return Err(format!("{} file(s) could not be hashed", failures).into());
+        }
+        return Ok(timed_out);
     }
 
-    if display_path.is_dir() {
-        stats.directories += 1;
-        let mut entries: Vec<_> = fs::read_dir(&display_path)?
-            .filter_map(Result::ok)
-            .filter(|e| config.show_hidden || !is_hidden(&e.path()))
-            .collect();
+    if let Some(manifest) = &config.verify_manifest {
+        let problems = verify_manifest(&root, manifest)?;
+        if problems > 0 {
+            return Err(format!("{} manifest problem(s) found", problems).into());
+        }
+        return Ok(timed_out);
+    }
+
+    if config.du_format {
+        print_du_format(&root, &config, &mut io::stdout().lock())?;
+        return Ok(timed_out);
+    }
 
-        sort_entries_by_path(&mut entries, config.sort_by);
+    if config.disk_usage {
+        aggregate_sizes(&mut root, &config);
+    }
+    if let Some(count) = config.biggest {
+        keep_biggest(&mut root.children, count, config.max_depth);
+    } else if let (true, Some(max_depth)) = (config.disk_usage, config.max_depth) {
+        cut_to_depth(&mut root.children, max_depth);
+    }
+    if config.percent || config.bars.is_some() {
+        annotate_shares(&mut root.children, root.info.size, root.info.size, &config);
+    }
 
-        let total_entries = entries.len();
-        for (index, entry) in entries.iter().enumerate() {
-            let is_last = index == total_entries - 1;
-            
-            if is_last && level > 0 {
-                print!("{}└── ", "│   ".repeat(level - 1));
-            }
+    if matches!(config.color_scale_mode, ColorScaleMode::Relative) {
+        config.scale_range = scale_range(&root.children, &config);
+    }
 
-            print_tree(&entry.path(), level + 1, config, stats)?;
+    let mut stats = listing_stats(&root, &config);
+    stats.errors += unreadable_entries;
+    if config.disk_usage && root.info.is_dir {
+        // Includes whatever --biggest left out.
+        stats.total_size = root.info.size;
+    }
 
-            if is_last && level > 0 {
-                print!("{}    ", "    ".repeat(level - 1));
-            }
+    if let Some(exec) = &config.exec {
+        let failures = run_exec(exec, &flatten_root(root, &config), &config)?;
+        if failures > 0 {
+            return Err(format!("{} command(s) failed", failures).into());
+        }
+        return Ok(timed_out);
+    }
+
+    if let Some(template) = &config.template {
+        print_template(template, root, &stats, &config)?;
+        return Ok(timed_out);
+    }
+
+    if config.html {
+        print_html(&root, &stats, &config)?;
+        return Ok(timed_out);
+    }
+
+    match config.json {
+        Some(JsonSchema::Native) => {
+            print_json(root, &stats, &config)?;
+            return Ok(timed_out);
+        }
+        Some(JsonSchema::Tree) => {
+            print_tree_json(&root, &stats, &config, &mut io::stdout().lock())?;
+            return Ok(timed_out);
         }
+        None => {}
+    }
+
+    if config.yaml {
+        print_yaml(root, &stats, &config)?;
+        return Ok(timed_out);
+    }
+
+    if config.xml {
+        print_tree_xml(&root, &stats, &config)?;
+        return Ok(timed_out);
+    }
+
+    match config.output_format {
+        Some(OutputFormat::Markdown) => {
+            print_markdown(&root, &config)?;
+            return Ok(timed_out);
+        }
+        Some(OutputFormat::MarkdownTree) => {
+            print_markdown_tree(&root, &stats, &config)?;
+            return Ok(timed_out);
+        }
+        Some(format) => {
+            print_table(flatten_root(root, &config), format, &config)?;
+            return Ok(timed_out);
+        }
+        None => {}
+    }
+
+    if config.org {
+        print_org(&root, &stats, &config)?;
+        return Ok(timed_out);
+    }
+
+    if config.export_script {
+        print_export_script(&root, &config)?;
+        return Ok(timed_out);
+    }
+
+    if config.dot {
+        print_dot(&root, &config)?;
+        return Ok(timed_out);
+    }
+
+    if config.mermaid {
+        print_mermaid(&root, &config)?;
+        return Ok(timed_out);
+    }
+
+    if let Some(style) = config.plantuml {
+        print_plantuml(&root, style, &config)?;
+        return Ok(timed_out);
+    }
+
+    if let Some(svg_path) = &config.svg {
+        write_svg(&root, svg_path, &config)?;
+        return Ok(timed_out);
+    }
+
+    if config.print0 {
+        print_paths0(flatten_root(root, &config), &config)?;
+        return Ok(timed_out);
+    }
+
+    if let Some(count) = config.recent {
+        print_recent(flatten_root(root, &config), count, &config)?;
+    } else if let Some(group_by) = config.group_by {
+        print_grouped(flatten_root(root, &config), group_by, &config)?;
     } else {
-        stats.files += 1;
-        let metadata = fs::metadata(&display_path)?;
-        stats.total_size += metadata.len();
+        print_entries(root, &config)?;
     }
 
-    Ok(())
-}
+    // Print summary
+    let summary = if config.dirs_only {
+        format!("\n{} directories", stats.directories)
+    } else {
+        format!("\n{} directories, {} files", stats.directories, stats.files)
+    };
+    let total_size = format!("Total size: {}", format_size(stats.total_size));
+    
+    // Apply color to summary if enabled
+    let (summary, total_size) = if use_color(&config) {
+        (
+            format!("\x1B[1;34m{}\x1B[0m", summary),
+            format!("\x1B[1;32m{}\x1B[0m", total_size)
+        )
+    } else {
+        (summary, total_size)
+    };
 
-fn print_tree_entry(path: &Path, prefix: &str, config: &Config) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
-    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
-    let formatted_name = format_file_name(&file_name, config);
-    let hyperlinked_name = format_hyperlink(path, &formatted_name, config);
-    let icon = get_icon(path, config);
-    let color = get_color_for_scale(path, config);
-    let type_indicator = get_type_indicator(&fs::metadata(path)?.file_type(), config.classify);
+    println!("{}", summary);
+    if let Some(breakdown) = kind_breakdown(&stats) {
+        println!("{}", breakdown);
+    }
+    println!("{}", total_size);
+    if let Some(unique_data) = unique_data {
+        println!("Unique data: {} (shared extents counted once)", format_size(unique_data));
+    }
+    if let Some(space) = volume_space(&path).filter(|_| config.show_volume && config.from_json.is_none() && !config.from_file) {
+        println!("Volume: {} used, {} free of {} ({}% full)",
+            format_size(space.used),
+            format_size(space.available),
+            format_size(space.total),
+            // Like df, relative to what non-root users can fill.
+            (space.used as f64 / (space.used + space.available).max(1) as f64 * 100.0).ceil());
+    }
 
-    write!(stdout, "{}", prefix)?;
-    write!(stdout, "{}{}{}{}\x1B[0m", color, icon, hyperlinked_name, type_indicator)?;
+    if timed_out {
+        println!("Listing truncated: timed out before every directory was read");
+    }
 
-    if config.show_size {
-        let size = fs::metadata(path)?.len();
-        write!(stdout, " [{}]", format_size(size))?;
+    if let Some(counts) = checked {
+        println!("{} OK, {} FAILED, {} MISSING", counts.ok, counts.failed, counts.missing);
+        if counts.failed + counts.missing > 0 {
+            return Err(format!("{} file(s) did not match their checksum", counts.failed + counts.missing).into());
+        }
     }
 
-    writeln!(stdout)
+    Ok(timed_out)
 }
 
-fn sort_entries(entries: &mut Vec<FileInfo>, sort_by: SortBy) {
-    match sort_by {
-        SortBy::Name => entries.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name())),
-        SortBy::Size => entries.sort_by(|a, b| b.size.cmp(&a.size)),
-        SortBy::ModTime => entries.sort_by(|a, b| b.mod_time.cmp(&a.mod_time)),
+/// Writes the tree in ncdu's JSON export format, to a file or to stdout for `-`, so it can be
+/// browsed later with `ncdu -f`.
+fn write_ncdu_export(root: &TreeNode, export_path: &str) -> io::Result<()> {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut export = format!(
+        "[1,2,{{\"progname\":\"{}\",\"progver\":\"{}\",\"timestamp\":{}}},\n",
+        env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), timestamp
+    );
+    // ncdu names the root by its full path and every other entry by its file name.
+    let root_name = fs::canonicalize(&root.info.path).unwrap_or_else(|_| root.info.path.clone());
+    write_ncdu_node(root, &root_name.to_string_lossy(), &mut export);
+    export.push_str("]\n");
+
+    if export_path == "-" {
+        io::stdout().lock().write_all(export.as_bytes())
+    } else {
+        fs::write(export_path, export)
     }
 }
 
-fn sort_entries_by_path(entries: &mut Vec<fs::DirEntry>, sort_by: SortBy) {
-    match sort_by {
-        SortBy::Name => entries.sort_by(|a, b| a.file_name().cmp(&b.file_name())),
-        SortBy::Size => entries.sort_by(|a, b| b.metadata().map(|m| m.len()).unwrap_or(0)
-                                         .cmp(&a.metadata().map(|m| m.len()).unwrap_or(0))),
-        SortBy::ModTime => entries.sort_by(|a, b| b.metadata().and_then(|m| m.modified()).unwrap_or_else(|_| SystemTime::now())
-                                            .cmp(&a.metadata().and_then(|m| m.modified()).unwrap_or_else(|_| SystemTime::now()))),
+/// A directory is an array of its own info followed by its contents; anything else is a
+/// single info object.
+fn write_ncdu_node(node: &TreeNode, name: &str, out: &mut String) {
+    let info = &node.info;
+    let mut object = format!("{{\"name\":{},\"asize\":{},\"dsize\":{}", json_string(name), info.size, info.disk_size);
+    if let Ok(mtime) = info.mod_time.duration_since(SystemTime::UNIX_EPOCH) {
+        object.push_str(&format!(",\"mtime\":{}", mtime.as_secs()));
     }
-}
+    if !info.is_dir && !info.file_type.is_file() {
+        object.push_str(",\"notreg\":true");
+    }
+    object.push('}');
 
-fn is_hidden(path: &Path) -> bool {
-    path.file_name()
-        .and_then(|name| name.to_str())
-        .map(|name| name.starts_with("."))
-        .unwrap_or(false)
+    if !info.is_dir {
+        out.push_str(&object);
+        return;
+    }
+    out.push('[');
+    out.push_str(&object);
+    for child in &node.children {
+        out.push_str(",\n");
+        let child_name = child.info.path.file_name().unwrap_or_default().to_string_lossy();
+        write_ncdu_node(child, &child_name, out);
+    }
+    out.push(']');
 }
 
-fn get_color_for_scale(path: &Path, config: &Config) -> String {
-    match config.color_scale {
-        Some(ColorScale::Age) => get_color_for_age(path, config),
-        Some(ColorScale::Size) => get_color_for_size(path, config),
-        Some(ColorScale::All) => {
-            let age_color = get_color_for_age(path, config);
-            let size_color = get_color_for_size(path, config);
-            format!("{};{}", age_color, size_color)
-        },
-        None => String::new(),
+/// Compares the tree with a manifest and prints every entry that is missing, not listed,
+/// or of a different size. Returns the number of problems.
+fn verify_manifest(root: &TreeNode, manifest: &str) -> Result<usize, Box<dyn Error>> {
+    let text = if manifest == "-" { io::read_to_string(io::stdin())? } else { fs::read_to_string(manifest)? };
+    let expected = parse_manifest(&text);
+
+    // Directories that hold listed entries count as listed, since path lists often omit them.
+    let mut listed: HashSet<PathBuf> = HashSet::new();
+    for path in expected.keys() {
+        listed.extend(path.ancestors().skip(1).filter(|ancestor| !ancestor.as_os_str().is_empty()).map(Path::to_path_buf));
     }
-}
 
-fn get_color_for_age(path: &Path, config: &Config) -> String {
-    let metadata = fs::metadata(path).unwrap();
-    let age = SystemTime::now().duration_since(metadata.modified().unwrap()).unwrap().as_secs();
-    
-    match config.color_scale_mode {
-        ColorScaleMode::Fixed => {
-            if age < 60 * 60 * 24 { // 1 day
-                "\x1B[38;5;46m".to_string() // Bright green
-            } else if age < 60 * 60 * 24 * 7 { // 1 week
-                "\x1B[38;5;226m".to_string() // Yellow
-            } else if age < 60 * 60 * 24 * 30 { // 1 month
-                "\x1B[38;5;208m".to_string() // Orange
-            } else {
-                "\x1B[38;5;196m".to_string() // Red
-            }
-        },
-        ColorScaleMode::Gradient => {
-            let max_age = 60 * 60 * 24 * 365; // 1 year
-            let normalized_age = (age as f32 / max_age as f32).min(1.0);
-            let hue = (1.0 - normalized_age) * 120.0; // 120 (green) to 0 (red)
-            let (r, g, b) = hue_to_rgb(hue);
-            format!("\x1B[38;2;{};{};{}m", r, g, b)
-        },
+    let mut actual: HashMap<PathBuf, &FileInfo> = HashMap::new();
+    for entry in tree_entries(&root.children) {
+        actual.insert(entry.path.strip_prefix(&root.info.path).unwrap_or(&entry.path).to_path_buf(), entry);
+    }
+
+    let mut problems: Vec<(PathBuf, String)> = Vec::new();
+    for (path, size) in &expected {
+        match actual.get(path) {
+            None => problems.push((path.clone(), "missing".to_string())),
+            Some(entry) => match size {
+                Some(size) if !entry.is_dir && entry.size != *size => problems.push((
+                    path.clone(),
+                    format!("size mismatch (manifest {}, actual {})", size, entry.size),
+                )),
+                _ => {},
+            },
+        }
+    }
+    for path in actual.keys() {
+        if !expected.contains_key(path) && !listed.contains(path) {
+            problems.push((path.clone(), "extra".to_string()));
+        }
+    }
+    problems.sort();
+
+    let mut stdout = io::stdout().lock();
+    for (path, problem) in &problems {
+        writeln!(stdout, "{}: {}", problem, path.display())?;
     }
+    let count = |label: &str| problems.iter().filter(|(_, problem)| problem.starts_with(label)).count();
+    writeln!(stdout, "\n{} listed, {} missing, {} extra, {} size mismatches",
+        expected.len(), count("missing"), count("extra"), count("size mismatch"))?;
+
+    Ok(problems.len())
 }
 
-fn get_color_for_size(path: &Path, config: &Config) -> String {
-    let size = fs::metadata(path).unwrap().len();
-    
-    match config.color_scale_mode {
-        ColorScaleMode::Fixed => {
-            if size < 1024 { // 1 KB
-                "\x1B[38;5;46m".to_string() // Bright green
-            } else if size < 1024 * 1024 { // 1 MB
-                "\x1B[38;5;226m".to_string() // Yellow
-            } else if size < 1024 * 1024 * 100 { // 100 MB
-                "\x1B[38;5;208m".to_string() // Orange
-            } else {
-                "\x1B[38;5;196m".to_string() // Red
-            }
-        },
-        ColorScaleMode::Gradient => {
-            let max_size = 1024 * 1024 * 1024; // 1 GB
-            let normalized_size = (size as f32 / max_size as f32).min(1.0);
-            let hue = (1.0 - normalized_size) * 120.0; // 120 (green) to 0 (red)
-            let (r, g, b) = hue_to_rgb(hue);
-            format!("\x1B[38;2;{};{};{}m", r, g, b)
-        },
+/// Prints a `sha256sum` compatible line for every listed file, with paths relative to
+/// the root, hashing on the worker threads. Returns how many files could not be read.
+fn print_hash_manifest(root: &TreeNode, config: &Config) -> io::Result<usize> {
+    let files: Vec<&FileInfo> = tree_entries(&root.children).into_iter()
+        // Links are hashed through to regular files only; dangling links and links to
+        // directories have no content of their own.
+        .filter(|entry| entry.file_type.is_file()
+            || (entry.file_type.is_symlink() && fs::metadata(&entry.path).is_ok_and(|target| target.is_file())))
+        .collect();
+    let hashes = parallel_map(&files, worker_threads(config), |entry| sha256_file(&entry.path));
+
+    let mut stdout = io::stdout().lock();
+    let mut failures = 0;
+    for (entry, hash) in files.iter().zip(hashes) {
+        let relative = entry.path.strip_prefix(&root.info.path).unwrap_or(&entry.path);
+        match hash {
+            Ok(hash) => writeln!(stdout, "{}", checksum_line(&hash, &relative.to_string_lossy()))?,
+            Err(e) => {
+                eprintln!("{}: {}", entry.path.display(), e);
+                failures += 1;
+            },
+        }
     }
+    Ok(failures)
 }
 
-fn hue_to_rgb(hue: f32) -> (u8, u8, u8) {
-    let c = 1.0;
-    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
-    let m = 0.0;
+/// Re-hashes the files listed in a `sha256sum` manifest, with paths relative to the root,
+/// and marks them `OK`, `FAILED` or `MISSING` in the tree. Only the listed files and the
+/// directories leading to them are kept.
+fn check_checksums(root: &mut TreeNode, sums: &str, config: &Config) -> Result<CheckCounts, Box<dyn Error>> {
+    let text = if sums == "-" { io::read_to_string(io::stdin())? } else { fs::read_to_string(sums)? };
+    let mut listed = Vec::new();
+    for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let (hash, path) = parse_checksum_line(line)
+            .ok_or_else(|| format!("{}:{}: not a sha256sum line", sums, number + 1))?;
+        let path = path.strip_prefix("./").unwrap_or(&path).to_string();
+        listed.push((root.info.path.join(path), hash));
+    }
 
-    let (r, g, b) = match hue {
-        h if h < 60.0 => (c, x, 0.0),
-        h if h < 120.0 => (x, c, 0.0),
-        h if h < 180.0 => (0.0, c, x),
-        h if h < 240.0 => (0.0, x, c),
-        h if h < 300.0 => (x, 0.0, c),
-        _ => (c, 0.0, x),
-    };
+    let results = parallel_map(&listed, worker_threads(config), |(path, expected)| match sha256_file(path) {
+        Ok(hash) if hash.eq_ignore_ascii_case(expected) => "OK",
+        Ok(_) => "FAILED",
+        Err(e) if e.kind() == io::ErrorKind::NotFound => "MISSING",
+        Err(_) => "FAILED (unreadable)",
+    });
 
-    (
-        ((r + m) * 255.0) as u8,
-        ((g + m) * 255.0) as u8,
-        ((b + m) * 255.0) as u8
-    )
+    let mut counts = CheckCounts { ok: 0, failed: 0, missing: 0 };
+    let mut statuses = HashMap::new();
+    for ((path, _), status) in listed.into_iter().zip(results) {
+        match status {
+            "OK" => counts.ok += 1,
+            "MISSING" => counts.missing += 1,
+            _ => counts.failed += 1,
+        }
+        statuses.insert(path, status);
+    }
+
+    add_notes(&mut root.children, &mut statuses);
+    root.children.retain_mut(node_is_checked);
+    // Whatever is left was not found in the tree, so place it where it should have been.
+    let mut missing: Vec<(PathBuf, &str)> = statuses.into_iter().collect();
+    missing.sort();
+    for (path, status) in missing {
+        insert_missing(root, &path, status, config);
+    }
+    Ok(counts)
 }
 
-fn get_icon(path: &Path, config: &Config) -> &'static str {
-    match config.icons {
-        IconOption::Always => get_icon_for_file(path),
-        IconOption::Auto => {
-            if atty::is(atty::Stream::Stdout) {
-                get_icon_for_file(path)
-            } else {
-                ""
+/// Notes the files that share extents with other files (reflink copies, deduplicated or
+/// snapshotted data) and returns the bytes the listed files occupy with every shared
+/// extent counted once. Falls back to the allocated size where extents cannot be read.
+fn account_shared_extents(root: &mut TreeNode, config: &Config) -> u64 {
+    let files: Vec<(PathBuf, u64)> = tree_entries(&root.children).into_iter()
+        .filter(|entry| entry.file_type.is_file() && !entry.virtual_fs)
+        .map(|entry| (entry.path.clone(), entry.disk_size))
+        .collect();
+    let extents = parallel_map(&files, io_concurrency(config), |(path, _)| file_extents(path));
+
+    let mut unique = 0;
+    let mut shared_ranges = Vec::new();
+    let mut notes = HashMap::new();
+    for ((path, disk_size), extents) in files.into_iter().zip(extents) {
+        let Ok(extents) = extents else {
+            unique += disk_size;
+            continue;
+        };
+        let mut shared_bytes = 0;
+        for extent in extents {
+            match extent.physical {
+                Some(physical) if extent.shared => {
+                    shared_bytes += extent.length;
+                    shared_ranges.push((physical, physical + extent.length));
+                },
+                _ => unique += extent.length,
             }
-        },
-        IconOption::Never => "",
+        }
+        if shared_bytes > 0 {
+            notes.insert(path, format!("shared extents: {}", format_entry_size(shared_bytes, config)));
+        }
     }
-}
 
-fn get_icon_for_file(path: &Path) -> &'static str {
-    if path.is_dir() {
-        "📁 "
-    } else {
-        match path.extension().and_then(|s| s.to_str()) {
-            Some("txt") => "📄 ",
-            Some("rs") => "🦀 ",
-            Some("py") => "🐍 ",
-            Some("js") => "🟨 ",
-            Some("html") => "🌐 ",
-            Some("css") => "🎨 ",
-            Some("json") => "🔧 ",
-            Some("md") => "📝 ",
-            Some("png") | Some("jpg") | Some("jpeg") | Some("gif") => "🖼️ ",
-            Some("mp3") | Some("wav") | Some("ogg") => "🎵 ",
-            Some("mp4") | Some("avi") | Some("mkv") => "🎥 ",
-            Some("pdf") => "📚 ",
-            Some("zip") | Some("tar") | Some("gz") => "🗜️ ",
-            Some("exe") => "⚙️ ",
-            _ => "📄 ",
+    // Clones can share parts of an extent, so merge overlapping ranges before adding them up.
+    shared_ranges.sort_unstable();
+    let mut merged_end = 0;
+    for (start, end) in shared_ranges {
+        let start = start.max(merged_end);
+        if end > start {
+            unique += end - start;
+            merged_end = end;
         }
     }
+
+    add_notes(&mut root.children, &mut notes);
+    unique
 }
 
-fn format_file_name(name: &str, config: &Config) -> String {
-    if config.quote_names && name.contains(' ') {
-        format!("\"{}\"", name)
-    } else {
-        name.to_string()
+/// Adds the note given for each entry's path, taking it out of `notes`.
+fn add_notes<T: ToString>(nodes: &mut [TreeNode], notes: &mut HashMap<PathBuf, T>) {
+    for node in nodes {
+        if let Some(note) = notes.remove(&node.info.path) {
+            node.info.notes.push(note.to_string());
+        }
+        add_notes(&mut node.children, notes);
     }
 }
 
+/// Drops everything below `node` that was not listed, and tells whether anything is left.
+fn node_is_checked(node: &mut TreeNode) -> bool {
+    node.children.retain_mut(node_is_checked);
+    let marked = node.info.notes.iter()
+        .any(|note| ["OK", "FAILED", "MISSING"].iter().any(|status| note.starts_with(status)));
+    marked || !node.children.is_empty()
+}
 
-fn format_hyperlink(path: &Path, name: &str, config: &Config) -> String {
-    if config.hyperlink {
-        let full_path = if path.is_absolute() {
-            path.to_string_lossy().to_string()
-        } else {
-            env::current_dir().unwrap().join(path).to_string_lossy().to_string()
+/// Adds a placeholder for a listed file that is not in the tree, creating the missing
+/// directories on the way.
+fn insert_missing(root: &mut TreeNode, path: &Path, status: &str, config: &Config) {
+    let relative = path.strip_prefix(&root.info.path).unwrap_or(path);
+    let components: Vec<_> = relative.components().collect();
+    let mut node = root;
+    for (index, component) in components.iter().enumerate() {
+        let child_path = node.info.path.join(component);
+        let is_last = index + 1 == components.len();
+        let position = match node.children.iter().position(|child| child.info.path == child_path) {
+            Some(position) => position,
+            None => {
+                let kind = if is_last { FileKind::File } else { FileKind::Directory };
+                let mut info = stored_file_info(child_path.clone(), kind, 0, SystemTime::UNIX_EPOCH, 0);
+                info.depth = index + 1;
+                if is_last {
+                    info.notes.push(status.to_string());
+                }
+                node.children.push(TreeNode { info, children: Vec::new() });
+                sort_nodes(&mut node.children, config);
+                node.children.iter().position(|child| child.info.path == child_path).expect("just inserted")
+            },
         };
-        format!("\x1B]8;;file://{}\x1B\\{}\x1B]8;;\x1B\\", full_path, name)
-    } else {
-        name.to_string()
+        node = &mut node.children[position];
     }
 }
 
-fn get_display_path(path: &Path, config: &Config) -> PathBuf {
-    match config.absolute_path {
-        AbsolutePathOption::On => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
-        AbsolutePathOption::Follow => {
-            if path.is_symlink() {
-                fs::read_link(path).unwrap_or_else(|_| path.to_path_buf())
-            } else {
-                path.to_path_buf()
-            }
-        },
-        AbsolutePathOption::Off => path.to_path_buf(),
+/// Splits a `sha256sum` line into its hash and unescaped path.
+fn parse_checksum_line(line: &str) -> Option<(String, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (hash, rest) = line.split_once(' ')?;
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    // Two spaces for text mode, a space and `*` for binary mode.
+    let path = rest.strip_prefix([' ', '*'])?;
+    if !escaped {
+        return Some((hash.to_string(), path.to_string()));
     }
-}
 
-fn get_type_indicator(file_type: &fs::FileType, classify: Classify) -> &'static str {
-    match classify {
-        Classify::Always => {
-            if file_type.is_dir() { "/" }
-            else if file_type.is_symlink() { "@" }
-            else if file_type.is_file() { 
-                #[cfg(unix)]
-                {
-                    use std::os::unix::fs::FileTypeExt;
-                    if file_type.is_socket() { "=" }
-                    else if file_type.is_fifo() { "|" }
-                    else { "" }
-                }
-                #[cfg(not(unix))]
-                { "" }
-            }
-            else { "" }
-        },
-        Classify::Auto => {
-            if file_type.is_dir() { "/" }
-            else if file_type.is_symlink() { "@" }
-            else { "" }
-        },
-        Classify::Never => "",
+    let mut unescaped = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => unescaped.push('\\'),
+            },
+            (c, false) => unescaped.push(c),
+        }
     }
+    Some((hash.to_string(), unescaped))
 }
 
-fn get_file_type_str(file_type: &fs::FileType) -> &'static str {
-    if file_type.is_dir() { "Directory" }
-    else if file_type.is_symlink() { "Symlink" }
-    else if file_type.is_file() { "File" }
-    else { "Other" }
+/// Formats `HASH  path` the way `sha256sum` does, escaping names that contain a backslash
+/// or newline and marking such lines with a leading backslash.
+fn checksum_line(hash: &str, path: &str) -> String {
+    if path.contains(['\\', '\n']) {
+        format!("\\{}  {}", hash, path.replace('\\', "\\\\").replace('\n', "\\n"))
+    } else {
+        format!("{}  {}", hash, path)
+    }
 }
 
-fn format_size(size: u64) -> String {
-    const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
-    let mut size = size as f64;
-    let mut unit_index = 0;
-
-    while size >= 1024.0 && unit_index < UNITS.len() - 1 {
-        size /= 1024.0;
-        unit_index += 1;
+fn sha256_file(path: &Path) -> io::Result<String> {
+    use std::io::Read;
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
     }
+    Ok(hasher.finish().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
 
-    format!("{:.2} {}", size, UNITS[unit_index])
+/// SHA-256 (FIPS 180-4), fed incrementally.
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    length: u64,
 }
 
-#[cfg(unix)]
-fn is_executable(path: &Path) -> bool {
-    use std::os::unix::fs::PermissionsExt;
-    if let Ok(metadata) = fs::metadata(path) {
-        return metadata.permissions().mode() & 0o111 != 0;
+const SHA256_ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        Sha256 {
+            state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+            block: [0; 64],
+            block_len: 0,
+            length: 0,
+        }
     }
-    false
-}
 
-#[cfg(not(unix))]
-fn is_executable(_path: &Path) -> bool {
-    false
-}
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == 64 {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
 
+    fn finish(mut self) -> [u8; 32] {
+        let bit_length = self.length * 8;
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_length.to_be_bytes());
 
+        let mut digest = [0; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
 
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7) ^ schedule[i - 15].rotate_right(18) ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17) ^ schedule[i - 2].rotate_right(19) ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16].wrapping_add(s0).wrapping_add(schedule[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(SHA256_ROUND_CONSTANTS[i]).wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+/// Reads a manifest of paths relative to the root, with optional sizes. Accepts plain path
+/// lists (`tar tf`, `find`), `SIZE<TAB>PATH` lines (`du -ab`, `--du-format -s`) and
+/// verbose tar listings (`tar tvf`).
+fn parse_manifest(text: &str) -> HashMap<PathBuf, Option<u64>> {
+    let tar_line = Regex::new(r"^[-dlhcbps][-rwxsStT]{9}\S*\s+\S+\s+(\d+)\s+\S+\s+\S+\s+(.+)$").expect("valid regex");
+    let mut entries = HashMap::new();
+
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let (path, size) = if let Some(captures) = tar_line.captures(line) {
+            // Links are listed as `name -> target` or `name link to target`.
+            let name = &captures[2];
+            let name = name.split(" -> ").next().unwrap_or(name);
+            let name = name.split(" link to ").next().unwrap_or(name);
+            // Only regular files have a meaningful size in the listing.
+            let is_file = line.starts_with('-');
+            (name.to_string(), if is_file { captures[1].parse().ok() } else { None })
+        } else if let Some((size, path)) = line.split_once('\t').filter(|(size, _)| size.chars().all(|c| c.is_ascii_digit())) {
+            (path.to_string(), size.parse().ok())
+        } else {
+            (line.to_string(), None)
+        };
+
+        let path = path.trim_end_matches('/');
+        let path = path.strip_prefix("./").unwrap_or(path);
+        if !path.is_empty() && path != "." {
+            entries.insert(PathBuf::from(path), size);
+        }
+    }
+    entries
+}
+
+/// Prints `SIZE\tPATH` for every entry, contents before their directory, like `du -a`.
+/// Sizes are disk usage in KiB, or apparent bytes with `-s` (`du -ab`). As with
+/// `du --max-depth`, `-L` only limits which entries are printed, not what is counted.
+/// Returns the entry's total.
+fn print_du_format(node: &TreeNode, config: &Config, out: &mut impl Write) -> io::Result<u64> {
+    let mut total = if config.raw_sizes { node.info.size } else { node.info.disk_size };
+    for child in &node.children {
+        total += print_du_format(child, config, out)?;
+    }
+    if config.max_depth.is_none_or(|max_depth| node.info.depth <= max_depth) {
+        let shown = if config.raw_sizes { total } else { total.div_ceil(1024) };
+        writeln!(out, "{}\t{}", shown, node.info.path.display())?;
+    }
+    Ok(total)
+}
+
+/// A parsed JSON document.
+#[derive(Debug)]
+enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            JsonValue::Number(number) if *number >= 0.0 => Some(*number as u64),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            JsonValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+fn parse_json(text: &str) -> Result<JsonValue, Box<dyn Error>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = 0;
+    let value = parse_json_value(&chars, &mut pos)?;
+    skip_json_whitespace(&chars, &mut pos);
+    if pos < chars.len() {
+        return Err(format!("Unexpected '{}' after the JSON document", chars[pos]).into());
+    }
+    Ok(value)
+}
+
+fn skip_json_whitespace(chars: &[char], pos: &mut usize) {
+    while chars.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+}
+
+fn parse_json_value(chars: &[char], pos: &mut usize) -> Result<JsonValue, Box<dyn Error>> {
+    skip_json_whitespace(chars, pos);
+    let literal = |word: &str, value: JsonValue, pos: &mut usize| -> Result<JsonValue, Box<dyn Error>> {
+        let end = *pos + word.chars().count();
+        if chars.get(*pos..end).is_some_and(|found| found.iter().copied().eq(word.chars())) {
+            *pos = end;
+            Ok(value)
+        } else {
+            Err(format!("Invalid JSON value at character {}", *pos).into())
+        }
+    };
+
+    match chars.get(*pos) {
+        Some('{') => {
+            *pos += 1;
+            let mut members = Vec::new();
+            skip_json_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&'}') {
+                *pos += 1;
+                return Ok(JsonValue::Object(members));
+            }
+            loop {
+                skip_json_whitespace(chars, pos);
+                let key = parse_json_string(chars, pos)?;
+                skip_json_whitespace(chars, pos);
+                if chars.get(*pos) != Some(&':') {
+                    return Err(format!("Expected ':' at character {}", *pos).into());
+                }
+                *pos += 1;
+                members.push((key, parse_json_value(chars, pos)?));
+                skip_json_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some('}') => {
+                        *pos += 1;
+                        return Ok(JsonValue::Object(members));
+                    },
+                    _ => return Err(format!("Expected ',' or '}}' at character {}", *pos).into()),
+                }
+            }
+        },
+        Some('[') => {
+            *pos += 1;
+            let mut items = Vec::new();
+            skip_json_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&']') {
+                *pos += 1;
+                return Ok(JsonValue::Array(items));
+            }
+            loop {
+                items.push(parse_json_value(chars, pos)?);
+                skip_json_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some(']') => {
+                        *pos += 1;
+                        return Ok(JsonValue::Array(items));
+                    },
+                    _ => return Err(format!("Expected ',' or ']' at character {}", *pos).into()),
+                }
+            }
+        },
+        Some('"') => Ok(JsonValue::String(parse_json_string(chars, pos)?)),
+        Some('t') => literal("true", JsonValue::Bool(true), pos),
+        Some('f') => literal("false", JsonValue::Bool(false), pos),
+        Some('n') => literal("null", JsonValue::Null, pos),
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let start = *pos;
+            while chars.get(*pos).is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                *pos += 1;
+            }
+            let number: String = chars[start..*pos].iter().collect();
+            number.parse().map(JsonValue::Number).map_err(|_| format!("Invalid JSON number '{}'", number).into())
+        },
+        Some(c) => Err(format!("Unexpected '{}' at character {}", c, *pos).into()),
+        None => Err("Unexpected end of JSON document".into()),
+    }
+}
+
+fn parse_json_string(chars: &[char], pos: &mut usize) -> Result<String, Box<dyn Error>> {
+    if chars.get(*pos) != Some(&'"') {
+        return Err(format!("Expected a string at character {}", *pos).into());
+    }
+    *pos += 1;
+
+    let mut text = String::new();
+    loop {
+        let c = *chars.get(*pos).ok_or("Unterminated JSON string")?;
+        *pos += 1;
+        match c {
+            '"' => return Ok(text),
+            '\\' => {
+                let escape = *chars.get(*pos).ok_or("Unterminated JSON string")?;
+                *pos += 1;
+                text.push(match escape {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let code = parse_json_code_unit(chars, pos)?;
+                        let code = match code {
+                            // A character outside the BMP, written as a UTF-16 surrogate pair.
+                            0xD800..=0xDBFF if chars.get(*pos..*pos + 2) == Some(&['\\', 'u']) => {
+                                *pos += 2;
+                                let low = parse_json_code_unit(chars, pos)?;
+                                if !(0xDC00..=0xDFFF).contains(&low) {
+                                    return Err(format!("Unpaired surrogate \\u{:04x} in JSON string", code).into());
+                                }
+                                0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
+                            },
+                            code => code,
+                        };
+                        char::from_u32(code).ok_or_else(|| format!("Unpaired surrogate \\u{:04x} in JSON string", code))?
+                    },
+                    other => other,
+                });
+            },
+            c => text.push(c),
+        }
+    }
+}
+
+/// Reads the four hex digits of a `\\u` escape.
+fn parse_json_code_unit(chars: &[char], pos: &mut usize) -> Result<u32, Box<dyn Error>> {
+    let hex: String = chars.get(*pos..*pos + 4).ok_or("Truncated \\u escape in JSON string")?.iter().collect();
+    *pos += 4;
+    u32::from_str_radix(&hex, 16).map_err(|_| format!("Invalid \\u escape '{}'", hex).into())
+}
+
+/// Loads a tree saved with `--json` (or GNU `tree -J`) from a file, or stdin for `-`. The
+/// walk-time filters, depth limit and sort order are applied as if it were being scanned.
+fn load_json_tree(file: &str, config: &Config) -> Result<TreeNode, Box<dyn Error>> {
+    let text = if file == "-" { io::read_to_string(io::stdin())? } else { fs::read_to_string(file)? };
+    let document = parse_json(&text)?;
+
+    // GNU tree writes an array of roots followed by a report object.
+    let root = match &document {
+        JsonValue::Array(items) => items.iter()
+            .find(|item| item.get("type").and_then(JsonValue::as_str) != Some("report"))
+            .ok_or("The JSON document contains no tree")?,
+        JsonValue::Object(_) => document.get("root").unwrap_or(&document),
+        _ => return Err("Expected a JSON object or array".into()),
+    };
+
+    if let Some(JsonValue::Array(entries)) = root.get("entries") {
+        return json_flat_tree(entries, config);
+    }
+    json_tree_node(root, None, 0, config)
+}
+
+/// Rebuilds the nesting of a flat `--json` listing from each entry's depth, dropping the
+/// entries the walk would not have listed and sorting every directory once it is complete.
+fn json_flat_tree(entries: &[JsonValue], config: &Config) -> Result<TreeNode, Box<dyn Error>> {
+    let depth_of = |entry: &JsonValue| entry.get("depth").and_then(JsonValue::as_u64).unwrap_or(1).max(1) as usize;
+    // The entries' paths include the root they were listed from.
+    let root_path = entries.iter()
+        .find_map(|entry| Some(Path::new(entry.get("path")?.as_str()?).ancestors().nth(depth_of(entry))?.to_path_buf()))
+        .filter(|path| !path.as_os_str().is_empty())
+        .unwrap_or_else(|| PathBuf::from(&config.root_path));
+    let root_info = stored_file_info(root_path, FileKind::Directory, 0, SystemTime::UNIX_EPOCH, 0);
+    let mut stack = vec![TreeNode { info: root_info, children: Vec::new() }];
+    let close = |stack: &mut Vec<TreeNode>| {
+        let mut node = stack.pop().expect("the root never leaves the stack");
+        sort_nodes(&mut node.children, config);
+        stack.last_mut().expect("the root never leaves the stack").children.push(node);
+    };
+
+    // The depth of a dropped entry, whose contents go with it.
+    let mut dropped: Option<usize> = None;
+    for entry in entries {
+        let depth = depth_of(entry);
+        if dropped.is_some_and(|dropped| depth > dropped) {
+            continue;
+        }
+        dropped = None;
+        while stack.len() > depth {
+            close(&mut stack);
+        }
+        let node = json_tree_node(entry, None, depth, config)?;
+        if stack.len() < depth || (depth > 1 && !should_descend(depth - 1, config)) || is_filtered_out(&node.info, config) {
+            dropped = Some(depth);
+            continue;
+        }
+        stack.push(TreeNode { info: node.info, children: Vec::new() });
+    }
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    let mut root = stack.pop().expect("the root never leaves the stack");
+    sort_nodes(&mut root.children, config);
+    Ok(root)
+}
+
+fn json_tree_node(value: &JsonValue, parent: Option<&Path>, depth: usize, config: &Config) -> Result<TreeNode, Box<dyn Error>> {
+    let name = value.get("name").and_then(JsonValue::as_str).ok_or("A JSON entry has no \"name\"")?;
+    let path = match (value.get("path").and_then(JsonValue::as_str), parent) {
+        (Some(path), _) => PathBuf::from(path),
+        (None, Some(parent)) => parent.join(name),
+        (None, None) => PathBuf::from(name),
+    };
+    let children = value.get("children").or_else(|| value.get("contents"));
+    let kind = match value.get("type").and_then(JsonValue::as_str) {
+        Some("directory" | "dir") => FileKind::Directory,
+        Some("link" | "symlink") => FileKind::Symlink,
+        Some("fifo") => FileKind::Fifo,
+        Some("socket") => FileKind::Socket,
+        Some("char") => FileKind::CharDevice,
+        Some("block") => FileKind::BlockDevice,
+        Some("file") => FileKind::File,
+        Some(_) => FileKind::Other,
+        None if children.is_some() => FileKind::Directory,
+        None => FileKind::File,
+    };
+    let mod_time = match value.get("mtime").or_else(|| value.get("time")) {
+        Some(JsonValue::Number(seconds)) => std::time::Duration::try_from_secs_f64(seconds.max(0.0)).ok()
+            .and_then(|since_epoch| SystemTime::UNIX_EPOCH.checked_add(since_epoch))
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+        Some(JsonValue::String(text)) => DateTime::parse_from_rfc3339(text).map(SystemTime::from).unwrap_or(SystemTime::UNIX_EPOCH),
+        _ => SystemTime::UNIX_EPOCH,
+    };
+    let mode = value.get("mode").and_then(JsonValue::as_str)
+        .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+        .unwrap_or(0);
+    let size = value.get("size").and_then(JsonValue::as_u64).unwrap_or(0);
+
+    let mut info = stored_file_info(path, kind, size, mod_time, mode);
+    info.depth = depth;
+    // A followed link to a directory is stored with its contents.
+    info.is_dir = kind.is_dir() || children.is_some();
+    info.link_target = value.get("target").and_then(JsonValue::as_str).map(|target| format!(" -> {}", target));
+    info.broken_link = kind == FileKind::Symlink && value.get("broken").and_then(JsonValue::as_bool).unwrap_or(false);
+
+    let mut nodes = Vec::new();
+    if let (Some(JsonValue::Array(items)), true) = (children, should_descend(depth, config) || depth == 0) {
+        for item in items {
+            let child = json_tree_node(item, Some(&info.path), depth + 1, config)?;
+            if is_filtered_out(&child.info, config) {
+                continue;
+            }
+            nodes.push(child);
+        }
+        sort_nodes(&mut nodes, config);
+    }
+    Ok(TreeNode { info, children: nodes })
+}
+
+/// Whether the name filters drop an entry that was loaded rather than scanned.
+fn is_filtered_out(info: &FileInfo, config: &Config) -> bool {
+    let file_name = info.path.file_name().unwrap_or_default();
+    (!config.show_hidden && is_hidden(&info.path))
+        || config.ignore.as_ref().is_some_and(|ignore| ignore.is_match(file_name))
+        || (!info.is_dir && !matches_pattern(&info.path, Path::new(&config.root_path), config))
+}
+
+/// A directory of a `--fromfile` listing, with the entries listed below it.
+#[derive(Default)]
+struct ListedDir {
+    entries: HashMap<String, Option<ListedDir>>,
+}
+
+/// Builds the tree for `--fromfile` from a list of paths, one per line or NUL-separated,
+/// read from PATH (standard input for `-` or no PATH). Every parent of a listed path is a
+/// directory, as is a path ending in `/`; the rest are files.
+fn load_path_list(config: &Config) -> Result<TreeNode, Box<dyn Error>> {
+    let file = config.root_path.as_str();
+    let bytes = if matches!(file, "-" | ".") {
+        let mut bytes = Vec::new();
+        io::Read::read_to_end(&mut io::stdin(), &mut bytes)?;
+        bytes
+    } else {
+        fs::read(file).map_err(|e| format!("{}: {}", file, e))?
+    };
+    let text = String::from_utf8_lossy(&bytes);
+    let separator = if text.contains('\0') { '\0' } else { '\n' };
+
+    let mut listing = ListedDir::default();
+    for line in text.split(separator).map(|line| line.strip_suffix('\r').unwrap_or(line)) {
+        // `find .` prints `.` for the root and `./` in front of every path.
+        let is_dir = line.ends_with('/');
+        let names: Vec<&str> = line.split('/').filter(|name| !name.is_empty() && *name != ".").collect();
+        let Some((last, parents)) = names.split_last() else { continue };
+        let mut dir = &mut listing;
+        for name in parents {
+            dir = dir.entries.entry(name.to_string()).or_default().get_or_insert_with(ListedDir::default);
+        }
+        let entry = dir.entries.entry(last.to_string()).or_default();
+        if is_dir {
+            entry.get_or_insert_with(ListedDir::default);
+        }
+    }
+
+    let root_info = stored_file_info(PathBuf::from("."), FileKind::Directory, 0, SystemTime::UNIX_EPOCH, 0);
+    let children = listed_nodes(listing, &root_info.path, 1, config);
+    Ok(TreeNode { info: root_info, children })
+}
+
+fn listed_nodes(dir: ListedDir, parent: &Path, depth: usize, config: &Config) -> Vec<TreeNode> {
+    let mut nodes = Vec::new();
+    for (name, entry) in dir.entries {
+        let kind = if entry.is_some() { FileKind::Directory } else { FileKind::File };
+        let mut info = stored_file_info(parent.join(name), kind, 0, SystemTime::UNIX_EPOCH, 0);
+        info.depth = depth;
+        if is_filtered_out(&info, config) {
+            continue;
+        }
+        let children = match entry {
+            Some(dir) if should_descend(depth, config) => listed_nodes(dir, &info.path, depth + 1, config),
+            _ => Vec::new(),
+        };
+        nodes.push(TreeNode { info, children });
+    }
+    sort_nodes(&mut nodes, config);
+    nodes
+}
+
+/// An entry that was not read from the filesystem, such as one loaded by --from-json.
+fn stored_file_info(path: PathBuf, kind: FileKind, size: u64, mod_time: SystemTime, mode: u32) -> FileInfo {
+    FileInfo {
+        path,
+        size,
+        mod_time,
+        accessed: None,
+        created: None,
+        file_type: kind,
+        is_dir: kind.is_dir(),
+        mode,
+        disk_size: size,
+        owner: None,
+        flags: 0,
+        has_acl: false,
+        has_capabilities: false,
+        broken_link: false,
+        unreadable: false,
+        virtual_fs: false,
+        depth: 0,
+        link_target: None,
+        notes: Vec::new(),
+        label: None,
+        share: None,
+        bar: None,
+    }
+}
+
+/// Prints the `--json` document: in tree mode the root with its entries nested below it in
+/// `children`, in the flat modes an `entries` array holding every listed entry with its
+/// depth. Either is followed by a `summary` object with the counts of the text summary.
+fn print_json(root: TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let mut json = String::from("{\n");
+    if matches!(config.display_mode, DisplayMode::Tree) {
+        json.push_str("  \"root\": ");
+        write_json_node(&root, &config.root_path, 1, &mut json);
+    } else {
+        json.push_str("  \"entries\": [");
+        for (index, entry) in flatten_root(root, config).iter().enumerate() {
+            json.push_str(if index == 0 { "\n    " } else { ",\n    " });
+            let name = entry.label.map(String::from).unwrap_or_else(|| file_name_lossy(entry));
+            json.push_str(&format!("{{{},\"depth\":{}}}", json_entry_fields(entry, &name), entry.depth));
+        }
+        json.push_str("\n  ]");
+    }
+    json.push_str(&format!(",\n  \"summary\": {}\n}}\n", json_summary(stats)));
+
+    io::stdout().lock().write_all(json.as_bytes())
+}
+
+/// The `summary` object of `--json` and `--ndjson`.
+fn json_summary(stats: &TreeStats) -> String {
+    let fields: Vec<String> = summary_fields(stats).iter().map(|(key, value)| format!("\"{}\":{}", key, value)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// The counts of the `--json` and `--yaml` summaries, in order.
+fn summary_fields(stats: &TreeStats) -> [(&'static str, u64); 9] {
+    [
+        ("directories", stats.directories as u64),
+        ("files", stats.files as u64),
+        ("total_size", stats.total_size),
+        ("symlinks", stats.symlinks as u64),
+        ("broken_links", stats.broken_links as u64),
+        ("sockets", stats.sockets as u64),
+        ("fifos", stats.fifos as u64),
+        ("devices", stats.devices as u64),
+        ("errors", stats.errors as u64),
+    ]
+}
+
+/// Prints the document of `--json` as YAML: the same keys, with block mappings and
+/// sequences in place of objects and arrays.
+fn print_yaml(root: TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let mut yaml = String::new();
+    if matches!(config.display_mode, DisplayMode::Tree) {
+        yaml.push_str("root:\n");
+        write_yaml_node(&root, &config.root_path, "  ", "  ", &mut yaml);
+    } else {
+        let entries = flatten_root(root, config);
+        yaml.push_str(if entries.is_empty() { "entries: []\n" } else { "entries:\n" });
+        for entry in &entries {
+            let name = entry.label.map(String::from).unwrap_or_else(|| file_name_lossy(entry));
+            write_yaml_fields(entry, &name, "  - ", "    ", &mut yaml);
+            yaml.push_str(&format!("    depth: {}\n", entry.depth));
+        }
+    }
+    yaml.push_str("summary:\n");
+    for (key, value) in summary_fields(stats) {
+        yaml.push_str(&format!("  {}: {}\n", key, value));
+    }
+
+    io::stdout().lock().write_all(yaml.as_bytes())
+}
+
+/// Writes `node` as a mapping whose first line starts with `first` and the rest with
+/// `indent`, its children as a sequence below it.
+fn write_yaml_node(node: &TreeNode, name: &str, first: &str, indent: &str, yaml: &mut String) {
+    write_yaml_fields(&node.info, name, first, indent, yaml);
+    if node.info.is_dir {
+        if node.children.is_empty() {
+            yaml.push_str(&format!("{}children: []\n", indent));
+        } else {
+            yaml.push_str(&format!("{}children:\n", indent));
+            let first = format!("{}  - ", indent);
+            let indent = format!("{}    ", indent);
+            for child in &node.children {
+                write_yaml_node(child, &file_name_lossy(&child.info), &first, &indent, yaml);
+            }
+        }
+    }
+}
+
+fn write_yaml_fields(info: &FileInfo, name: &str, first: &str, indent: &str, yaml: &mut String) {
+    yaml.push_str(&format!("{}name: {}\n", first, yaml_string(name)));
+    yaml.push_str(&format!("{}path: {}\n", indent, yaml_string(&info.path.to_string_lossy())));
+    yaml.push_str(&format!("{}type: {}\n", indent, type_name(info.file_type)));
+    yaml.push_str(&format!("{}size: {}\n", indent, info.size));
+    // Quoted so that YAML 1.1 readers keep the time and the octal mode as strings.
+    yaml.push_str(&format!("{}mtime: \"{}\"\n", indent, rfc3339(info.mod_time)));
+    yaml.push_str(&format!("{}mode: \"{:04o}\"\n", indent, info.mode & 0o7777));
+    if let Some(target) = link_target_text(info) {
+        yaml.push_str(&format!("{}target: {}\n", indent, yaml_string(&target)));
+    }
+}
+
+/// A plain scalar when `text` can only be read back as that string, otherwise a
+/// double-quoted one, whose escapes are those of JSON.
+fn yaml_string(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let plain = text.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || "_./".contains(c))
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || "_./-".contains(c))
+        && !matches!(lower.as_str(), "true" | "false" | "yes" | "no" | "on" | "off" | "y" | "n" | "null" | ".inf" | ".nan");
+    if plain { text.to_string() } else { json_string(text) }
+}
+
+fn write_json_node(node: &TreeNode, name: &str, level: usize, json: &mut String) {
+    json.push_str(&format!("{{{}", json_entry_fields(&node.info, name)));
+    if node.info.is_dir {
+        let indent = "  ".repeat(level);
+        json.push_str(",\"children\":[");
+        for (index, child) in node.children.iter().enumerate() {
+            json.push_str(if index == 0 { "\n" } else { ",\n" });
+            json.push_str(&format!("{}  ", indent));
+            write_json_node(child, &file_name_lossy(&child.info), level + 1, json);
+        }
+        if !node.children.is_empty() {
+            json.push_str(&format!("\n{}", indent));
+        }
+        json.push(']');
+    }
+    json.push('}');
+}
+
+/// The type names of `--json` and `--output-format`.
+fn type_name(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::Other => "other",
+        kind => gnu_type_name(kind),
+    }
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Local>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+/// Prints the path of every entry followed by a NUL byte, for `xargs -0` and `fzf --read0`.
+fn print_paths0(entries: Vec<FileInfo>, config: &Config) -> io::Result<()> {
+    let mut paths = Vec::new();
+    for entry in &entries {
+        paths.extend_from_slice(&path_bytes(&get_display_path(&entry.path, config)));
+        paths.push(0);
+    }
+    io::stdout().lock().write_all(&paths)
+}
+
+/// Prints one row per entry with a header row, as CSV (RFC 4180 quoting) or TSV (with
+/// tabs, newlines and backslashes in paths escaped as `\t`, `\n` and `\\`).
+fn print_table(entries: Vec<FileInfo>, format: OutputFormat, config: &Config) -> io::Result<()> {
+    let (separator, quote): (&str, fn(&str) -> String) = match format {
+        OutputFormat::Tsv => ("\t", tsv_field),
+        _ => (",", csv_field),
+    };
+    let mut table = ["path", "type", "size", "mtime", "depth", "permissions"].join(separator);
+    table.push('\n');
+    for entry in &entries {
+        let path = get_display_path(&entry.path, config);
+        let row = [
+            quote(&path.to_string_lossy()),
+            type_name(entry.file_type).to_string(),
+            entry.size.to_string(),
+            rfc3339(entry.mod_time),
+            entry.depth.to_string(),
+            format_permissions(entry),
+        ];
+        table.push_str(&row.join(separator));
+        table.push('\n');
+    }
+    io::stdout().lock().write_all(table.as_bytes())
+}
+
+/// Prints the tree as nested Markdown bullet lists, two spaces of indent per level, with
+/// a `/` after directory names.
+fn print_markdown(root: &TreeNode, config: &Config) -> io::Result<()> {
+    let mut markdown = format!("- {}/\n", markdown_escape(&get_display_path(&root.info.path, config).to_string_lossy()));
+    write_markdown_nodes(&root.children, 1, config, &mut markdown);
+    io::stdout().lock().write_all(markdown.as_bytes())
+}
+
+fn write_markdown_nodes(nodes: &[TreeNode], level: usize, config: &Config, markdown: &mut String) {
+    for node in nodes {
+        let slash = if node.info.is_dir { "/" } else { "" };
+        markdown.push_str(&format!("{}- {}{}\n", "  ".repeat(level), markdown_escape(&display_name(&node.info, config)), slash));
+        write_markdown_nodes(&node.children, level + 1, config, markdown);
+    }
+}
+
+/// Backslash-escapes the characters Markdown would read as formatting in a list item.
+fn markdown_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>#|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Prints the tree with its summary in a fenced code block, without colors, ready to paste
+/// into a README or a pull request.
+fn print_markdown_tree(root: &TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let mut lines = vec![get_display_path(&root.info.path, config).to_string_lossy().into_owned()];
+    markdown_tree_lines(&root.children, "", config, &mut lines);
+    lines.push(String::new());
+    lines.push(format!("{} directories, {} files", stats.directories, stats.files));
+
+    // The fence has to be longer than any run of backticks in the names.
+    let mut longest = 0;
+    for line in &lines {
+        let mut run = 0;
+        for c in line.chars() {
+            run = if c == '`' { run + 1 } else { 0 };
+            longest = longest.max(run);
+        }
+    }
+    let fence = "`".repeat(cmp::max(3, longest + 1));
+    let mut markdown = format!("{}text\n", fence);
+    for line in &lines {
+        markdown.push_str(line);
+        markdown.push('\n');
+    }
+    markdown.push_str(&format!("{}\n", fence));
+    io::stdout().lock().write_all(markdown.as_bytes())
+}
+
+fn markdown_tree_lines(nodes: &[TreeNode], prefix: &str, config: &Config, lines: &mut Vec<String>) {
+    for (index, node) in nodes.iter().enumerate() {
+        let is_last = index == nodes.len() - 1;
+        let indicator = get_type_indicator(&node.info.file_type, config.classify);
+        lines.push(format!("{}{}{}{}", prefix, config.charset.connector(is_last), display_name(&node.info, config), indicator));
+        let child_prefix = format!("{}{}", prefix, config.charset.indent(is_last));
+        markdown_tree_lines(&node.children, &child_prefix, config, lines);
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn tsv_field(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// The members every `--json` entry has, without the surrounding braces.
+fn json_entry_fields(info: &FileInfo, name: &str) -> String {
+    let kind = type_name(info.file_type);
+    let mtime = rfc3339(info.mod_time);
+    let mut fields = format!(
+        "\"name\":{},\"path\":{},\"type\":\"{}\",\"size\":{},\"mtime\":\"{}\",\"mode\":\"{:04o}\"",
+        json_string(name), json_string(&info.path.to_string_lossy()), kind, info.size, mtime, info.mode & 0o7777);
+    if let Some(target) = link_target_text(info) {
+        fields.push_str(&format!(",\"target\":{}", json_string(&target)));
+    }
+    if info.broken_link {
+        fields.push_str(",\"broken\":true");
+    }
+    fields
+}
+
+/// Where a symlink points, for `--json` and `--yaml`.
+fn link_target_text(info: &FileInfo) -> Option<String> {
+    if !info.file_type.is_symlink() {
+        return None;
+    }
+    // Entries loaded with --from-json only have the target they were saved with.
+    fs::read_link(&info.path).map(|target| target.display().to_string()).ok()
+        .or_else(|| info.link_target.as_deref().and_then(|target| target.strip_prefix(" -> ")).map(String::from))
+}
+
+fn file_name_lossy(info: &FileInfo) -> String {
+    info.path.file_name().unwrap_or(info.path.as_os_str()).to_string_lossy().into_owned()
+}
+
+/// Prints GNU tree's `-J` document: an array holding the root and a report object, with
+/// the same field names, type strings and layout.
+fn print_tree_json(root: &TreeNode, stats: &TreeStats, config: &Config, out: &mut impl Write) -> io::Result<()> {
+    let mut json = String::from("[\n");
+    write_tree_json_node(root, &config.root_path, 1, config, &mut json);
+    // GNU tree does not count the root in its report.
+    let directories = if root.info.is_dir { stats.directories.saturating_sub(1) } else { stats.directories };
+    json.push_str("\n,\n  {\"type\":\"report\"");
+    if config.disk_usage {
+        json.push_str(&format!(",\"size\":{}", root.info.size));
+    }
+    json.push_str(&format!(",\"directories\":{}", directories));
+    // Like GNU tree, -d leaves the files out of the report.
+    if !config.dirs_only {
+        json.push_str(&format!(",\"files\":{}", stats.files));
+    }
+    json.push_str("}\n]\n");
+
+    out.write_all(json.as_bytes())
+}
+
+fn write_tree_json_node(node: &TreeNode, name: &str, level: usize, config: &Config, json: &mut String) {
+    let info = &node.info;
+    let indent = "  ".repeat(level);
+    let kind = gnu_type_name(info.file_type);
+
+    json.push_str(&format!("{}{{\"type\":\"{}\",\"name\":{}", indent, kind, json_string(name)));
+    if config.show_size {
+        json.push_str(&format!(",\"size\":{}", info.size));
+    }
+    if info.file_type.is_symlink() {
+        let target = fs::read_link(&info.path).map(|target| target.display().to_string()).unwrap_or_default();
+        json.push_str(&format!(",\"target\":{}", json_string(&target)));
+    }
+
+    if info.is_dir {
+        json.push_str(",\"contents\":[");
+        for (index, child) in node.children.iter().enumerate() {
+            json.push_str(if index == 0 { "\n" } else { ",\n" });
+            let child_name = child.info.path.file_name().unwrap_or_default().to_string_lossy();
+            write_tree_json_node(child, &child_name, level + 1, config, json);
+        }
+        json.push_str(&format!("\n{}]", indent));
+    }
+    json.push('}');
+}
+
+/// The type names GNU tree's JSON and XML output use.
+fn gnu_type_name(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::Directory => "directory",
+        FileKind::Symlink => "link",
+        FileKind::Fifo => "fifo",
+        FileKind::Socket => "socket",
+        FileKind::CharDevice => "char",
+        FileKind::BlockDevice => "block",
+        FileKind::File | FileKind::Other => "file",
+    }
+}
+
+/// Prints GNU tree's `-X` document: a `<tree>` element holding the root, with one element
+/// per entry named after its type, followed by a `<report>`.
+fn print_tree_xml(root: &TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tree>\n");
+    write_tree_xml_node(root, &config.root_path, 1, config, &mut xml);
+    // GNU tree does not count the root in its report.
+    let directories = if root.info.is_dir { stats.directories.saturating_sub(1) } else { stats.directories };
+    xml.push_str("  <report>\n");
+    if config.disk_usage {
+        xml.push_str(&format!("    <size>{}</size>\n", root.info.size));
+    }
+    xml.push_str(&format!("    <directories>{}</directories>\n", directories));
+    if !config.dirs_only {
+        xml.push_str(&format!("    <files>{}</files>\n", stats.files));
+    }
+    xml.push_str("  </report>\n</tree>\n");
+
+    io::stdout().lock().write_all(xml.as_bytes())
+}
+
+fn write_tree_xml_node(node: &TreeNode, name: &str, level: usize, config: &Config, xml: &mut String) {
+    let info = &node.info;
+    let indent = "  ".repeat(level);
+    let kind = gnu_type_name(info.file_type);
+
+    xml.push_str(&format!("{}<{} name=\"{}\"", indent, kind, html_escape(name)));
+    if info.file_type.is_symlink() {
+        let target = fs::read_link(&info.path).map(|target| target.display().to_string()).unwrap_or_default();
+        xml.push_str(&format!(" target=\"{}\"", html_escape(&target)));
+    }
+    if config.show_size {
+        xml.push_str(&format!(" size=\"{}\"", info.size));
+    }
+    xml.push('>');
+
+    if info.is_dir && !node.children.is_empty() {
+        xml.push('\n');
+        for child in &node.children {
+            let child_name = child.info.path.file_name().unwrap_or_default().to_string_lossy();
+            write_tree_xml_node(child, &child_name, level + 1, config, xml);
+        }
+        xml.push_str(&indent);
+    }
+    xml.push_str(&format!("</{}>\n", kind));
+}
+
+/// Quotes a string as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn use_color(config: &Config) -> bool {
+    match config.color {
+        ColorOption::Always => true,
+        ColorOption::Auto => atty::is(atty::Stream::Stdout),
+        ColorOption::Never => false,
+    }
+}
+
+/// Prints the tree or the flat listing chosen by the display mode.
+fn print_entries(root: TreeNode, config: &Config) -> io::Result<()> {
+    match config.display_mode {
+        DisplayMode::Tree => {
+            print_tree_root(&root.info, config)?;
+            print_tree(&root.children, "", config)?;
+        },
+        _ if config.format.is_some() => print_entries_formatted(&flatten_root(root, config), config)?,
+        DisplayMode::OneLine | DisplayMode::Flat => print_entries_oneline(&flatten_root(root, config), config)?,
+        DisplayMode::Long => print_entries_long(&flatten_root(root, config), config)?,
+        DisplayMode::Grid => print_entries_grid(&flatten_root(root, config), config)?,
+    }
+    Ok(())
+}
+
+/// Prints the entries under one header per group, largest group first. Each group is
+/// listed with the flat mode in use; tree mode falls back to one entry per line.
+fn print_grouped(entries: Vec<FileInfo>, group_by: GroupBy, config: &Config) -> io::Result<()> {
+    let mut groups: Vec<(String, Vec<FileInfo>)> = Vec::new();
+    for entry in entries {
+        let key = group_key(&entry, group_by);
+        match groups.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, members)) => members.push(entry),
+            None => groups.push((key, vec![entry])),
+        }
+    }
+
+    let group_size = |members: &[FileInfo]| -> u64 {
+        members.iter().filter(|entry| !entry.is_dir).map(|entry| entry.size).sum()
+    };
+    groups.sort_by(|(a_name, a), (b_name, b)| group_size(b).cmp(&group_size(a)).then_with(|| a_name.cmp(b_name)));
+
+    for (index, (name, members)) in groups.iter().enumerate() {
+        let header = format!("{} ({} {}, {})",
+            name,
+            members.len(),
+            if members.len() == 1 { "entry" } else { "entries" },
+            format_size(group_size(members))
+        );
+        {
+            let mut stdout = io::stdout().lock();
+            if index > 0 {
+                writeln!(stdout)?;
+            }
+            if use_color(config) {
+                writeln!(stdout, "\x1B[1m{}\x1B[0m", header)?;
+            } else {
+                writeln!(stdout, "{}", header)?;
+            }
+        }
+
+        match config.display_mode {
+            _ if config.format.is_some() => print_entries_formatted(members, config)?,
+            DisplayMode::OneLine | DisplayMode::Tree | DisplayMode::Flat => print_entries_oneline(members, config)?,
+            DisplayMode::Long => print_entries_long(members, config)?,
+            DisplayMode::Grid => print_entries_grid(members, config)?,
+        }
+    }
+    Ok(())
+}
+
+/// Lists the most recently modified files, newest first, with how long ago each changed.
+fn print_recent(mut entries: Vec<FileInfo>, count: usize, config: &Config) -> io::Result<()> {
+    entries.retain(|entry| !entry.is_dir);
+    entries.sort_by_key(|entry| cmp::Reverse(entry.mod_time));
+    entries.truncate(count);
+
+    let now = SystemTime::now();
+    let ages: Vec<String> = entries.iter().map(|entry| format_relative_time(entry.mod_time, now)).collect();
+    let age_width = ages.iter().map(|age| age.len()).max().unwrap_or(0);
+
+    let mut stdout = io::stdout().lock();
+    for (entry, age) in entries.iter().zip(&ages) {
+        let formatted_name = format_file_name(&display_name(entry, config), config);
+        let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
+        let icon = get_icon(&entry.path, config);
+        let color = get_color_for_scale(entry, config);
+        let type_indicator = get_type_indicator(&entry.file_type, config.classify);
+        let annotations = entry_annotations(entry);
+
+        write!(stdout, "{}{:>width$}  {}{}{}{}", color, age, icon, hyperlinked_name, type_indicator, annotations, width = age_width)?;
+        write!(stdout, "{}", size_suffix(entry, config))?;
+        writeln!(stdout, "\x1B[0m")?;
+    }
+    Ok(())
+}
+
+/// Describes a time relative to `now`, e.g. `5 minutes ago`.
+fn format_relative_time(time: SystemTime, now: SystemTime) -> String {
+    let seconds = match now.duration_since(time) {
+        Ok(age) => age.as_secs(),
+        Err(_) => return "in the future".to_string(),
+    };
+
+    const UNITS: [(&str, u64); 6] = [
+        ("year", 60 * 60 * 24 * 365),
+        ("month", 60 * 60 * 24 * 30),
+        ("week", 60 * 60 * 24 * 7),
+        ("day", 60 * 60 * 24),
+        ("hour", 60 * 60),
+        ("minute", 60),
+    ];
+    match UNITS.iter().find(|(_, length)| seconds >= *length) {
+        Some((unit, length)) => {
+            let amount = seconds / length;
+            format!("{} {}{} ago", amount, unit, if amount == 1 { "" } else { "s" })
+        },
+        None => "just now".to_string(),
+    }
+}
+
+fn group_key(entry: &FileInfo, group_by: GroupBy) -> String {
+    match group_by {
+        GroupBy::Extension if entry.is_dir => "(directories)".to_string(),
+        GroupBy::Extension => entry.path.extension()
+            .map(|ext| format!(".{}", ext.to_string_lossy().to_lowercase()))
+            .unwrap_or_else(|| "(no extension)".to_string()),
+        GroupBy::Type => entry_kind(entry).to_string(),
+        GroupBy::Owner => match entry.owner {
+            Some((uid, _)) => user_name(uid),
+            None => "(unknown)".to_string(),
+        },
+    }
+}
+
+/// Applies an option's argument, given the option as it was spelled.
+type SetValue = fn(&mut Config, &str, &str) -> Result<(), Box<dyn Error>>;
+/// Applies an option that consumes the arguments after it; returns how many it took.
+type RunCommand = fn(&mut Config, &str, &[String]) -> Result<usize, Box<dyn Error>>;
+
+/// How an option changes the configuration when it is given.
+#[derive(Clone, Copy)]
+enum OptionAction {
+    Switch(fn(&mut Config)),
+    // Prints something instead of the listing and exits.
+    Exit(fn()),
+    Value(SetValue),
+    Command(RunCommand),
+}
+
+/// An entry of the option reference. Parsing, `--help`, the man page and the shell
+/// completions all come from `OPTION_SECTIONS`, and the README's Options section is
+/// checked against it. An entry without names is a note about the options around it.
+#[derive(Clone, Copy)]
+struct OptionSpec {
+    // The spellings shown in the reference, short ones first.
+    names: &'static [&'static str],
+    // Spellings that are accepted and completed, but only mentioned in the help.
+    aliases: &'static [&'static str],
+    // What follows the names in the reference: a placeholder such as `<N>`, or the only
+    // value accepted.
+    value: Option<&'static str>,
+    choices: &'static [&'static str],
+    // Markdown, as in the README.
+    help: &'static str,
+    details: &'static [&'static str],
+    action: OptionAction,
+    // What `--no-NAME` does, for the options it can undo.
+    negate: Option<fn(&mut Config)>,
+}
+
+impl OptionSpec {
+    const fn new(names: &'static [&'static str], value: Option<&'static str>, help: &'static str, action: OptionAction) -> OptionSpec {
+        OptionSpec { names, aliases: &[], value, choices: &[], help, details: &[], action, negate: None }
+    }
+
+    const fn switch(names: &'static [&'static str], help: &'static str, set: fn(&mut Config)) -> OptionSpec {
+        OptionSpec::new(names, None, help, OptionAction::Switch(set))
+    }
+
+    const fn exit(names: &'static [&'static str], help: &'static str, run: fn()) -> OptionSpec {
+        OptionSpec::new(names, None, help, OptionAction::Exit(run))
+    }
+
+    const fn value(
+        names: &'static [&'static str],
+        value: &'static str,
+        help: &'static str,
+        set: SetValue,
+    ) -> OptionSpec {
+        OptionSpec::new(names, Some(value), help, OptionAction::Value(set))
+    }
+
+    const fn command(
+        names: &'static [&'static str],
+        value: &'static str,
+        help: &'static str,
+        run: RunCommand,
+    ) -> OptionSpec {
+        OptionSpec::new(names, Some(value), help, OptionAction::Command(run))
+    }
+
+    const fn note(help: &'static str) -> OptionSpec {
+        OptionSpec::switch(&[], help, |_| {})
+    }
+
+    const fn aliases(self, aliases: &'static [&'static str]) -> OptionSpec {
+        OptionSpec { aliases, ..self }
+    }
+
+    const fn choices(self, choices: &'static [&'static str]) -> OptionSpec {
+        OptionSpec { choices, ..self }
+    }
+
+    const fn details(self, details: &'static [&'static str]) -> OptionSpec {
+        OptionSpec { details, ..self }
+    }
+
+    const fn negate(self, negate: fn(&mut Config)) -> OptionSpec {
+        OptionSpec { negate: Some(negate), ..self }
+    }
+
+    fn is_note(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    fn spellings(&self) -> impl Iterator<Item = &'static str> {
+        self.names.iter().chain(self.aliases).copied()
+    }
+
+    /// The names and value as the reference shows them, e.g. `-L, --level <N>`.
+    fn flags(&self) -> String {
+        match self.value {
+            Some(value) => format!("{} {}", self.names.join(", "), value),
+            None => self.names.join(", "),
+        }
+    }
+
+    /// The name of the value, such as `N` or `FILE`, for the completion scripts.
+    fn placeholder(&self) -> Option<&'static str> {
+        let value = self.value?.split(' ').next().unwrap_or_default();
+        Some(value.trim_start_matches('<').trim_end_matches('>'))
+    }
+
+    fn takes_file(&self) -> bool {
+        self.placeholder().is_some_and(|value| value.contains("FILE"))
+    }
+
+    /// The first sentence of the help, which may contain an `e.g.`, as plain text.
+    fn summary(&self) -> String {
+        let plain = plain_text(self.help);
+        let end = plain
+            .match_indices(". ")
+            .map(|(index, _)| index)
+            .find(|index| !plain[..*index].ends_with("e.g") && !plain[..*index].ends_with("i.e"))
+            .unwrap_or(plain.len());
+        plain[..end].trim_end_matches('.').to_string()
+    }
+}
+
+/// Every option of the reference, in order, without the notes.
+fn documented_options() -> impl Iterator<Item = &'static OptionSpec> {
+    OPTION_SECTIONS.iter().flat_map(|(_, options)| options.iter()).filter(|option| !option.is_note())
+}
+
+fn find_option(name: &str) -> Option<&'static OptionSpec> {
+    documented_options().find(|option| option.spellings().any(|spelling| spelling == name))
+}
+
+/// The option reference, by section.
+static OPTION_SECTIONS: &[(&str, &[OptionSpec])] = &[
+    ("Display Modes", &[
+        OptionSpec::switch(&["-1", "--oneline"], "Display one entry per line", |config| config.display_mode = DisplayMode::OneLine)
+            .negate(|config| if matches!(config.display_mode, DisplayMode::OneLine) {
+                config.display_mode = DisplayMode::Tree;
+            }),
+        OptionSpec::switch(&["-l", "--long"], "Display extended file metadata as a table", |config| config.display_mode = DisplayMode::Long)
+            .negate(|config| if matches!(config.display_mode, DisplayMode::Long) {
+                config.display_mode = DisplayMode::Tree;
+            }),
+        OptionSpec::switch(&["-G", "--grid"], "Display entries as a grid", |config| config.display_mode = DisplayMode::Grid)
+            .negate(|config| if matches!(config.display_mode, DisplayMode::Grid) {
+                config.display_mode = DisplayMode::Tree;
+            }),
+        OptionSpec::switch(&["-T", "--tree"], "Recurse into directories as a tree (default)", |config| config.display_mode = DisplayMode::Tree),
+        OptionSpec::value(&["--charset"], "<CHARSET>", "Characters to draw the tree's branches with (utf-8, ascii). `utf-8` (default) uses box drawing characters, `ascii` draws `|--` and `` `-- ``", |config, _, value| {
+            config.charset = match value.to_ascii_lowercase().as_str() {
+                "utf-8" | "utf8" => Charset::Utf8,
+                "ascii" | "us-ascii" => Charset::Ascii,
+                other => return Err(format!("Invalid charset '{}' (expected utf-8 or ascii)", other).into()),
+            };
+            Ok(())
+        })
+            .choices(&["utf-8", "ascii"]),
+        OptionSpec::value(&["--biggest"], "<K>", "Show only the K largest entries of every directory as a tree, largest first, with their total sizes and `--bars`, like `dust`. Combine with `-L` to limit how deep it goes; sizes still count everything below", |config, flag, value| {
+            config.biggest = Some(parse_positive(value, flag)?);
+            config.display_mode = DisplayMode::Tree;
+            config.disk_usage = true;
+            config.show_size = true;
+            config.bars.get_or_insert(BarScale::Directory);
+            Ok(())
+        }),
+        OptionSpec::switch(&["--flat"], "Recurse into directories and print every entry as a path relative to the root, one per line, like a colorized `find`. Filters, sorting and `--absolute` apply as in the other modes", |config| config.display_mode = DisplayMode::Flat)
+            .negate(|config| if matches!(config.display_mode, DisplayMode::Flat) {
+                config.display_mode = DisplayMode::Tree;
+            }),
+        OptionSpec::switch(&["--print0"], "Print the path of every listed entry ending in a NUL byte instead of drawing anything, for `xargs -0` and `fzf --read0`. Paths are as walked from the root given, or absolute with `--absolute`; filters and sorting apply", |config| config.print0 = true)
+            .negate(|config| config.print0 = false),
+        OptionSpec::value(&["--recent"], "<N>", "Walk the whole tree and list only the N most recently modified files, newest first, each with how long ago it changed (`5 minutes ago`) and its path relative to the root. Filters apply before the files are picked", |config, flag, value| {
+            config.recent = Some(parse_positive(value, flag)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--group-by"], "<KEY>", "List entries under one header per group, with each group's entry count and total size, largest group first. Groups are listed with the flat mode in use (one per line in tree mode)", |config, _, value| {
+            config.group_by = Some(match value {
+                "ext" | "extension" => GroupBy::Extension,
+                "type" => GroupBy::Type,
+                "owner" => GroupBy::Owner,
+                _ => return Err("Invalid group-by option (expected ext, type or owner)".into()),
+            });
+            Ok(())
+        })
+            .choices(&["ext", "type", "owner"])
+            .details(&[
+                "`ext`: By file extension; directories get a group of their own",
+                "`type`: By entry type (`file`, `dir`, `symlink`, `other`)",
+                "`owner`: By owning user",
+            ]),
+    ]),
+    ("Custom Output", &[
+        OptionSpec::value(&["--format"], "<FORMAT>", "Print each entry using a format string instead of the mode's default layout. Tree mode keeps its branches in front of each line; the other modes print one line per entry.", |config, _, value| {
+            config.format = Some(parse_format(value)?);
+            Ok(())
+        })
+            .details(&[
+                "Fields: `{name}`, `{path}`, `{size}`, `{bytes}`, `{mtime}`, `{perms}`, `{type}`, `{depth}`, `{ext}`, `{indicator}`",
+                "More fields: `{relpath}` (relative to the root), `{parent}`, `{target}` (where a symlink points), `{blocks}` (disk usage in KiB), `{mode}` (octal permissions), `{typechar}` (`f`, `d`, `l`, ...), `{user}`, `{group}`, `{uid}`, `{gid}`",
+                "`{field:>8}` right-aligns in 8 columns (`<` left, `^` center); `{mtime:%Y-%m-%d}` takes a strftime format",
+                "`{{` and `}}` print literal braces",
+            ]),
+        OptionSpec::value(&["--printf"], "<FORMAT>", r#"Like `--format`, but with `find -printf` directives, e.g. `--printf '%p %s %TY-%Tm-%Td\n'`"#, |config, _, value| {
+            config.format = Some(parse_printf(value)?);
+            Ok(())
+        })
+            .details(&[
+                "Supported: `%p`, `%P`, `%f`, `%h`, `%l`, `%s`, `%k`, `%d`, `%m`, `%M`, `%y`, `%u`, `%g`, `%U`, `%G`, `%t`, `%T<letter>` (including `%T@` and `%T+`) and `%%`, with optional widths such as `%-20f` or `%10s`",
+                r#"Escapes: `\n`, `\t`, `\r`, `\0`, `\\`; the trailing `\n` is optional since every entry is printed on its own line"#,
+            ]),
+        OptionSpec::value(&["--template"], "<FILE>", "Render the whole report from a template file instead of the listing and summary. The file is split into sections by `{% header %}`, `{% entry %}` and `{% footer %}` lines:", |config, _, value| {
+            config.template = Some(load_template(value)?);
+            Ok(())
+        })
+            .details(&[
+                "the entry section is repeated for every entry and accepts the `--format` fields, plus `{indent}` (two spaces per level below the root)",
+                "the header and footer accept `{root}`, `{directories}`, `{files}`, `{total_size}` and `{total_bytes}`, plus the per-kind counts `{symlinks}`, `{broken_links}`, `{sockets}`, `{fifos}`, `{devices}` and `{errors}`",
+            ]),
+    ]),
+    ("Verification", &[
+        OptionSpec::value(&["--verify-manifest"], "<FILE>", "Compare the tree with a manifest (`-` reads stdin) and print every entry that is missing, not listed (`extra`) or has a different size, followed by a count of each; exits with an error when anything differs. Paths are relative to the root. Accepts plain path lists (`tar tf`, `find`), `SIZE<TAB>PATH` lines (`du -ab`, `--du-format -s`) and verbose tar listings (`tar tvf`). Directories holding listed entries don't need to be listed themselves", |config, _, value| {
+            config.verify_manifest = Some(value.to_string());
+            Ok(())
+        }),
+        OptionSpec::value(&["--manifest"], "sha256", "Print a `sha256sum` compatible `HASH  path` line for every listed file instead of the tree, with paths relative to the root, e.g. `tree --manifest sha256 dist > SUMS`. Symlinks are hashed through to the file they point to; dangling links and links to directories are skipped. Files are hashed on the worker threads (see `--threads`) and all filters apply", |config, _, value| match value {
+            "sha256" => {
+                config.hash_manifest = true;
+                Ok(())
+            },
+            other => Err(format!("Unsupported manifest hash '{}' (expected sha256)", other).into()),
+        })
+            .choices(&["sha256"]),
+        OptionSpec::value(&["--check"], "<FILE>", "Re-hash the files listed in a `sha256sum` manifest (`-` reads stdin; paths relative to the root) and show them in the tree marked `[OK]`, `[FAILED]` or `[MISSING]`, followed by a count of each. Exits with an error unless every file matches", |config, _, value| {
+            config.check = Some(value.to_string());
+            Ok(())
+        }),
+    ]),
+    ("Export", &[
+        OptionSpec::value(&["--ncdu-export"], "<FILE>", "Write the scanned tree in ncdu's JSON export format instead of printing it (`-` writes to stdout). Browse it later with `ncdu -f FILE`, e.g. after scanning on a server or in CI", |config, _, value| {
+            config.ncdu_export = Some(value.to_string());
+            Ok(())
+        }),
+        OptionSpec::switch(&["--du-format"], "Print `SIZE<TAB>PATH` for every entry, contents before their directory and the root last, like `du -a`. Sizes are disk usage in KiB, or apparent sizes in bytes with `-s` (like `du -ab`). As with `du --max-depth`, `-L` limits the lines printed but not the totals", |config| config.du_format = true)
+            .negate(|config| config.du_format = false),
+        OptionSpec::switch(&["--html"], "Print the tree as a self-contained HTML page instead: directories are collapsible (`<details>`), carry a badge with the size of their contents, and a search box filters entries by name, keeping the directories that lead to matches open. Entries are colored by kind as in the terminal, through the CSS classes `dir`, `symlink`, `exec`, `archive` and `image`, and show their icons with `--icons always`. Redirect it to a file to share a scan", |config| config.html = true)
+            .negate(|config| config.html = false),
+        OptionSpec::value(&["--base-href"], "<URL>", "Link every entry in the `--html` page to its path below URL, like GNU tree's `-H`, e.g. `tree --base-href https://example.com/files dist > index.html`. Implies `--html`", |config, _, value| {
+            config.base_href = Some(value.trim_end_matches('/').to_string());
+            config.html = true;
+            Ok(())
+        }),
+        OptionSpec::value(&["--svg"], "<FILE>", "Draw the tree as an SVG graphic instead of printing it (`-` writes to stdout): one box per entry labelled with its size, joined to its directory by lines and shaded from green to red by size", |config, _, value| {
+            config.svg = Some(value.to_string());
+            Ok(())
+        }),
+        OptionSpec::switch(&["--org"], "Print the tree as an Org-mode outline instead: one heading per entry with `TYPE`, `SIZE`, `BYTES` and `MODIFIED` properties (a directory's size is the total of its contents), and the summary counts on the root heading", |config| config.org = true)
+            .negate(|config| config.org = false),
+        OptionSpec::switch(&["--dot"], "Print the directory structure as a Graphviz graph, e.g. `tree --dot src | dot -Tsvg > src.svg`: every directory is a cluster labelled with its name and nested like the tree, with its files as nodes inside it", |config| config.dot = true)
+            .negate(|config| config.dot = false),
+        OptionSpec::switch(&["--mermaid"], "Print the directory structure as a Mermaid flowchart (`graph TD`) with an arrow from every directory to each of its entries, to paste into a fenced `mermaid` code block in GitHub or GitLab Markdown. Limit its size with `-L`", |config| config.mermaid = true)
+            .negate(|config| config.mermaid = false),
+        OptionSpec::value(&["--mermaid-label-width"], "<N>", "Shorten longer node labels to N characters, ending in `…` (default 40; `0` keeps them whole). Implies `--mermaid`", |config, _, value| {
+            config.mermaid_label_width = value.parse().map_err(|_| format!("--mermaid-label-width requires a number, got '{}'", value))?;
+            config.mermaid = true;
+            Ok(())
+        }),
+        OptionSpec::switch(&["--plantuml"], "Print the directory structure as a PlantUML work breakdown structure, with directories as boxes and files as boxless leaves", |config| {
+            config.plantuml.get_or_insert(PlantUmlStyle::Wbs);
+        })
+            .negate(|config| config.plantuml = None),
+        OptionSpec::value(&["--plantuml-style"], "<STYLE>", "PlantUML diagram syntax to use (wbs, mindmap; default wbs). Implies `--plantuml`", |config, _, value| {
+            config.plantuml = Some(match value {
+                "wbs" => PlantUmlStyle::Wbs,
+                "mindmap" => PlantUmlStyle::MindMap,
+                _ => return Err("Invalid PlantUML style (expected wbs or mindmap)".into()),
+            });
+            Ok(())
+        })
+            .choices(&["wbs", "mindmap"]),
+        OptionSpec::switch(&["--export-script"], "Print a POSIX shell script that recreates the listed structure below the current directory with `mkdir -p`, `touch` and `ln -s`, e.g. to scaffold test fixtures from a real tree. File contents are not copied", |config| config.export_script = true)
+            .negate(|config| {
+                config.export_script = false;
+                config.export_perms = false;
+            }),
+        OptionSpec::switch(&["--export-perms"], "Also `chmod` every entry to its current permissions. Implies `--export-script`", |config| {
+            config.export_script = true;
+            config.export_perms = true;
+        })
+            .negate(|config| config.export_perms = false),
+        OptionSpec::value(&["--from-json"], "<FILE>", "Render a tree saved earlier instead of scanning the filesystem (`-` reads stdin), with any display mode. Accepts the output of `--json` and of GNU `tree -J`: nested entries with `name`, `type`, `size`, `mtime` (RFC 3339 or seconds since the epoch) and `children` (or `contents`), or a flat `entries` list with `depth`. A link marked `broken` counts as broken in the summary. Depth limits, hidden-file and pattern filters and sorting apply as for a scan", |config, _, value| {
+            config.from_json = Some(value.to_string());
+            Ok(())
+        }),
+        OptionSpec::switch(&["--fromfile"], "Render a tree from a list of paths instead of scanning the filesystem, like GNU tree's `--fromfile`. PATH is the file holding the list, with standard input read when it is `-` or left out, e.g. `git ls-files | tree --fromfile`. Paths are separated by newlines, or by NULs if there are any (`find -print0`). Every parent of a listed path is shown as a directory, as is a path ending in `/`; the rest are files. Sizes and times are unknown, but depth limits, hidden-file and pattern filters and sorting by name apply", |config| config.from_file = true)
+            .negate(|config| config.from_file = false),
+        OptionSpec::switch(&["-J", "--json"], r#"Print the listing as JSON instead, for `jq` or scripts. In tree mode the document holds the root under `root`, each directory's entries nested in `children`; the other modes give an `entries` array in listing order, each entry with its `depth`. Every entry has `name`, `path`, `type` (`file`, `directory`, `link`, `fifo`, `socket`, `char`, `block` or `other`), `size` in bytes, `mtime` (RFC 3339), `mode` (octal, e.g. `"0644"`) and, for symlinks, `target`, plus `"broken": true` when the target is missing. A `summary` object follows with `directories`, `files`, `total_size` and the per-kind counts of the `Including` line. Filters, sorting and `--du` sizes apply, and `--from-json` reads the document back"#, |config| config.json = Some(JsonSchema::Native))
+            .negate(|config| config.json = None),
+        OptionSpec::switch(&["--ndjson"], "Stream the listing as newline-delimited JSON: one object per entry, with the fields and `depth` of `--json`, written as soon as the entry is read rather than after the whole tree, then a final line holding the `summary`. Entries come out in walk order, each directory before its contents. Depth limits, hidden-file and pattern filters apply; options that need the whole tree first (`--prune`, `--prune-unmatched`, `--files-only`, `--du`, `--biggest`) do not", |config| config.ndjson = true)
+            .negate(|config| config.ndjson = false),
+        OptionSpec::switch(&["--yaml"], "Print the document of `--json` as YAML instead, with the same keys and nesting, for manifests and diffs. Times and modes are quoted so they stay strings", |config| config.yaml = true)
+            .negate(|config| config.yaml = false),
+        OptionSpec::value(&["--json-compat"], "tree", "Print the tree as JSON in exactly GNU `tree -J`'s schema (`type`, `name`, `size` with `-s`, `target`, `contents` and a closing `report` object), so parsers written against `tree -J` work unmodified", |config, _, value| {
+            config.json = Some(match value {
+                "tree" => JsonSchema::Tree,
+                _ => return Err("Invalid JSON compatibility schema (expected tree)".into()),
+            });
+            Ok(())
+        })
+            .choices(&["tree"]),
+        OptionSpec::value(&["--output-format"], "<FORMAT>", "Print the listing in another format instead (csv, tsv, markdown, markdown-tree)", |config, _, value| {
+            config.output_format = Some(match value {
+                "csv" => OutputFormat::Csv,
+                "tsv" => OutputFormat::Tsv,
+                "markdown" | "md" => OutputFormat::Markdown,
+                "markdown-tree" => OutputFormat::MarkdownTree,
+                _ => return Err("Invalid output format (expected csv, tsv, markdown or markdown-tree)".into()),
+            });
+            Ok(())
+        })
+            .choices(&["csv", "tsv", "markdown", "markdown-tree"])
+            .details(&[
+                r#"`csv`, `tsv`: One row per entry for spreadsheets and data pipelines, after a header row: `path`, `type` (as in `--json`), `size` in bytes, `mtime` (RFC 3339), `depth` and `permissions` (`drwxr-xr-x`). CSV fields are quoted as RFC 4180 requires; in TSV, tabs, newlines and backslashes in paths are written as `\t`, `\n` and `\\`"#,
+                "`markdown`: The tree as nested bullet lists, with a `/` after directory names and Markdown syntax in names escaped, for pasting into READMEs and pull requests",
+                "`markdown-tree`: The tree as drawn in the terminal, with its summary, in a fenced code block and without colors (`--charset ascii` also works)",
+            ]),
+        OptionSpec::switch(&["--xml"], "Print the tree as XML in GNU `tree -X`'s format (`-X` is `--dereference` here): a `<tree>` element with one element per entry named after its type (`directory`, `file`, `link`, `fifo`, `socket`, `char`, `block`) carrying `name`, `target` for symlinks and `size` with `-s`, nested like the tree, and a closing `<report>` with the `directories` and `files` counts", |config| config.xml = true)
+            .negate(|config| config.xml = false),
+    ]),
+    ("Running Commands", &[
+        OptionSpec::command(&["--exec"], r#"<COMMAND...> \;"#, "Run COMMAND once for every listed entry instead of printing the listing, with `{}` replaced by the entry's path, like `find -exec`. All filters and sorting apply, so the same options that preview a listing select the entries acted on.", parse_exec),
+        OptionSpec::command(&["--exec-batch"], r#"<COMMAND...> \;"#, r#"Run COMMAND once with all paths, substituted for a `{}` argument or appended at the end. Ending `--exec` with `+` instead of `\;` does the same."#, parse_exec),
+        OptionSpec::switch(&["--dry-run"], "Print the commands `--exec` would run without running them.", |config| config.dry_run = true)
+            .negate(|config| config.dry_run = false),
+        OptionSpec::note("The exit status is non-zero if any command fails."),
+    ]),
+    ("Command Columns", &[
+        OptionSpec::switch(&["--owner"], r#"Add an Owner column to the long view: `user:group` on Unix, and the account owning the file (`DOMAIN\name`, or its SID when the account no longer resolves) on Windows"#, |config| config.show_owner = true)
+            .negate(|config| config.show_owner = false),
+        OptionSpec::switch(&["--acl"], "Mark entries whose ACL grants more than their permission bits show with `[+]`, and add `+` to `{perms}`/`%M` like `ls -l` does. Reads POSIX ACLs on Linux and entries set directly on a file (not inherited) on Windows", |config| config.show_acl = true)
+            .negate(|config| {
+                config.show_acl = false;
+                config.acl_summary = false;
+            }),
+        OptionSpec::switch(&["--acl-summary"], r#"Like `--acl`, but list the extra entries, e.g. `[+ user:alice:rw-, mask::rw-]` or `[+ allow CORP\alice:M]`"#, |config| {
+            config.show_acl = true;
+            config.acl_summary = true;
+        })
+            .negate(|config| config.acl_summary = false),
+        OptionSpec::switch(&["--capabilities"], "Show the Linux file capabilities attached to binaries (the `security.capability` attribute), e.g. `[cap_net_bind_service+ep]`, and highlight those binaries in black on red, like `ls` does. Capabilities grant privileges much like setuid does", |config| config.show_capabilities = true)
+            .negate(|config| config.show_capabilities = false),
+        OptionSpec::switch(&["--flags"], "Add a Flags column to the long view with the file flags set by `chflags` on macOS and FreeBSD, named like `ls -lo` shows them (e.g. `uchg,nodump`, `schg`, `hidden`), or `-` when none are set", |config| config.show_flags = true)
+            .negate(|config| config.show_flags = false),
+        OptionSpec::value(&["--column"], "<NAME=COMMAND>", "Add a column to the long view whose value is the first line printed by COMMAND for each entry, with `{}` replaced by the entry's path (e.g. `--column 'Kind=file --brief {}'`). Can be given several times. Commands run in parallel on the worker threads (see `--threads`), each physical file is only processed once per run, and failing commands show `?`.", |config, _, value| {
+            config.columns.push(parse_command_column(value)?);
+            Ok(())
+        }),
+    ]),
+    ("Sorting and Traversal", &[
+        OptionSpec::value(&["--sort"], "<OPTION>", "Sort entries by the specified criteria", |config, _, value| {
+            config.sort_by = match value {
+                "name" => SortBy::Name,
+                "size" => SortBy::Size,
+                "time" => SortBy::ModTime,
+                "version" => SortBy::Version,
+                "ext" | "extension" => SortBy::Extension,
+                "none" => SortBy::None,
+                _ => return Err("Invalid sort option".into()),
+            };
+            Ok(())
+        })
+            .choices(&["name", "size", "time", "version", "ext", "none"])
+            .details(&[
+                "`name`: Sort by name (default)",
+                "`size`: Sort by size",
+                "`time`: Sort by modification time",
+                "`version`: Sort names naturally, so runs of digits compare by value: `file2` before `file10`, `v1.9.0` before `v1.10.0` (the same as `-v`)",
+                "`ext`: Sort by extension, then by name, so files of one type are listed together; names without an extension come first",
+                "`none`: Keep the order the directory is read in, skipping the sort entirely, which is fastest for enormous directories (`-U` is a shorthand, as in GNU tree)",
+            ]),
+        OptionSpec::switch(&["--dirs-first"], "List directories before files in every directory, whatever the sort key and also with `-r` (`--dirsfirst` is an alias, as in GNU tree)", |config| config.dir_order = DirOrder::First)
+            .aliases(&["--dirsfirst"])
+            .negate(|config| config.dir_order = DirOrder::Mixed),
+        OptionSpec::switch(&["--dirs-last"], "List directories after files in every directory", |config| config.dir_order = DirOrder::Last)
+            .negate(|config| config.dir_order = DirOrder::Mixed),
+        OptionSpec::switch(&["-x", "--across"], "Sort the grid across, rather than downwards", |config| config.sort_across = true)
+            .negate(|config| config.sort_across = false),
+        OptionSpec::switch(&["-R", "--recurse"], "Recurse into directories (applies to non-tree modes)", |config| config.recurse = true)
+            .negate(|config| config.recurse = false),
+        OptionSpec::switch(&["--one-file-system"], "Don't descend into directories on a different filesystem than the root (mount points are still listed), by comparing device numbers, so scanning `/` or a project with bind mounts stays on one volume. `--xdev` is an alias, as in `find`", |config| config.one_file_system = true)
+            .aliases(&["--xdev"])
+            .negate(|config| config.one_file_system = false),
+        OptionSpec::switch(&["--show-mounts"], "Mark directories that are mount points, e.g. `[mounted: ext4 /dev/sdb1]` (filesystem type and source are read from `/proc/mounts` on Linux)", |config| config.show_mounts = true)
+            .negate(|config| config.show_mounts = false),
+        OptionSpec::switch(&["--filesystem"], "Add a Filesystem column to the long view naming the filesystem and device each entry resides on, e.g. `ext4 /dev/sda1` (from `/proc/mounts` on Linux, `statfs` on macOS, the volume information on Windows). Useful together with `--show-mounts` on trees spanning several disks", |config| config.show_filesystem = true)
+            .negate(|config| config.show_filesystem = false),
+        OptionSpec::value(&["--virtual-fs"], "<MODE>", "How to treat kernel pseudo filesystems (names-only, skip, include) such as `/proc`, `/sys`, `debugfs` or `cgroup` (and `devfs` on macOS), whose sizes are made up and where reading some files blocks. `names-only` (default) lists their entries without reading any metadata, so sizes show as 0 and they are left out of color scales and extent checks; `skip` marks such mounts `[virtual filesystem, not read]` without descending into them; `include` reads them like any other directory", |config, _, value| {
+            config.virtual_fs = match value {
+                "include" => VirtualFs::Include,
+                "skip" => VirtualFs::Skip,
+                "names-only" => VirtualFs::NamesOnly,
+                _ => return Err("Invalid virtual-fs option (expected include, skip or names-only)".into()),
+            };
+            Ok(())
+        })
+            .choices(&["names-only", "skip", "include"]),
+        OptionSpec::switch(&["--subvolumes"], "Mark directories that are btrfs subvolumes or snapshots as `[btrfs subvolume]` or `[btrfs snapshot]`, so backup and snapshot layouts stand out (Linux only). With `--show-mounts`, subvolumes are only marked as mounted when they appear in the mount table", |config| config.show_subvolumes = true)
+            .negate(|config| config.show_subvolumes = false),
+    ]),
+    ("Filtering", &[
+        OptionSpec::value(&["--pattern"], "<REGEX>", "Only show files whose name matches the given regex pattern (directories are always shown). For shell wildcards, use `-P`", |config, _, value| {
+            config.pattern = Some(Regex::new(value)?);
+            Ok(())
+        }),
+        OptionSpec::switch(&["--matchdirs"], "Match `--pattern` and `-P` against directory names as well, and list a matching directory with everything inside it, like `tree --matchdirs`. Without it the patterns only select files: every directory is shown, and `--prune-unmatched` hides those without matches", |config| config.match_dirs = true)
+            .negate(|config| config.match_dirs = false),
+        OptionSpec::switch(&["--full-path"], r#"Match `--pattern` against each file's path below the root instead of its name, e.g. `--full-path --pattern 'src/.*\.rs$'`"#, |config| config.full_path = true)
+            .negate(|config| config.full_path = false),
+        OptionSpec::value(&["-P", "--glob"], "<WILDCARD>", "Only show files matching the shell wildcard (`*`, `?`, `[a-z]`, `{rs,toml}`; alternatives separated by `|`, e.g. `'*.rs|*.toml'`), like `tree -P`. A wildcard without `/` is matched against the file name, one with `/` against the path below the root, where `**` spans any number of directories (`-P 'src/**/*.rs'`). Give it more than once to accept more files. With `--pattern` too, a file must match both; `-I` wins over either, and directories are always shown", |config, _, value| {
+            config.glob_patterns.push(value.to_string());
+            config.glob = Some(build_wildcard(&config.glob_patterns, false)?);
+            Ok(())
+        }),
+        OptionSpec::switch(&["--ignore-case"], "Match `--pattern`, `-P` and `-I` without regard to case, so `-P '*.jpg'` also lists `IMG.JPG`", |config| config.ignore_case = true)
+            .negate(|config| config.ignore_case = false),
+        OptionSpec::switch(&["--prune-unmatched"], "With `--pattern` or `-P`, hide directories that contain no matching files anywhere below them, so only the paths leading to matches remain", |config| config.prune_unmatched = true)
+            .negate(|config| config.prune_unmatched = false),
+        OptionSpec::switch(&["--prune"], "Leave out directories with nothing listed inside them once every filter has been applied, including those that only held empty directories, like `tree --prune`", |config| config.prune = true)
+            .negate(|config| config.prune = false),
+        OptionSpec::switch(&["-a", "--all"], "Show hidden files and directories. Flat modes also list the `.` and `..` entries of the root, like `ls -a`", |config| {
+            config.show_hidden = true;
+            config.show_dot_entries = true;
+        })
+            .negate(|config| {
+                config.show_hidden = false;
+                config.show_dot_entries = false;
+            }),
+        OptionSpec::switch(&["-A", "--almost-all"], "Show hidden files and directories, but never `.` and `..` (`--show-hidden` is an alias)", |config| {
+            config.show_hidden = true;
+            config.show_dot_entries = false;
+        })
+            .aliases(&["--show-hidden"])
+            .negate(|config| {
+                config.show_hidden = false;
+                config.show_dot_entries = false;
+            }),
+        OptionSpec::switch(&["--hidden-only"], "Only list hidden entries and everything inside hidden directories", |config| config.hidden_only = true)
+            .negate(|config| config.hidden_only = false),
+        OptionSpec::switch(&["--files-only"], "Don't list directories in flat modes; in tree mode, only keep the directories that lead to files", |config| config.files_only = true)
+            .negate(|config| config.files_only = false),
+        OptionSpec::switch(&["-d", "--dirs-only"], "List only directories, in every display mode, like `tree -d`; the summary then counts directories alone", |config| config.dirs_only = true)
+            .negate(|config| config.dirs_only = false),
+        OptionSpec::switch(&["--entry-counts"], "Mark every directory with the number of entries listed directly inside it, e.g. `src/ [12 entries]`. With `-d`, the files that are left out still count", |config| config.entry_counts = true)
+            .negate(|config| config.entry_counts = false),
+        OptionSpec::switch(&["--show-empty"], "Mark zero-byte files and directories with nothing in them as `[empty]`", |config| config.show_empty = true)
+            .negate(|config| config.show_empty = false),
+        OptionSpec::value(&["--empty-badge"], "<TEXT>", "The mark `--show-empty` uses, e.g. `--empty-badge 'EMPTY - remove?'`. Implies `--show-empty`", |config, _, value| {
+            config.empty_badge = value.to_string();
+            config.show_empty = true;
+            Ok(())
+        }),
+        OptionSpec::value(&["--ext"], "<EXT>", "Only list files with the given extension, ignoring case (`--ext rs` also lists `MAIN.RS`). Repeat it to accept more extensions, e.g. `--ext rs --ext toml`. Combined with other filters, an entry must pass all of them", |config, _, value| {
+            config.extensions.push(value.trim_start_matches('.').to_lowercase());
+            Ok(())
+        }),
+        OptionSpec::value(&["--user"], "<NAME>", "Only list entries owned by the user, given by name or uid (Unix only)", |config, _, value| {
+            config.user = Some(user_id(value)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--group"], "<NAME>", "Only list entries belonging to the group, given by name or gid (Unix only)", |config, _, value| {
+            config.group = Some(group_id(value)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--type"], "<TYPES>", "Only list entries of the given kinds, like `fd --type` (`-t` sorts by time, as in GNU tree). List several separated by commas (`--type f,l`) or give the option more than once; an entry of any of them is listed", |config, _, value| {
+            config.types.extend(parse_type_filters(value)?);
+            Ok(())
+        })
+            .choices(&["f", "file", "d", "dir", "l", "symlink", "x", "executable", "e", "empty"])
+            .details(&[
+                "`f`, `file`: regular files",
+                "`d`, `dir`: directories",
+                "`l`, `symlink`: symbolic links",
+                "`x`, `executable`: files with an execute permission bit set",
+                "`e`, `empty`: empty files and directories",
+            ]),
+        OptionSpec::switch(&["--only-empty"], "Only list empty files and directories, the same as `--type empty`, for cleanup audits", |config| config.types.push(TypeFilter::Empty))
+            .negate(|config| config.types.retain(|kind| *kind != TypeFilter::Empty)),
+        OptionSpec::note("In tree mode, `--hidden-only`, `--ext`, `--user`, `--group`, `--type` and `--only-empty` keep the directories leading to the entries they list, for structure"),
+        OptionSpec::note("A `.treeignore` (or `.rtreeignore`) file in any directory hides matching entries from that directory down, using gitignore syntax (`build/`, `*.o`, `!keep.o`), without affecting git and also outside git repositories. One in the root covers the whole tree; deeper files can re-include what outer ones hide, and `.rtreeignore` wins over a `.treeignore` beside it. An entry is hidden when any of these files, `.gitignore`, `-I` or `--ignore-dir` excludes it"),
+        OptionSpec::value(&["-I", "--ignore"], "<WILDCARD>", "Don't list files or directories whose name matches the wildcard, nor descend into such directories (alternatives separated by `|`). Give it more than once to add patterns, e.g. `-I '*.log' -I target`", |config, _, value| {
+            config.ignore_patterns.push(value.to_string());
+            config.ignore = Some(build_wildcard(&config.ignore_patterns, false)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--ignore-dir"], "<NAME>", "Skip directories with exactly this name, such as `node_modules` or `target`, without reading anything inside them. Repeat it for more names: `--ignore-dir node_modules --ignore-dir target`", |config, _, value| {
+            config.ignore_dirs.push(value.to_string());
+            Ok(())
+        }),
+        OptionSpec::switch(&["--no-treeignore"], "Ignore `.treeignore` and `.rtreeignore` files (`--treeignore` restores the default)", |config| config.treeignore = false)
+            .negate(|config| config.treeignore = true),
+        OptionSpec::note("Inside a git repository, entries that git ignores are hidden, as `fd` and `rg` do: the `.gitignore` files from the top of the repository down and `.git/info/exclude` apply, also when the root is a subdirectory of the repository. Outside a repository `.gitignore` files have no effect"),
+        OptionSpec::switch(&["--no-gitignore"], "List the entries git ignores as well (`--gitignore` restores the default)", |config| config.gitignore = false)
+            .negate(|config| config.gitignore = true),
+    ]),
+    ("Query Expressions", &[
+        OptionSpec::value(&["--where"], "<EXPR>", "Only show entries for which the expression holds. In tree mode the directories leading to matches are kept so every match stays in place.", |config, _, value| {
+            add_filter(config, parse_query(value)?);
+            Ok(())
+        })
+            .details(&[
+                "Numeric fields: `size` (accepts `K`, `M`, `G`, ... suffixes, powers of 1024), `depth`, `age` (time since modification, accepts `s`, `m`, `h`, `d`, `w`, `y`)",
+                r#"`mtime` compares against a date: `mtime > "2024-01-31"` or `"2024-01-31 14:00:00"`"#,
+                "Text fields: `name`, `path`, `ext`, `type` (`file`, `dir`, `symlink` or `other`); compare with `==`/`!=`, or match a regex with `~`/`!~`",
+                r#"Combine with `&&`, `||`, `!` and parentheses, e.g. `--where 'size > 10MB && ext == "log" && depth <= 3'`"#,
+            ]),
+        OptionSpec::value(&["--size"], "<[+|-]N>", "find-style size filter: `+10M` is more than 10 MiB, `-1K` less than 1 KiB, `0` exactly empty", |config, _, value| {
+            add_filter(config, parse_size_predicate(value)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--min-size"], "<SIZE>", "Only show entries of at least the given size, e.g. `--min-size 10M` to find what takes up space. Sizes accept the suffixes of `--size`, as powers of 1024", |config, _, value| {
+            add_filter(config, Expr::Number { field: NumberField::Size, op: CompareOp::Ge, value: parse_size(value)? });
+            Ok(())
+        }),
+        OptionSpec::value(&["--max-size"], "<SIZE>", "Only show entries of at most the given size, e.g. `--max-size 0` for leftover empty files", |config, _, value| {
+            add_filter(config, Expr::Number { field: NumberField::Size, op: CompareOp::Le, value: parse_size(value)? });
+            Ok(())
+        }),
+        OptionSpec::value(&["--mtime"], "<[+|-]N>", "find-style modification age in days: `-7` is within the last week, `+30` more than 30 days ago, `1` between one and two days ago. Other units can be given, e.g. `-12h`", |config, _, value| {
+            add_filter(config, parse_mtime_predicate(value)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--newer-than"], "<TIME>", r#"Only show entries modified after a date (`2024-01-31`, `"2024-01-31 14:00:00"`) or within a duration of now (`3d`; units as for `--where`'s `age`)"#, |config, _, value| {
+            add_filter(config, Expr::Time { op: CompareOp::Gt, value: parse_time_point(value)? });
+            Ok(())
+        }),
+        OptionSpec::value(&["--older-than"], "<TIME>", "Only show entries modified before a date, or longer ago than a duration, e.g. `--older-than 1y`", |config, _, value| {
+            add_filter(config, Expr::Time { op: CompareOp::Lt, value: parse_time_point(value)? });
+            Ok(())
+        }),
+        OptionSpec::value(&["--changed-within"], "<DURATION>", "Only show entries modified within the duration, e.g. `--changed-within 2w` for what changed in the last two weeks", |config, _, value| {
+            add_filter(config, Expr::Number { field: NumberField::Age, op: CompareOp::Le, value: parse_duration(value)?.as_secs() });
+            Ok(())
+        }),
+        OptionSpec::value(&["--depth"], "<N|MIN..MAX>", "Only show entries at depth N, or within a range (`2..4`, `2..`, `..4`)", |config, _, value| {
+            add_filter(config, parse_depth_predicate(value)?);
+            Ok(())
+        }),
+        OptionSpec::note("`--where` and the find-style filters can be repeated; an entry is shown only if all of them hold"),
+    ]),
+    ("Depth Control", &[
+        OptionSpec::value(&["-L", "--level"], "<N>", "Limit the depth of directory traversal (`--max-depth` is an alias). N must be at least 1. Entries of the root are at depth 1, so `-L 1` lists only the root's direct contents. Applies to every display mode (flat modes also need `-R` to go deeper than one level).", |config, flag, value| {
+            config.max_depth = Some(parse_positive(value, flag)?);
+            Ok(())
+        })
+            .aliases(&["--max-depth"]),
+        OptionSpec::value(&["--min-depth"], "<N>", "Only list entries at depth N or deeper, the same as `--depth N..`: `--flat --min-depth 3` lists everything under `src/*/*` but not the top levels. In tree mode the directories above them are kept for structure; the flat modes neither list nor count them", |config, flag, value| {
+            add_filter(config, Expr::Number { field: NumberField::Depth, op: CompareOp::Ge, value: parse_positive(value, flag)? as u64 });
+            Ok(())
+        }),
+        OptionSpec::value(&["--filelimit"], "<N>", "Don't descend into directories holding more than N entries (hidden ones count with `-a`); they are listed marked `[1234 entries exceeds filelimit]`, which keeps the output and the time spent on huge directories bounded, like `tree --filelimit`", |config, flag, value| {
+            config.file_limit = Some(parse_positive(value, flag)?);
+            Ok(())
+        }),
+    ]),
+    ("File Type Indicators", &[
+        OptionSpec::value(&["-F", "--classify"], "<WHEN>", "Display type indicator by file names", |config, _, value| {
+            config.classify = match value {
+                "always" => Classify::Always,
+                "auto" => Classify::Auto,
+                "never" => Classify::Never,
+                _ => return Err("Invalid classify option".into()),
+            };
+            Ok(())
+        })
+            .choices(&["always", "auto", "never"])
+            .details(&[
+                "`always`: Always show type indicators",
+                "`auto`: Show type indicators for directories and symlinks (default)",
+                "`never`: Never show type indicators",
+            ]),
+    ]),
+    ("Color Options", &[
+        OptionSpec::value(&["--color"], "<WHEN>", "When to use terminal colors (always, auto, never). Unless `--color-scale` is given, entries are colored by kind: directories bold blue, symlinks cyan, executables green, archives red and images magenta", |config, _, value| {
+            config.color = match value {
+                "always" => ColorOption::Always,
+                "auto" => ColorOption::Auto,
+                "never" => ColorOption::Never,
+                _ => return Err("Invalid color option".into()),
+            };
+            Ok(())
+        })
+            .aliases(&["--colour"])
+            .choices(&["always", "auto", "never"])
+            .negate(|config| config.color = ColorOption::Never),
+        OptionSpec::value(&["--color-scale"], "<OPTION>", "Highlight levels of 'field' distinctly (all, age, size). With `all`, the hue shows the age (green for new, red for old) and the brightness the size, so small files appear dimmer", |config, _, value| {
+            config.color_scale = Some(match value {
+                "all" => ColorScale::All,
+                "age" => ColorScale::Age,
+                "size" => ColorScale::Size,
+                _ => return Err("Invalid color scale option".into()),
+            });
+            Ok(())
+        })
+            .aliases(&["--colour-scale"])
+            .choices(&["all", "age", "size"])
+            .negate(|config| config.color_scale = None),
+        OptionSpec::value(&["--color-scale-mode"], "<MODE>", "Use gradient or fixed colors in --color-scale (fixed, gradient, relative). `gradient` spans a year of age and 1 GB of size; `relative` stretches the gradient over the oldest and newest, smallest and largest entries actually listed, with sizes on a logarithmic scale", |config, _, value| {
+            config.color_scale_mode = match value {
+                "fixed" => ColorScaleMode::Fixed,
+                "gradient" => ColorScaleMode::Gradient,
+                "relative" => ColorScaleMode::Relative,
+                _ => return Err("Invalid color scale mode".into()),
+            };
+            Ok(())
+        })
+            .aliases(&["--colour-scale-mode"])
+            .choices(&["fixed", "gradient", "relative"]),
+        OptionSpec::value(&["--color-scale-time"], "<TIME>", "Timestamp the age scale reads (modified, accessed, created; default modified). `accessed` suits cache-cleanup scans; entries without the chosen timestamp fall back to their modification time", |config, _, value| {
+            config.color_scale_time = match value {
+                "modified" | "mtime" => ScaleTime::Modified,
+                "accessed" | "atime" => ScaleTime::Accessed,
+                "created" | "birth" => ScaleTime::Created,
+                _ => return Err("Invalid color scale time (expected modified, accessed or created)".into()),
+            };
+            Ok(())
+        })
+            .aliases(&["--colour-scale-time"])
+            .choices(&["modified", "accessed", "created"]),
+    ]),
+    ("Icons", &[
+        OptionSpec::value(&["--icons"], "<WHEN>", "When to display icons (always, auto, never)", |config, _, value| {
+            config.icons = match value {
+                "always" => IconOption::Always,
+                "auto" => IconOption::Auto,
+                "never" => IconOption::Never,
+                _ => return Err("Invalid icons option".into()),
+            };
+            Ok(())
+        })
+            .choices(&["always", "auto", "never"])
+            .negate(|config| config.icons = IconOption::Never),
+    ]),
+    ("File Name Formatting", &[
+        OptionSpec::switch(&["--quote"], "Quote file names with spaces (default; `--quotes` is an alias)", |config| config.quote_names = true)
+            .aliases(&["--quotes"])
+            .negate(|config| config.quote_names = false),
+        OptionSpec::switch(&["--no-quotes"], "Don't quote file names with spaces", |config| config.quote_names = false)
+            .negate(|config| config.quote_names = true),
+    ]),
+    ("Hyperlinks", &[
+        OptionSpec::switch(&["--hyperlink"], "Display entries as hyperlinks", |config| config.hyperlink = true)
+            .negate(|config| config.hyperlink = false),
+    ]),
+    ("Path Display", &[
+        OptionSpec::value(&["--absolute"], "<OPTION>", "Display entries with their absolute path (on, follow, off). `follow` also resolves symbolic links in the path.", |config, _, value| {
+            config.absolute_path = match value {
+                "on" => AbsolutePathOption::On,
+                "follow" => AbsolutePathOption::Follow,
+                "off" => AbsolutePathOption::Off,
+                _ => return Err("Invalid absolute path option".into()),
+            };
+            Ok(())
+        })
+            .choices(&["on", "follow", "off"]),
+    ]),
+    ("Symbolic Links", &[
+        OptionSpec::switch(&["-X", "--dereference"], "Dereference symbolic links when displaying information", |config| config.dereference = true)
+            .negate(|config| config.dereference = false),
+        OptionSpec::switch(&["-H", "--dereference-command-line"], "Follow a symbolic link given as PATH, while links inside the tree are still displayed as links. Without `-H`, `--follow` or `-X`, a symlinked PATH is shown as the link itself.", |config| config.dereference_roots = true)
+            .negate(|config| config.dereference_roots = false),
+        OptionSpec::switch(&["--follow"], "Descend into symbolic links that point to directories. Loops are detected and shown as `[recursive, not followed]` instead of being walked again. A directory reachable through several links is only expanded the first time; later occurrences show `[see ./first/path]` and don't count towards the summary twice.", |config| config.follow = true)
+            .negate(|config| config.follow = false),
+        OptionSpec::switch(&["--resolve-links"], "Show the complete resolution chain of every symbolic link, e.g. `a -> b -> c (final)`. Chains that end in a missing target, a loop or an unreadable link are marked `(missing)`, `(loop)` or `(unreadable: ...)`.", |config| config.resolve_links = true)
+            .negate(|config| config.resolve_links = false),
+    ]),
+    ("File Size", &[
+        OptionSpec::switch(&["--show-size"], "Show file sizes", |config| config.show_size = true)
+            .negate(|config| config.show_size = false),
+        OptionSpec::switch(&["--compression"], "Mark files the filesystem stores compressed (btrfs and ext4 `chattr +c`, macOS `UF_COMPRESSED`, NTFS compression) with `[compressed]`, adding their size on disk where the platform reports it", |config| config.show_compression = true)
+            .negate(|config| config.show_compression = false),
+        OptionSpec::switch(&["--reflinks"], "Read file extent maps (Linux FIEMAP: btrfs, XFS, ext4) to mark files sharing data with reflink copies, deduplicated files or snapshots as `[shared extents: SIZE]`, and add a `Unique data:` line to the summary that counts every shared extent once", |config| config.reflinks = true)
+            .negate(|config| config.reflinks = false),
+        OptionSpec::switch(&["--volume"], "Add a `Volume:` line to the summary with the used and free space of the filesystem holding PATH", |config| config.show_volume = true)
+            .negate(|config| config.show_volume = false),
+        OptionSpec::note("Sparse files, whose allocated blocks fall well short of their apparent size (VM images, databases), are marked with what they take up on disk: `[~1.00 GB (sparse, 12.00 MB on disk)]`"),
+        OptionSpec::switch(&["--du"], "Show each directory's size as the total of everything listed below it plus its own, counting the levels `-L` hides, and sort by those totals with `--sort size`. The summary's total size is then the root's total. Implies `--show-size`", |config| {
+            config.disk_usage = true;
+            config.show_size = true;
+        })
+            .negate(|config| {
+                config.disk_usage = false;
+                config.percent = false;
+                config.bars = None;
+            }),
+        OptionSpec::switch(&["--percent"], "Annotate each size with its share of the parent directory, e.g. `src/ [45.20 MB (62%)]`. Implies `--du`", |config| {
+            config.disk_usage = true;
+            config.show_size = true;
+            config.percent = true;
+        })
+            .negate(|config| config.percent = false),
+        OptionSpec::switch(&["--bars"], "Draw a small bar after each size showing its share of the parent directory, e.g. `[45.20 MB] │██████▏   │`. Implies `--du`", |config| {
+            config.disk_usage = true;
+            config.show_size = true;
+            config.bars.get_or_insert(BarScale::Directory);
+        })
+            .negate(|config| config.bars = None),
+        OptionSpec::value(&["--bar-scale"], "<SCALE>", "What the bars are scaled against (dir, global): each directory (`dir`, default) or the whole listing (`global`). Implies `--bars`", |config, _, value| {
+            config.bars = Some(match value {
+                "dir" | "directory" => BarScale::Directory,
+                "global" => BarScale::Global,
+                _ => return Err("Invalid bar scale (expected dir or global)".into()),
+            });
+            config.disk_usage = true;
+            config.show_size = true;
+            Ok(())
+        })
+            .choices(&["dir", "global"]),
+    ]),
+    ("Screen Width", &[
+        OptionSpec::value(&["-w", "--width"], "<COLS>", "Set screen width in columns", |config, _, value| {
+            config.screen_width = Some(value.parse()?);
+            Ok(())
+        }),
+    ]),
+    ("Performance", &[
+        OptionSpec::value(&["--threads"], "<N>", "Number of worker threads (defaults to the number of available CPUs)", |config, flag, value| {
+            config.threads = Some(parse_positive(value, flag)?);
+            Ok(())
+        }),
+        OptionSpec::value(&["--io-concurrency"], "<N>", "Maximum number of concurrent filesystem calls (defaults to the thread count)", |config, flag, value| {
+            config.io_concurrency = Some(parse_positive(value, flag)?);
+            Ok(())
+        }),
+        OptionSpec::switch(&["--trace"], "Log every directory visited to stderr as `key=value` lines: entries found and listed, how many each filter (hidden, `-I`, `.treeignore`, patterns) dropped, and the time spent reading it, plus directories that could not be opened and what the tree-wide filters kept. Setting `RUST_TREE_LOG` (to anything but `0` or `off`) does the same", |config| config.trace = true)
+            .negate(|config| config.trace = false),
+        OptionSpec::value(&["--timeout"], "<DURATION>", "Stop reading directories once the time is up (e.g. `30s`, `5m`, or plain seconds). What was gathered is still printed: directories left unread are marked `[not read: timed out]`, the summary ends with `Listing truncated: ...`, and tree exits with status 124 (like `timeout`), so cron jobs on slow network mounts can tell a partial listing apart", |config, _, value| {
+            config.timeout = Some(parse_duration(value)?);
+            Ok(())
+        }),
+    ]),
+    ("GNU tree Compatibility", &[
+        OptionSpec::note("These short flags behave like their GNU tree counterparts, so existing scripts keep working"),
+        OptionSpec::switch(&["-s"], "Show sizes in bytes", |config| {
+            config.show_size = true;
+            config.raw_sizes = !config.human_sizes;
+        }),
+        OptionSpec::switch(&["-h"], "Show sizes in human readable form (takes precedence over `-s`)", |config| {
+            config.show_size = true;
+            config.raw_sizes = false;
+            config.human_sizes = true;
+        }),
+        OptionSpec::switch(&["-t"], "Sort by modification time", |config| config.sort_by = SortBy::ModTime),
+        OptionSpec::switch(&["-r"], "Reverse the sort order", |config| config.reverse_sort = true),
+        OptionSpec::switch(&["-v"], "Sort version numbers within names naturally (`file2` before `file10`)", |config| config.sort_by = SortBy::Version),
+        OptionSpec::switch(&["-U"], "Leave entries unsorted, in directory order (`--sort none`)", |config| config.sort_by = SortBy::None),
+        OptionSpec::switch(&["-C"], "Always use colors", |config| config.color = ColorOption::Always),
+        OptionSpec::switch(&["-n"], "Never use colors", |config| config.color = ColorOption::Never),
+    ]),
+    ("Help", &[
+        OptionSpec::exit(&["--help"], "Print this option reference and exit", || {
+            print!("{}", help_text());
+            process::exit(0);
+        }),
+        OptionSpec::exit(&["--version"], "Print the version and exit", || {
+            println!("tree {}", env!("CARGO_PKG_VERSION"));
+            process::exit(0);
+        }),
+        OptionSpec::exit(&["--generate-man"], "Print this documentation as a roff man page and exit, e.g. `tree --generate-man > /usr/local/share/man/man1/tree.1`", || {
+            print!("{}", man_page());
+            process::exit(0);
+        }),
+        OptionSpec::note("Options are checked strictly: an unknown option is an error rather than being taken as PATH. Use `--` to pass a PATH that starts with a dash, e.g. `tree -- -odd-name`"),
+        // Handled in main, before the config file's settings are merged in.
+        OptionSpec::switch(&["--no-config"], "Don't read the config file", |_| {}),
+        // Expanded in main; only left in when checking a profile's settings.
+        OptionSpec::value(&["--profile"], "<NAME>", "Apply the options of a profile defined in the config file (see Configuration File) at this point of the command line. Can be given more than once", |_, _, _| Ok(())),
+        OptionSpec::note("Every switch can be turned off again by putting `no-` in front of its long name, so a default set in the config file or the environment can be overridden for one run: `--no-show-size`, `--no-hyperlink`, `--no-all`, `--no-du` and so on. `--no-icons` and `--no-color` mean `never`, `--no-color-scale` drops the color scale, `--no-json` goes back to the text listing, and `--no-oneline`, `--no-long`, `--no-grid` and `--no-flat` go back to the tree. Only `--tree` and the options that print something else and exit have no `--no-` form"),
+        OptionSpec::note("The `--color*` options can also be spelled `--colour*`"),
+        OptionSpec::note("Single-letter options can be bundled (`-la` is `-l -a`), and a bundle may end in one that takes a value, attached or not (`-aL2`, `-aL 2`). Long options take their value either as the next argument or attached with `=` (`--level=2`)"),
+    ]),
+];
+
+fn parse_args(args: &[String]) -> Result<Config, Box<dyn Error>> {
+    let mut config = Config {
+        max_depth: None,
+        file_limit: None,
+        show_hidden: false,
+        root_path: String::from("."),
+        sort_by: SortBy::Name,
+        pattern: None,
+        glob: None,
+        glob_patterns: Vec::new(),
+        ignore: None,
+        ignore_patterns: Vec::new(),
+        ignore_dirs: Vec::new(),
+        show_size: false,
+        display_mode: DisplayMode::Tree, // Changed default to Tree
+        classify: Classify::Auto,
+        dereference: false,
+        color: ColorOption::Auto,
+        color_scale: None,
+        color_scale_mode: ColorScaleMode::Fixed,
+        icons: IconOption::Auto,
+        quote_names: true,
+        hyperlink: false,
+        absolute_path: AbsolutePathOption::Off,
+        screen_width: None,
+        sort_across: false,
+        recurse: false,
+        threads: None,
+        io_concurrency: None,
+        one_file_system: false,
+        show_mounts: false,
+        follow: false,
+        dereference_roots: false,
+        resolve_links: false,
+        files_only: false,
+        dirs_only: false,
+        entry_counts: false,
+        show_empty: false,
+        empty_badge: "empty".to_string(),
+        hidden_only: false,
+        show_dot_entries: false,
+        raw_sizes: false,
+        human_sizes: false,
+        reverse_sort: false,
+        dir_order: DirOrder::Mixed,
+        format: None,
+        template: None,
+        where_expr: None,
+        types: Vec::new(),
+        extensions: Vec::new(),
+        user: None,
+        group: None,
+        columns: Vec::new(),
+        exec: None,
+        dry_run: false,
+        group_by: None,
+        recent: None,
+        disk_usage: false,
+        percent: false,
+        bars: None,
+        biggest: None,
+        ncdu_export: None,
+        du_format: false,
+        html: false,
+        base_href: None,
+        xml: false,
+        ndjson: false,
+        yaml: false,
+        print0: false,
+        dot: false,
+        mermaid: false,
+        mermaid_label_width: 40,
+        output_format: None,
+        svg: None,
+        org: false,
+        plantuml: None,
+        export_script: false,
+        export_perms: false,
+        from_json: None,
+        from_file: false,
+        json: None,
+        verify_manifest: None,
+        hash_manifest: false,
+        check: None,
+        show_compression: false,
+        reflinks: false,
+        show_volume: false,
+        show_subvolumes: false,
+        show_flags: false,
+        show_owner: false,
+        show_acl: false,
+        acl_summary: false,
+        show_capabilities: false,
+        show_filesystem: false,
+        scale_range: None,
+        treeignore: true,
+        gitignore: true,
+        prune_unmatched: false,
+        prune: false,
+        full_path: false,
+        match_dirs: false,
+        ignore_case: false,
+        color_scale_time: ScaleTime::Modified,
+        timeout: None,
+        virtual_fs: VirtualFs::NamesOnly,
+        charset: Charset::Utf8,
+        trace: env::var("RUST_TREE_LOG").is_ok_and(|level| !matches!(level.as_str(), "" | "0" | "off")),
+    };
+
+    // Attached values and short option bundles are split into separate arguments as they
+    // are reached, so options and their values are only ever matched whole.
+    let mut args = args.to_vec();
+    // An argument split off the one before it: (index, whether it came from a bundle).
+    let mut attached: Option<(usize, bool)> = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        // The option before it did not take the split-off argument as its value.
+        if let Some((index, from_bundle)) = attached.filter(|(index, _)| *index <= i) {
+            attached = None;
+            if index == i && from_bundle {
+                args[i] = format!("-{}", args[i]);
+            } else if index == i {
+                return Err(format!("{} does not take a value", args[i - 1]).into());
+            }
+        }
+
+        let arg = args[i].clone();
+        match arg.as_str() {
+            // Everything after `--` is a path, even when it starts with a dash.
+            "--" => {
+                if let Some(path) = args[i + 1..].last() {
+                    config.root_path = path.clone();
+                }
+                break;
+            }
+            // `--max-depth=3`
+            arg if arg.starts_with("--") && arg.contains('=') => {
+                let (flag, value) = arg.split_once('=').unwrap_or_default();
+                args.splice(i..=i, [flag.to_string(), value.to_string()]);
+                attached = Some((i + 1, false));
+                continue;
+            }
+            // `-laT`, or `-L3` where the rest is the option's value.
+            arg if arg.starts_with('-') && !arg.starts_with("--") && arg.chars().count() > 2 => {
+                let split = arg.char_indices().nth(2).map_or(arg.len(), |(index, _)| index);
+                let (flag, rest) = arg.split_at(split);
+                args.splice(i..=i, [flag.to_string(), rest.to_string()]);
+                attached = Some((i + 1, true));
+                continue;
+            }
+            arg if arg.starts_with('-') && arg != "-" => match find_option(arg).map(|option| option.action) {
+                Some(OptionAction::Switch(set)) => set(&mut config),
+                Some(OptionAction::Exit(run)) => run(),
+                Some(OptionAction::Value(set)) => {
+                    i += 1;
+                    let value = args.get(i).ok_or_else(|| format!("{} requires a value", arg))?;
+                    set(&mut config, arg, value)?;
+                }
+                Some(OptionAction::Command(run)) => i += run(&mut config, arg, &args[i + 1..])?,
+                None => match negation(arg) {
+                    Some(negate) => negate(&mut config),
+                    None => return Err(format!("Unknown option '{}' (see tree --help)", arg).into()),
+                },
+            },
+            _ => {
+                config.root_path = args[i].clone();
+            }
+        }
+        i += 1;
+    }
+
+    // Compiled again once all options are read, so --ignore-case may come after the patterns.
+    if config.ignore_case {
+        if let Some(pattern) = &config.pattern {
+            config.pattern = Some(RegexBuilder::new(pattern.as_str()).case_insensitive(true).build()?);
+        }
+        if config.glob.is_some() {
+            config.glob = Some(build_wildcard(&config.glob_patterns, true)?);
+        }
+        if config.ignore.is_some() {
+            config.ignore = Some(build_wildcard(&config.ignore_patterns, true)?);
+        }
+    }
+
+    Ok(config)
+}
+
+/// What `--no-NAME` does: turns off what `--NAME` turns on, so a default from the config
+/// file or the environment can be overridden. For the options documented as `--no-NAME`,
+/// `--NAME` restores the default instead.
+fn negation(arg: &str) -> Option<fn(&mut Config)> {
+    let name = arg.strip_prefix("--")?;
+    let option = match name.strip_prefix("no-") {
+        Some(name) => find_option(&format!("--{}", name)),
+        None => find_option(&format!("--no-{}", name)),
+    };
+    option.and_then(|option| option.negate)
+}
+
+/// Reads the command of `--exec` and `--exec-batch`, up to its `;` or `+`, and returns how
+/// many arguments it took.
+fn parse_exec(config: &mut Config, flag: &str, rest: &[String]) -> Result<usize, Box<dyn Error>> {
+    let end = rest.iter().position(|arg| arg == ";" || arg == "+")
+        .ok_or_else(|| format!("{} must be terminated by ';' or '+'", flag))?;
+    if end == 0 {
+        return Err(format!("{} requires a command", flag).into());
+    }
+    config.exec = Some(ExecAction {
+        command: rest[..end].to_vec(),
+        // Like find, a '+' terminator batches the paths into one invocation.
+        batch: flag == "--exec-batch" || rest[end] == "+",
+    });
+    Ok(end + 1)
+}
+
+/// Locates the config file: `$RTREE_CONFIG` if set, otherwise `rtree/config.toml` under
+/// `$XDG_CONFIG_HOME` or `~/.config`. Only an explicitly named file has to exist.
+fn config_file_path() -> Option<(PathBuf, bool)> {
+    if let Some(path) = env::var_os("RTREE_CONFIG").filter(|path| !path.is_empty()) {
+        return Some((PathBuf::from(path), true));
+    }
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some((base.join("rtree").join("config.toml"), false))
+}
+
+/// The config file's settings, as the command-line options they stand for.
+#[derive(Default)]
+struct ConfigFile {
+    defaults: Vec<String>,
+    // The `[profile.NAME]` tables, applied with `--profile NAME`.
+    profiles: HashMap<String, Vec<String>>,
+}
+
+fn load_config_file() -> Result<ConfigFile, Box<dyn Error>> {
+    let Some((path, required)) = config_file_path() else {
+        return Ok(ConfigFile::default());
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(ConfigFile::default()),
+        Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+    };
+    parse_config_file(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Puts the option layers in the order `parse_args` reads them, with profiles expanded.
+/// Later options win, so defaults come first: the config file, then the environment,
+/// then the command line (whose first element is the program name).
+fn layered_args(config_file: &ConfigFile, env_options: Vec<String>, command_line: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut args = config_file.defaults.clone();
+    args.extend(env_options);
+    args.extend(command_line.iter().skip(1).cloned());
+    let mut args = expand_profiles(&args, &config_file.profiles, &mut Vec::new())?;
+    args.insert(0, command_line.first().cloned().unwrap_or_default());
+    Ok(args)
+}
+
+/// Replaces every `--profile NAME` with the options of that profile, expanding the
+/// profiles it selects in turn. `active` holds the profiles being expanded, to catch cycles.
+fn expand_profiles(args: &[String], profiles: &HashMap<String, Vec<String>>, active: &mut Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut expanded = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let name = match arg.strip_prefix("--profile=") {
+            Some(name) => name,
+            None if arg == "--profile" => rest.next().ok_or("--profile requires a value")?,
+            None => {
+                expanded.push(arg.clone());
+                // Whatever follows `--` is a path.
+                if arg == "--" {
+                    expanded.extend(rest.cloned());
+                    break;
+                }
+                continue;
+            }
+        };
+        if active.iter().any(|profile| profile == name) {
+            return Err(format!("Profile '{}' selects itself: {} -> {}", name, active.join(" -> "), name).into());
+        }
+        let Some(options) = profiles.get(name) else {
+            let mut known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            known.sort_unstable();
+            return Err(match known.len() {
+                0 => format!("Unknown profile '{}': the config file defines no profiles", name),
+                _ => format!("Unknown profile '{}' (defined: {})", name, known.join(", ")),
+            }
+            .into());
+        };
+        active.push(name.to_string());
+        expanded.extend(expand_profiles(options, profiles, active)?);
+        active.pop();
+    }
+    Ok(expanded)
+}
+
+/// Environment variables that stand in for an option, for CI and shared shell setups.
+const ENV_OPTIONS: [(&str, &str); 4] = [
+    ("RTREE_COLORS", "--color"),
+    ("RTREE_ICONS", "--icons"),
+    ("RTREE_TREE_CHARSET", "--charset"),
+    ("RTREE_DEFAULT_DEPTH", "--level"),
+];
+
+/// Reads the `ENV_OPTIONS` variables that are set, as `var` looks them up, into the
+/// options they stand for.
+fn env_args(var: impl Fn(&str) -> Option<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut args = Vec::new();
+    for (variable, option) in ENV_OPTIONS {
+        let Some(value) = var(variable).filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        let setting = [option.to_string(), value];
+        parse_args(&[String::new(), setting[0].clone(), setting[1].clone()]).map_err(|e| format!("{}: {}", variable, e))?;
+        args.extend(setting);
+    }
+    Ok(args)
+}
+
+/// Translates config file settings into options. Each `key = value` line names a long
+/// option: `true` passes the switch, `false` its `--no-` form, a string or number is its value,
+/// and an array repeats the option once per element. Keys may use `_` for `-`. Settings
+/// below a `[profile.NAME]` table go to that profile instead of the defaults.
+fn parse_config_file(text: &str) -> Result<ConfigFile, String> {
+    let mut file = ConfigFile::default();
+    // The profile whose table the lines belong to, if any.
+    let mut profile: Option<String> = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |message: String| format!("line {}: {}", number + 1, message);
+        if let Some(table) = line.strip_prefix('[') {
+            let table = table.split('#').next().unwrap_or_default().trim_end();
+            let name = table
+                .strip_suffix(']')
+                .and_then(|table| table.trim().strip_prefix("profile."))
+                .map(|name| name.trim().trim_matches('"'))
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| fail("the only tables supported are profiles, as in `[profile.NAME]`".to_string()))?;
+            file.profiles.entry(name.to_string()).or_default();
+            profile = Some(name.to_string());
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| fail("expected `key = value`".to_string()))?;
+        let key = key.trim().trim_matches('"').replace('_', "-");
+        let option = if key.chars().count() == 1 { format!("-{}", key) } else { format!("--{}", key) };
+        // Checking the line runs the option, so the ones that print and exit are refused first.
+        let exits = find_option(&option).is_some_and(|option| matches!(option.action, OptionAction::Exit(_)));
+        if key.is_empty() || key.starts_with('-') || key == "no-config" || exits {
+            return Err(fail(format!("'{}' cannot be set in the config file", key)));
+        }
+
+        let mut setting = Vec::new();
+        for value in parse_config_value(value.trim()).map_err(&fail)? {
+            match value {
+                ConfigValue::Bool(true) => setting.push(option.clone()),
+                // Turned off explicitly, in case the switch is on by default.
+                ConfigValue::Bool(false) if option.starts_with("--") => setting.push(match key.strip_prefix("no-") {
+                    Some(switch) => format!("--{}", switch),
+                    None => format!("--no-{}", key),
+                }),
+                ConfigValue::Bool(false) => {}
+                ConfigValue::Text(text) => setting.extend([option.clone(), text]),
+            }
+        }
+        // Checked on its own, so a mistake is reported against its line.
+        let mut check = vec![String::new()];
+        check.extend(setting.iter().cloned());
+        parse_args(&check).map_err(|e| fail(e.to_string()))?;
+        match &profile {
+            Some(name) => file.profiles.entry(name.clone()).or_default().extend(setting),
+            None => file.defaults.extend(setting),
+        }
+    }
+    Ok(file)
+}
+
+enum ConfigValue {
+    Bool(bool),
+    Text(String),
+}
+
+/// Parses a TOML value: a string, integer, float, boolean, or a one-line array of those.
+fn parse_config_value(value: &str) -> Result<Vec<ConfigValue>, String> {
+    let mut chars = value.chars().peekable();
+    let mut values = Vec::new();
+    let in_array = chars.peek() == Some(&'[');
+    if in_array {
+        chars.next();
+    }
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace() || (in_array && *c == ',')) {
+            chars.next();
+        }
+        match chars.peek().copied() {
+            None | Some('#') if !in_array => return Err("missing value".to_string()),
+            None | Some('#') => return Err("unterminated array".to_string()),
+            Some(']') if in_array => {
+                chars.next();
+                break;
+            }
+            Some(quote @ ('"' | '\'')) => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".to_string()),
+                        Some(c) if c == quote => break,
+                        // Literal ('...') strings have no escapes.
+                        Some('\\') if quote == '"' => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(c @ ('"' | '\\')) => text.push(c),
+                            _ => return Err("unsupported escape in string".to_string()),
+                        },
+                        Some(c) => text.push(c),
+                    }
+                }
+                values.push(ConfigValue::Text(text));
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = chars.peek().copied().filter(|c| !c.is_whitespace() && !matches!(c, ',' | ']' | '#')) {
+                    word.push(c);
+                    chars.next();
+                }
+                values.push(match word.as_str() {
+                    "true" => ConfigValue::Bool(true),
+                    "false" => ConfigValue::Bool(false),
+                    number if number.replace('_', "").parse::<f64>().is_ok() => ConfigValue::Text(number.replace('_', "")),
+                    _ => return Err(format!("invalid value '{}' (strings must be quoted)", word)),
+                });
+            }
+        }
+        if !in_array {
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.peek().is_some_and(|c| *c != '#') {
+                return Err("unexpected text after the value".to_string());
+            }
+            break;
+        }
+    }
+    if in_array && chars.find(|c| !c.is_whitespace()).is_some_and(|c| c != '#') {
+        return Err("unexpected text after the array".to_string());
+    }
+    Ok(values)
+}
+
+// The sections of the man page other than the options come from the README.
+const README: &str = include_str!("../docs/README.md");
+const HELP_WIDTH: usize = 80;
+
+/// Renders the option reference as plain text: each option with its description wrapped
+/// and indented below it.
+fn help_text() -> String {
+    let mut help = String::from("Usage: tree [OPTIONS] [PATH]\n\nLists the contents of PATH (the current directory by default) as a tree.\n");
+    for (heading, options) in OPTION_SECTIONS {
+        help.push_str(&format!("\n{}:\n", heading));
+        for option in *options {
+            if option.is_note() {
+                help.push_str(&wrap_help(&plain_text(option.help), 2, 2));
+                continue;
+            }
+            help.push_str(&format!("  {}\n", option.flags()));
+            help.push_str(&wrap_help(&plain_text(option.help), 6, 6));
+            for detail in option.details {
+                help.push_str(&wrap_help(&format!("- {}", plain_text(detail)), 8, 10));
+            }
+        }
+    }
+    help
+}
+
+/// The option reference as the README's Options section lists it.
+#[cfg(test)]
+fn options_markdown() -> String {
+    let mut markdown = String::new();
+    for (heading, options) in OPTION_SECTIONS {
+        markdown.push_str(&format!("\n### {}\n", heading));
+        for option in *options {
+            if option.is_note() {
+                markdown.push_str(&format!("- {}\n", option.help));
+                continue;
+            }
+            markdown.push_str(&format!("- `{}`: {}\n", option.flags(), option.help));
+            for detail in option.details {
+                markdown.push_str(&format!("  - {}\n", detail));
+            }
+        }
+    }
+    markdown
+}
+
+/// Markdown `code` spans, including ``` `` `-- `` ``` ones that contain a backtick.
+fn code_spans() -> &'static Regex {
+    static CODE: OnceLock<Regex> = OnceLock::new();
+    CODE.get_or_init(|| Regex::new(r"``\s?(.+?)\s?``|`([^`]+)`").unwrap())
+}
+
+/// Converts Markdown text to plain text by dropping the delimiters of its code spans.
+fn plain_text(text: &str) -> String {
+    code_spans().replace_all(text, "$1$2").into_owned()
+}
+
+/// Word-wraps `text` to the help width, indenting the first line by `indent` spaces and
+/// the rest by `hanging`.
+fn wrap_help(text: &str, indent: usize, hanging: usize) -> String {
+    let mut wrapped = String::new();
+    let mut line = String::new();
+    let mut indent = indent;
+    for word in text.split_whitespace() {
+        if !line.is_empty() && indent + line.chars().count() + 1 + word.chars().count() > HELP_WIDTH {
+            wrapped.push_str(&format!("{}{}\n", " ".repeat(indent), line));
+            line.clear();
+            indent = hanging;
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        wrapped.push_str(&format!("{}{}\n", " ".repeat(indent), line));
+    }
+    wrapped
+}
+
+/// Renders the README as a tree(1) man page: the overview becomes the description, and
+/// every later section (options, configuration, examples, ...) one section of the page.
+fn man_page() -> String {
+    let mut page = format!(".TH TREE 1 \"\" \"tree {}\" \"User Commands\"\n", env!("CARGO_PKG_VERSION"));
+    page.push_str(".SH NAME\ntree \\- list the contents of directories as a tree\n");
+    page.push_str(".SH SYNOPSIS\n.B tree\n[\\fIOPTIONS\\fR] [\\fIPATH\\fR]\n");
+
+    let mut in_code = false;
+    // How far the open code block's fence is indented, which its lines are too.
+    let mut code_indent = 0;
+    let mut in_usage = false;
+    let mut in_options = false;
+    for line in README.lines() {
+        let trimmed = line.trim_start();
+        if in_options && !line.starts_with("## ") {
+            // Rendered from the option table instead.
+        } else if in_usage && (in_code || trimmed.starts_with("```")) {
+            // The usage line itself is the synopsis.
+            in_code ^= trimmed.starts_with("```");
+        } else if trimmed.starts_with("```") {
+            page.push_str(if in_code { ".fi\n.RE\n" } else { ".RS 4\n.nf\n" });
+            in_code = !in_code;
+            code_indent = line.len() - trimmed.len();
+        } else if in_code {
+            let indent = line.len() - line.trim_start_matches(' ').len();
+            page.push_str(&roff_line(&roff_escape(&line[indent.min(code_indent)..])));
+        } else if let Some(section) = line.strip_prefix("## ") {
+            in_usage = section == "Usage";
+            in_options = section == "Options";
+            match section {
+                "Overview" => page.push_str(".SH DESCRIPTION\n"),
+                // Part of the synopsis, with the note below it kept as a paragraph.
+                "Usage" => {}
+                "Configuration File" => page.push_str(".SH FILES\n"),
+                "Environment Variables" => page.push_str(".SH ENVIRONMENT\n"),
+                "Options" => page.push_str(&format!(".SH OPTIONS\n{}", options_roff())),
+                _ => page.push_str(&format!(".SH {}\n", section.to_uppercase())),
+            }
+        } else if let Some(heading) = line.strip_prefix("### ") {
+            page.push_str(&format!(".SS {}\n", roff_escape(heading)));
+        } else if let Some(detail) = line.strip_prefix("  - ") {
+            page.push_str(&format!(".RS\n.IP \\(bu 2\n{}.RE\n", roff_line(&roff_inline(detail))));
+        } else if let Some(item) = line.strip_prefix("- ") {
+            match item.strip_prefix('`').and_then(|item| item.split_once("`: ")) {
+                Some((flags, description)) => {
+                    page.push_str(&format!(".TP\n{}\n{}", roff_flags(flags), roff_line(&roff_inline(description))));
+                }
+                None => page.push_str(&format!(".IP \\(bu 2\n{}", roff_line(&roff_inline(item)))),
+            }
+        } else if let Some((number, item)) = line.split_once(". ").filter(|(number, _)| number.parse::<u32>().is_ok()) {
+            page.push_str(&format!(".IP {}. 4\n{}", number, roff_line(&roff_inline(item))));
+        } else if line.starts_with("   ") && !trimmed.is_empty() {
+            // A continuation of the numbered item above.
+            page.push_str(&roff_line(&roff_inline(trimmed)));
+        } else if !trimmed.is_empty() && !line.starts_with("# ") {
+            page.push_str(&format!(".PP\n{}", roff_line(&roff_inline(line))));
+        }
+    }
+    page
+}
+
+/// The option reference as the OPTIONS section of the man page.
+fn options_roff() -> String {
+    let mut roff = String::new();
+    for (heading, options) in OPTION_SECTIONS {
+        roff.push_str(&format!(".SS {}\n", roff_escape(heading)));
+        for option in *options {
+            if option.is_note() {
+                roff.push_str(&format!(".IP \\(bu 2\n{}", roff_line(&roff_inline(option.help))));
+                continue;
+            }
+            roff.push_str(&format!(".TP\n{}\n{}", roff_flags(&option.flags()), roff_line(&roff_inline(option.help))));
+            for detail in option.details {
+                roff.push_str(&format!(".RS\n.IP \\(bu 2\n{}.RE\n", roff_line(&roff_inline(detail))));
+            }
+        }
+    }
+    roff
+}
+
+/// Escapes text for roff: backslashes and dashes (so options copy and paste correctly).
+fn roff_escape(text: &str) -> String {
+    text.replace('\\', "\\e").replace('-', "\\-")
+}
+
+/// Ends a line of roff text, guarding a leading `.` or `'` from being read as a request.
+fn roff_line(text: &str) -> String {
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}\n", text)
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+/// Converts Markdown text to roff, setting `code` spans in bold.
+fn roff_inline(text: &str) -> String {
+    let mut roff = String::new();
+    let mut last = 0;
+    for span in code_spans().captures_iter(text) {
+        let whole = span.get(0).unwrap();
+        let content = span.get(1).or_else(|| span.get(2)).map_or("", |content| content.as_str());
+        roff.push_str(&roff_escape(&text[last..whole.start()]));
+        roff.push_str(&format!("\\fB{}\\fR", roff_escape(content)));
+        last = whole.end();
+    }
+    roff.push_str(&roff_escape(&text[last..]));
+    roff
+}
+
+/// Sets an option's flags in bold and its value placeholders in italics, as in
+/// `-L, --level <N>`.
+fn roff_flags(flags: &str) -> String {
+    let words: Vec<String> = flags
+        .split(' ')
+        .map(|word| match word.strip_prefix('<') {
+            Some(value) => format!("\\fI{}\\fR", roff_escape(value.trim_end_matches('>'))),
+            None => {
+                let (name, comma) = word.strip_suffix(',').map_or((word, ""), |name| (name, ","));
+                format!("\\fB{}\\fR{}", roff_escape(name), comma)
+            }
+        })
+        .collect();
+    words.join(" ")
+}
+
+/// The script for `tree completions SHELL`, completing the command name it was run as.
+/// Anything else, including `completions` without a known shell after it, is a listing.
+fn completion_script(command_line: &[String]) -> Option<String> {
+    if command_line.get(1).is_none_or(|arg| arg != "completions") {
+        return None;
+    }
+    let command = Path::new(&command_line[0])
+        .file_stem()
+        .map_or_else(|| "tree".to_string(), |name| name.to_string_lossy().into_owned());
+    let options: Vec<&OptionSpec> = documented_options().collect();
+    match command_line.get(2)?.as_str() {
+        "bash" => Some(bash_completion(&command, &options)),
+        "zsh" => Some(zsh_completion(&command, &options)),
+        "fish" => Some(fish_completion(&command, &options)),
+        "powershell" => Some(powershell_completion(&command, &options)),
+        _ => None,
+    }
+}
+
+fn bash_completion(command: &str, options: &[&OptionSpec]) -> String {
+    let function = format!("_{}", command.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    let mut script = format!("{}() {{\n", function);
+    script.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    script.push_str("    case \"$prev\" in\n");
+    for option in options.iter().filter(|option| option.value.is_some()) {
+        let action = if !option.choices.is_empty() {
+            format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", option.choices.join(" "))
+        } else if option.takes_file() {
+            "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string()
+        } else {
+            "COMPREPLY=()".to_string()
+        };
+        script.push_str(&format!("        {}) {}; return ;;\n", option.spellings().collect::<Vec<_>>().join("|"), action));
+    }
+    script.push_str("    esac\n");
+    let names: Vec<&str> = options.iter().flat_map(|option| option.spellings()).collect();
+    script.push_str(&format!("    if [[ \"$cur\" == -* ]]; then\n        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n", names.join(" ")));
+    script.push_str("    else\n        COMPREPLY=($(compgen -f -- \"$cur\"))\n    fi\n}\n");
+    script.push_str(&format!("complete -o filenames -F {} {}\n", function, command));
+    script
+}
+
+fn zsh_completion(command: &str, options: &[&OptionSpec]) -> String {
+    let mut script = format!("#compdef {}\n\n_arguments -s \\\n", command);
+    for option in options {
+        // Brackets end the description and quotes end the spec.
+        let summary = option.summary().replace(['[', ']'], "").replace('\'', "'\\''");
+        let action = if !option.choices.is_empty() {
+            format!(":{}:({})", option.placeholder().unwrap_or_default(), option.choices.join(" "))
+        } else if option.takes_file() {
+            format!(":{}:_files", option.placeholder().unwrap_or_default())
+        } else if let Some(value) = option.placeholder() {
+            format!(":{}: ", value)
+        } else {
+            String::new()
+        };
+        for name in option.spellings() {
+            script.push_str(&format!("  '{}[{}]{}' \\\n", name, summary, action));
+        }
+    }
+    script.push_str("  '*:path:_files'\n");
+    script
+}
+
+fn fish_completion(command: &str, options: &[&OptionSpec]) -> String {
+    let mut script = String::new();
+    for option in options {
+        let mut line = format!("complete -c {}", command);
+        for name in option.spellings() {
+            match name.strip_prefix("--") {
+                Some(long) => line.push_str(&format!(" -l {}", long)),
+                // fish takes single-letter short options only; `-1` is one, bundles are not.
+                None => line.push_str(&format!(" -s {}", &name[1..])),
+            }
+        }
+        if !option.choices.is_empty() {
+            line.push_str(&format!(" -x -a '{}'", option.choices.join(" ")));
+        } else if option.takes_file() {
+            line.push_str(" -r -F");
+        } else if option.value.is_some() {
+            line.push_str(" -x");
+        }
+        line.push_str(&format!(" -d '{}'\n", option.summary().replace('\\', "\\\\").replace('\'', "\\'")));
+        script.push_str(&line);
+    }
+    script
+}
+
+fn powershell_completion(command: &str, options: &[&OptionSpec]) -> String {
+    let quote = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    let mut script = format!("Register-ArgumentCompleter -Native -CommandName {} -ScriptBlock {{\n", quote(command));
+    script.push_str("    param($wordToComplete, $commandAst, $cursorPosition)\n\n    $options = @(\n");
+    for option in options {
+        for name in option.spellings() {
+            let choices: Vec<String> = option.choices.iter().map(|choice| quote(choice)).collect();
+            script.push_str(&format!(
+                "        @{{ Name = {}; Description = {}; Choices = @({}) }}\n",
+                quote(name),
+                quote(&option.summary()),
+                choices.join(", ")
+            ));
+        }
+    }
+    script.push_str("    )\n\n");
+    script.push_str("    $before = @($commandAst.CommandElements | Where-Object { $_.Extent.EndOffset -lt $cursorPosition })\n");
+    script.push_str("    $previous = if ($before.Count -gt 1) { $before[-1].ToString() } else { '' }\n");
+    script.push_str("    $current = $options | Where-Object { $_.Name -ceq $previous } | Select-Object -First 1\n");
+    script.push_str("    if ($current -and $current.Choices.Count -gt 0) {\n");
+    script.push_str("        $current.Choices | Where-Object { $_ -like \"$wordToComplete*\" } |\n");
+    script.push_str("            ForEach-Object { [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_) }\n");
+    script.push_str("    } elseif ($wordToComplete -like '-*') {\n");
+    script.push_str("        $options | Where-Object { $_.Name -clike \"$wordToComplete*\" } |\n");
+    script.push_str("            ForEach-Object { [System.Management.Automation.CompletionResult]::new($_.Name, $_.Name, 'ParameterName', $_.Description) }\n");
+    script.push_str("    }\n}\n");
+    script
+}
+
+/// Compiles a GNU tree wildcard such as `*.rs|*.toml` into a glob set, one glob per
+/// `|`-separated alternative.
+fn build_wildcard(patterns: &[String], case_insensitive: bool) -> Result<GlobSet, Box<dyn Error>> {
+    let mut builder = GlobSetBuilder::new();
+    for alternative in patterns.iter().flat_map(|pattern| pattern.split('|')) {
+        builder.add(GlobBuilder::new(alternative).literal_separator(true).case_insensitive(case_insensitive).build()?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Parses `NAME=COMMAND`, e.g. `Kind=file --brief {}`.
+fn parse_command_column(spec: &str) -> Result<CommandColumn, Box<dyn Error>> {
+    match spec.split_once('=') {
+        Some((name, command)) if !name.is_empty() && !command.trim().is_empty() => Ok(CommandColumn {
+            name: name.to_string(),
+            command: command.to_string(),
+        }),
+        _ => Err(format!("--column expects NAME=COMMAND, got '{}'", spec).into()),
+    }
+}
+
+fn parse_positive(value: &str, flag: &str) -> Result<usize, Box<dyn Error>> {
+    match value.parse::<usize>() {
+        Ok(0) | Err(_) => Err(format!("{} requires a positive integer, got '{}'", flag, value).into()),
+        Ok(n) => Ok(n),
+    }
+}
+
+/// Number of worker threads for CPU-bound work, defaulting to the available parallelism.
+fn worker_threads(config: &Config) -> usize {
+    config.threads.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+}
+
+/// Maximum number of filesystem calls issued concurrently, defaulting to the worker thread count.
+fn io_concurrency(config: &Config) -> usize {
+    config.io_concurrency.unwrap_or_else(|| worker_threads(config))
+}
+
+// Below this many items the cost of spawning threads outweighs the parallel stat calls.
+const PARALLEL_THRESHOLD: usize = 64;
+
+/// Applies `f` to every item using up to `workers` scoped threads, preserving input order.
+fn parallel_map<T, R, F>(items: &[T], workers: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if workers <= 1 || items.len() < PARALLEL_THRESHOLD {
+        return items.iter().map(&f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(workers);
+    thread::scope(|scope| {
+        let handles: Vec<_> = items.chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(&f).collect::<Vec<_>>()))
+            .collect();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().expect("worker thread panicked"))
+            .collect()
+    })
+}
+
+/// Builds the filtered, sorted tree below `root`, whether `--timeout` cut the walk short
+/// and how many entries could not be read. Every display mode renders from this single
+/// traversal so depth, hidden and pattern filtering behave identically.
+///
+/// A symlinked root is only followed with `-H`, `--follow` or `--dereference`; otherwise
+/// the link itself is the root, like `ls` does for command-line arguments.
+fn build_tree(root: &Path, config: &Config) -> io::Result<(TreeNode, bool, usize)> {
+    let follow_root = config.dereference_roots || config.follow || config.dereference;
+    let metadata = if follow_root { fs::metadata(root)? } else { fs::symlink_metadata(root)? };
+
+    let mut state = WalkState::new(root, &metadata, config);
+    let children = if metadata.is_dir() {
+        match walk(root, 1, config, &mut state) {
+            Err(_) if state.timed_out => Vec::new(),
+            children => children?,
+        }
+    } else {
+        Vec::new()
+    };
+
+    let link_target = if !metadata.file_type().is_symlink() {
+        None
+    } else if config.resolve_links {
+        Some(symlink_chain(root))
+    } else {
+        fs::read_link(root).ok().map(|target| format!(" -> {}", target.display()))
+    };
+
+    let mut info = file_info(root.to_path_buf(), &metadata, 0)?;
+    info.link_target = link_target;
+
+    Ok((TreeNode { info, children }, state.timed_out, state.unreadable_entries))
+}
+
+/// Walks `root` for `--ndjson`, writing every entry as a line of JSON as soon as it is
+/// read, and a summary line at the end. Returns whether the walk ran out of time.
+fn stream_ndjson(root: &Path, config: &Config) -> io::Result<bool> {
+    let follow_root = config.dereference_roots || config.follow || config.dereference;
+    let metadata = if follow_root { fs::metadata(root)? } else { fs::symlink_metadata(root)? };
+    let mut state = WalkState::new(root, &metadata, config);
+    state.streamed = Some(TreeStats::default());
+    if metadata.is_dir() {
+        match walk(root, 1, config, &mut state) {
+            Err(_) if state.timed_out => {}
+            result => drop(result?),
+        }
+    }
+
+    let mut stats = state.streamed.unwrap_or_default();
+    tally_entry(&file_info(root.to_path_buf(), &metadata, 0)?, &mut stats);
+    writeln!(io::stdout().lock(), "{{\"summary\":{}}}", json_summary(&stats))?;
+    Ok(state.timed_out)
+}
+
+/// Counts an entry that `read_dir` returned but that could not be read, which is left out
+/// of the listing rather than failing its whole directory.
+fn skip_unreadable_entry(path: &Path, error: &io::Error, config: &Config, state: &mut WalkState) {
+    if config.trace {
+        eprintln!("trace entry={:?} error={:?}", path, error.to_string());
+    }
+    match &mut state.streamed {
+        Some(stats) => stats.errors += 1,
+        None => state.unreadable_entries += 1,
+    }
+}
+
+/// Reads the entries of `path`, which sit at `depth` below the root, and recurses into
+/// subdirectories while `should_descend` allows it.
+fn walk(path: &Path, depth: usize, config: &Config, state: &mut WalkState) -> io::Result<Vec<TreeNode>> {
+    if !within_max_depth(depth, config) {
+        return Ok(Vec::new());
+    }
+    if state.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        state.timed_out = true;
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+    }
+
+    let started = Instant::now();
+    let treeignore_depth = state.treeignores.len();
+    if config.treeignore {
+        // `.rtreeignore` comes last so its rules win when a directory has both.
+        state.treeignores.extend(TREEIGNORE_FILES.iter().filter_map(|name| load_ignore_file(path, name)));
+    }
+    let (gitignore_depth, was_in_git_repo) = (state.gitignores.len(), state.in_git_repo);
+    let absolute_dir = if config.gitignore { std::path::absolute(path)? } else { PathBuf::new() };
+    if config.gitignore {
+        if path.join(".git").exists() {
+            state.in_git_repo = true;
+            state.gitignores.extend(load_ignore_file(&absolute_dir, ".git/info/exclude"));
+        }
+        if state.in_git_repo {
+            state.gitignores.extend(load_ignore_file(&absolute_dir, ".gitignore"));
+        }
+    }
+
+    // How many entries each filter dropped, for --trace.
+    let (mut found, mut hidden, mut ignored, mut treeignored, mut gitignored, mut unmatched) = (0, 0, 0, 0, 0, 0);
+    let mut dir_entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                skip_unreadable_entry(path, &error, config, state);
+                continue;
+            },
+        };
+        found += 1;
+        if !config.show_hidden && !config.hidden_only && is_hidden(&entry.path()) {
+            hidden += 1;
+            continue;
+        }
+        if config.ignore.as_ref().is_some_and(|ignore| ignore.is_match(entry.file_name())) {
+            ignored += 1;
+            continue;
+        }
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if is_dir && config.ignore_dirs.iter().any(|name| entry.file_name() == name.as_str()) {
+            ignored += 1;
+            continue;
+        }
+        if is_ignored_by(&entry.path(), is_dir, &state.treeignores) {
+            treeignored += 1;
+            continue;
+        }
+        if !state.gitignores.is_empty() && is_ignored_by(&absolute_dir.join(entry.file_name()), is_dir, &state.gitignores) {
+            gitignored += 1;
+            continue;
+        }
+        dir_entries.push(entry);
+    }
+
+    let parent_device = if config.show_mounts {
+        fs::metadata(path).ok().and_then(|metadata| device_id(&metadata))
+    } else {
+        None
+    };
+
+    // Entries paired with the id of the directory to descend into, if any. Children are only
+    // walked after sorting so that the first occurrence of a directory is also printed first.
+    let mut pending = Vec::new();
+    let names_only = config.virtual_fs == VirtualFs::NamesOnly && is_virtual_fs(path);
+    if names_only {
+        for entry in dir_entries.drain(..) {
+            let kind = match entry.file_type() {
+                Ok(file_type) => FileKind::of(file_type),
+                Err(error) => {
+                    skip_unreadable_entry(&entry.path(), &error, config, state);
+                    continue;
+                },
+            };
+            if !kind.is_dir() && !state.in_matched_dir && !matches_pattern(&entry.path(), &state.root, config) {
+                unmatched += 1;
+                continue;
+            }
+            let mut info = stored_file_info(entry.path(), kind, 0, SystemTime::UNIX_EPOCH, 0);
+            info.depth = depth;
+            info.virtual_fs = true;
+            pending.push((info, (kind.is_dir() && should_descend(depth, config)).then_some(None)));
+        }
+    }
+
+    let all_metadata = parallel_map(&dir_entries, io_concurrency(config), |entry| {
+        if config.dereference {
+            fs::metadata(entry.path())
+        } else {
+            entry.metadata()
+        }
+    });
+
+    for (entry, metadata) in dir_entries.iter().zip(all_metadata) {
+        let path = entry.path();
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                skip_unreadable_entry(&path, &error, config, state);
+                continue;
+            },
+        };
+
+        // With --follow, a symlink to a directory is walked like the directory itself.
+        let target = if config.follow && metadata.file_type().is_symlink() {
+            fs::metadata(&path).ok().filter(|target| target.is_dir())
+        } else {
+            None
+        };
+        let dir_metadata = target.as_ref().unwrap_or(&metadata);
+        let is_dir = dir_metadata.is_dir();
+
+        if !is_dir && !state.in_matched_dir && !matches_pattern(&path, &state.root, config) {
+            unmatched += 1;
+            continue;
+        }
+
+        let mut notes = Vec::new();
+        let subvolume = if config.show_subvolumes && is_dir { btrfs_subvolume(&path, dir_metadata) } else { None };
+        if is_dir && parent_device.is_some() && device_id(dir_metadata) != parent_device {
+            // Every btrfs subvolume has its own device number, mounted or not.
+            if subvolume.is_none() || path.canonicalize().is_ok_and(|path| state.mounts.contains_key(&path)) {
+                notes.push(mount_label(&path, state));
+            }
+        }
+        if let Some(subvolume) = subvolume {
+            notes.push(subvolume.to_string());
+        }
+        if config.show_compression && metadata.is_file() && is_compressed(&path, &metadata) {
+            let on_disk = disk_usage(&metadata);
+            notes.push(if on_disk < metadata.len() {
+                format!("compressed, {} on disk", format_entry_size(on_disk, config))
+            } else {
+                "compressed".to_string()
+            });
+        }
+
+        // Entries on another filesystem are still listed, just never descended into.
+        let same_device = state.root_device.is_none() || device_id(dir_metadata) == state.root_device;
+        let dir_id = file_id(dir_metadata);
+        let is_cycle = dir_id.is_some_and(|id| state.ancestors.contains(&id));
+        // Without inode numbers there is no way to detect loops, so links are not followed.
+        let followable = target.is_none() || dir_id.is_some();
+
+        let link_target = if config.resolve_links && metadata.file_type().is_symlink() {
+            Some(symlink_chain(&path))
+        } else {
+            None
+        };
+
+        let capabilities = if config.show_capabilities && metadata.is_file() { file_capabilities(&path) } else { None };
+        let has_capabilities = capabilities.is_some();
+        notes.extend(capabilities);
+        let acl = if config.show_acl { extended_acl(&path) } else { None };
+        if let Some(acl) = &acl {
+            notes.push(if config.acl_summary && !acl.is_empty() { format!("+ {}", acl.join(", ")) } else { "+".to_string() });
+        }
+
+        let broken_link = metadata.file_type().is_symlink() && fs::metadata(&path).is_err();
+
+        let mut info = match file_info(path.clone(), &metadata, depth) {
+            Ok(info) => info,
+            Err(error) => {
+                skip_unreadable_entry(&path, &error, config, state);
+                continue;
+            },
+        };
+        info.is_dir = is_dir;
+        info.broken_link = broken_link;
+        info.has_acl = acl.is_some();
+        info.has_capabilities = has_capabilities;
+        info.link_target = link_target;
+        info.notes = notes;
+
+        let descend = if is_dir {
+            if is_cycle {
+                info.notes.push("recursive, not followed".to_string());
+                None
+            } else if config.virtual_fs == VirtualFs::Skip && is_virtual_fs(&info.path) {
+                info.notes.push("virtual filesystem, not read".to_string());
+                None
+            } else if same_device && followable && should_descend(depth, config) {
+                match config.file_limit.and_then(|limit| count_dir_entries(&info.path, config).filter(|count| *count > limit)) {
+                    Some(count) => {
+                        info.notes.push(format!("{} entries exceeds filelimit", count));
+                        None
+                    },
+                    None => Some(dir_id),
+                }
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        pending.push((info, descend));
+    }
+
+    if !is_unsorted(config) {
+        pending.sort_by(|(a, _), (b, _)| compare_entries(a, b, config));
+    }
+    if config.trace {
+        // Time spent on this directory alone; its subdirectories are logged on their own.
+        eprintln!("trace dir={:?} depth={} found={} listed={} hidden={} ignored={} treeignored={} gitignored={} unmatched={} elapsed_ms={:.3}",
+            path, depth, found, pending.len(), hidden, ignored, treeignored, gitignored, unmatched,
+            started.elapsed().as_secs_f64() * 1000.0);
+    }
+
+    let mut nodes = Vec::with_capacity(pending.len());
+    for (mut info, descend) in pending {
+        if let Some(stats) = &mut state.streamed {
+            // Written before the walk moves on to its contents, so readers see parents first.
+            if matches_filters(&info, &state.root, config) && (info.is_dir || !config.dirs_only) {
+                let line = format!("{{{},\"depth\":{}}}\n", json_entry_fields(&info, &file_name_lossy(&info)), info.depth);
+                io::stdout().lock().write_all(line.as_bytes())?;
+                tally_entry(&info, stats);
+            }
+        }
+        let children = match descend {
+            Some(dir_id) => {
+                // When links are followed the same directory can be reached through several
+                // paths; only the first is walked so its subtree isn't duplicated in the output.
+                let first_seen = dir_id.and_then(|id| state.visited.get(&id));
+                if let Some(first_seen) = first_seen {
+                    info.notes.push(format!("see {}", first_seen.display()));
+                    Vec::new()
+                } else {
+                    if let Some(id) = dir_id.filter(|_| config.follow || config.dereference) {
+                        state.visited.insert(id, info.path.clone());
+                    }
+                    state.ancestors.extend(dir_id);
+                    let treeignore_depth = state.treeignores.len();
+                    let (gitignore_depth, in_git_repo) = (state.gitignores.len(), state.in_git_repo);
+                    let in_matched_dir = state.in_matched_dir;
+                    let name_filtered = config.pattern.is_some() || config.glob.is_some();
+                    if config.match_dirs && name_filtered && matches_pattern(&info.path, &state.root, config) {
+                        state.in_matched_dir = true;
+                    }
+                    let children = match walk(&info.path, depth + 1, config, state) {
+                        Ok(children) => children,
+                        Err(error) => {
+                            if config.trace {
+                                eprintln!("trace dir={:?} depth={} error={:?}", info.path, depth + 1, error.to_string());
+                            }
+                            state.treeignores.truncate(treeignore_depth);
+                            state.gitignores.truncate(gitignore_depth);
+                            state.in_git_repo = in_git_repo;
+                            if state.timed_out {
+                                info.notes.push("not read: timed out".to_string());
+                            } else {
+                                // Listed anyway, marked the way GNU tree does.
+                                info.notes.push("error opening dir".to_string());
+                                info.unreadable = true;
+                                if let Some(stats) = &mut state.streamed {
+                                    stats.errors += 1;
+                                }
+                            }
+                            Vec::new()
+                        },
+                    };
+                    state.in_matched_dir = in_matched_dir;
+                    if dir_id.is_some() {
+                        state.ancestors.pop();
+                    }
+                    children
+                }
+            },
+            None => Vec::new(),
+        };
+
+        if state.streamed.is_none() {
+            nodes.push(TreeNode { info, children });
+        }
+    }
+
+    state.treeignores.truncate(treeignore_depth);
+    state.gitignores.truncate(gitignore_depth);
+    state.in_git_repo = was_in_git_repo;
+    Ok(nodes)
+}
+
+/// The ignore files read in every directory unless `--no-treeignore` is given.
+const TREEIGNORE_FILES: [&str; 2] = [".treeignore", ".rtreeignore"];
+
+/// Reads the ignore file `name` below `dir` (`.treeignore`, `.gitignore`), if there is
+/// one. It uses gitignore syntax, with patterns relative to `dir`; lines that fail to parse
+/// are reported and skipped.
+fn load_ignore_file(dir: &Path, name: &str) -> Option<Gitignore> {
+    let file = dir.join(name);
+    if !file.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(error) = builder.add(&file) {
+        eprintln!("{}: {}", file.display(), error);
+    }
+    builder.build().map_err(|error| eprintln!("{}: {}", file.display(), error)).ok()
+}
+
+/// Git's rules that already apply at `root` when it lies inside a repository: the
+/// repository's `.git/info/exclude` and the `.gitignore` files from its top down to the
+/// parent of `root`. None outside a repository.
+fn enclosing_gitignores(root: &Path) -> Option<Vec<Gitignore>> {
+    let root = std::path::absolute(root).ok()?;
+    let ancestors: Vec<&Path> = root.ancestors().skip(1).collect();
+    let top = ancestors.iter().position(|dir| dir.join(".git").exists())?;
+    let mut gitignores: Vec<Gitignore> = load_ignore_file(ancestors[top], ".git/info/exclude").into_iter().collect();
+    for dir in ancestors[..=top].iter().rev() {
+        gitignores.extend(load_ignore_file(dir, ".gitignore"));
+    }
+    Some(gitignores)
+}
+
+/// Whether the innermost ignore file with a pattern matching `path` ignores it, so a
+/// deeper `!pattern` can bring back what an outer file hides.
+fn is_ignored_by(path: &Path, is_dir: bool, ignores: &[Gitignore]) -> bool {
+    ignores.iter().rev()
+        .map(|ignore| ignore.matched(path, is_dir))
+        .find(|matched| !matched.is_none())
+        .is_some_and(|matched| matched.is_ignore())
+}
+
+/// How many entries `dir` holds, not counting hidden ones unless they are shown, for
+/// `--filelimit`.
+fn count_dir_entries(dir: &Path, config: &Config) -> Option<usize> {
+    let entries = fs::read_dir(dir).ok()?.flatten();
+    Some(entries.filter(|entry| config.show_hidden || config.hidden_only || !is_hidden(&entry.path())).count())
+}
+
+fn within_max_depth(depth: usize, config: &Config) -> bool {
+    sizes_whole_tree(config) || config.max_depth.is_none_or(|max_depth| depth <= max_depth)
+}
+
+/// `--du`, `--du-format` and `--biggest` size directories by their whole contents, so the
+/// walk ignores `max_depth` and the tree is cut down to it afterwards.
+fn sizes_whole_tree(config: &Config) -> bool {
+    config.disk_usage || config.du_format || config.biggest.is_some()
+}
+
+/// Flat modes only list the root's direct entries unless `--recurse` is given.
+fn should_descend(depth: usize, config: &Config) -> bool {
+    let recursive = matches!(config.display_mode, DisplayMode::Tree | DisplayMode::Flat)
+        || config.recurse
+        || config.recent.is_some();
+    recursive && within_max_depth(depth + 1, config)
+}
+
+/// Whether a file passes both `--pattern` and `-P`. Wildcards are tried on the file name
+/// and on the path below `root`, so the ones containing `/` (and `**`) match whole paths;
+/// the regex only sees the path with `--full-path`.
+fn matches_pattern(path: &Path, root: &Path, config: &Config) -> bool {
+    let file_name = path.file_name().unwrap_or_default();
+    let relative_path = path.strip_prefix(root).unwrap_or(path);
+    let regex_match = config.pattern.as_ref().is_none_or(|pattern| {
+        let subject = if config.full_path { relative_path.as_os_str() } else { file_name };
+        pattern.is_match(&subject.to_string_lossy())
+    });
+    let glob_match = config.glob.as_ref().is_none_or(|glob| glob.is_match(file_name) || glob.is_match(relative_path));
+    regex_match && glob_match
+}
+
+/// Flat modes list a directory root's contents, or the root itself when it is not a directory.
+fn flatten_root(root: TreeNode, config: &Config) -> Vec<FileInfo> {
+    if root.info.is_dir {
+        let mut entries = if config.show_dot_entries { dot_entries(&root.info.path) } else { Vec::new() };
+        entries.extend(flatten_tree(root.children));
+        entries.retain(|entry| is_listed_flat(entry, &root.info.path, config));
+        entries
+    } else {
+        vec![root.info]
+    }
+}
+
+/// Whether the flat modes list an entry below `root`. The directories that were only
+/// kept as structure for tree mode are dropped.
+fn is_listed_flat(entry: &FileInfo, root: &Path, config: &Config) -> bool {
+    !(config.files_only && entry.is_dir) && (!has_entry_filters(config) || matches_filters(entry, root, config))
+}
+
+/// Whether the listing is printed flat, so its summary counts the flattened entries.
+fn lists_flat(config: &Config) -> bool {
+    !matches!(config.display_mode, DisplayMode::Tree) || config.recent.is_some() || config.group_by.is_some() || config.template.is_some()
+}
+
+/// The `.` and `..` entries `ls -a` lists for a directory; they are never descended into.
+fn dot_entries(dir: &Path) -> Vec<FileInfo> {
+    [(".", dir.to_path_buf()), ("..", dir.join(".."))].into_iter()
+        .filter_map(|(label, path)| {
+            let metadata = fs::metadata(&path).ok()?;
+            let mut info = file_info(path, &metadata, 1).ok()?;
+            info.label = Some(label);
+            Some(info)
+        })
+        .collect()
+}
+
+/// Removes directories whose subtrees contain no files, keeping the ones that give the
+/// remaining files their place in the tree.
+fn prune_to_files(nodes: &mut Vec<TreeNode>) {
+    for node in nodes.iter_mut() {
+        prune_to_files(&mut node.children);
+    }
+    nodes.retain(|node| !node.info.is_dir || !node.children.is_empty());
+}
+
+/// Drops everything but directories, for `--dirs-only`.
+fn keep_dirs(nodes: &mut Vec<TreeNode>) {
+    nodes.retain(|node| node.info.is_dir);
+    for node in nodes.iter_mut() {
+        keep_dirs(&mut node.children);
+    }
+}
+
+/// Adds the `--show-empty` badge to zero-byte files and directories with nothing in them.
+fn mark_empty(nodes: &mut [TreeNode], badge: &str) {
+    for node in nodes {
+        if TypeFilter::Empty.matches(&node.info) {
+            node.info.notes.push(badge.to_string());
+        }
+        mark_empty(&mut node.children, badge);
+    }
+}
+
+/// Marks every directory below `node` with how many entries are listed directly in it,
+/// e.g. `[12 entries]`, before `--dirs-only` takes the files away.
+fn count_entries(node: &mut TreeNode) {
+    for child in node.children.iter_mut().filter(|child| child.info.is_dir) {
+        let count = child.children.len();
+        child.info.notes.push(format!("{} {}", count, if count == 1 { "entry" } else { "entries" }));
+        count_entries(child);
+    }
+}
+
+/// Filters evaluated on the finished tree, since keeping a directory depends on its contents.
+fn has_entry_filters(config: &Config) -> bool {
+    config.hidden_only || config.where_expr.is_some() || !config.types.is_empty() || !config.extensions.is_empty()
+        || config.user.is_some() || config.group.is_some()
+}
+
+fn matches_filters(entry: &FileInfo, root: &Path, config: &Config) -> bool {
+    (!config.hidden_only || is_hidden_below(&entry.path, root))
+        && config.where_expr.as_ref().is_none_or(|expr| eval_query(expr, entry))
+        && (config.types.is_empty() || config.types.iter().any(|kind| kind.matches(entry)))
+        && (config.extensions.is_empty() || has_extension(entry, &config.extensions))
+        && config.user.is_none_or(|uid| entry.owner.is_some_and(|(owner, _)| owner == uid))
+        && config.group.is_none_or(|gid| entry.owner.is_some_and(|(_, group)| group == gid))
+}
+
+fn has_extension(entry: &FileInfo, extensions: &[String]) -> bool {
+    let extension = entry.path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+    !entry.is_dir && extension.is_some_and(|extension| extensions.contains(&extension))
+}
+
+/// Keeps matching entries plus the directories leading to them, so tree mode can still
+/// show where each match lives.
+fn prune_unmatched(nodes: &mut Vec<TreeNode>, root: &Path, config: &Config) {
+    nodes.retain_mut(|node| {
+        prune_unmatched(&mut node.children, root, config);
+        matches_filters(&node.info, root, config) || (node.info.is_dir && !node.children.is_empty())
+    });
+}
+
+/// With --du, gives each directory the size of everything shown below it plus its own,
+/// then re-sorts so size ordering uses the totals.
+fn aggregate_sizes(node: &mut TreeNode, config: &Config) -> u64 {
+    if node.info.is_dir {
+        for child in node.children.iter_mut() {
+            node.info.size += aggregate_sizes(child, config);
+        }
+        sort_nodes(&mut node.children, config);
+    }
+    node.info.size
+}
+
+/// Drops everything below `max_depth`, for a tree walked in full by `sizes_whole_tree`.
+fn cut_to_depth(nodes: &mut [TreeNode], max_depth: usize) {
+    for node in nodes {
+        if node.info.depth >= max_depth {
+            node.children.clear();
+        } else {
+            cut_to_depth(&mut node.children, max_depth);
+        }
+    }
+}
+
+/// Keeps the `count` largest entries of every directory, largest first, down to `max_depth`.
+fn keep_biggest(nodes: &mut Vec<TreeNode>, count: usize, max_depth: Option<usize>) {
+    nodes.sort_by_key(|node| cmp::Reverse(node.info.size));
+    nodes.truncate(count);
+    for node in nodes.iter_mut() {
+        if max_depth.is_some_and(|max_depth| node.info.depth >= max_depth) {
+            node.children.clear();
+        } else {
+            keep_biggest(&mut node.children, count, max_depth);
+        }
+    }
+}
+
+/// Records each entry's share of its parent for --percent and the fill of its --bars graph.
+fn annotate_shares(nodes: &mut [TreeNode], parent_size: u64, root_size: u64, config: &Config) {
+    let fraction = |size: u64, total: u64| if total == 0 { 0.0 } else { size as f64 / total as f64 };
+    for node in nodes {
+        let share = fraction(node.info.size, parent_size);
+        if config.percent {
+            node.info.share = Some(share);
+        }
+        node.info.bar = match config.bars {
+            Some(BarScale::Directory) => Some(share),
+            Some(BarScale::Global) => Some(fraction(node.info.size, root_size)),
+            None => None,
+        };
+        annotate_shares(&mut node.children, node.info.size, root_size, config);
+    }
+}
+
+/// Lists the special kinds among the counted entries, e.g. `Including 3 symlinks (1 broken),
+/// 1 socket`, or `None` for a tree of only regular files and directories.
+fn kind_breakdown(stats: &TreeStats) -> Option<String> {
+    let count = |n: usize, singular: &str, plural: &str| {
+        (n > 0).then(|| format!("{} {}", n, if n == 1 { singular } else { plural }))
+    };
+    let symlinks = count(stats.symlinks, "symlink", "symlinks").map(|symlinks| match stats.broken_links {
+        0 => symlinks,
+        broken => format!("{} ({} broken)", symlinks, broken),
+    });
+    let kinds: Vec<String> = [
+        symlinks,
+        count(stats.sockets, "socket", "sockets"),
+        count(stats.fifos, "fifo", "fifos"),
+        count(stats.devices, "device", "devices"),
+    ].into_iter().flatten().collect();
+    let errors = count(stats.errors, "unreadable entry", "unreadable entries");
+
+    match (kinds.is_empty(), errors) {
+        (true, None) => None,
+        (true, Some(errors)) => Some(errors),
+        (false, None) => Some(format!("Including {}", kinds.join(", "))),
+        (false, Some(errors)) => Some(format!("Including {}; {}", kinds.join(", "), errors)),
+    }
+}
+
+/// The counts of the summary: the whole filtered tree, or in the flat modes the root and
+/// the entries they list.
+fn listing_stats(root: &TreeNode, config: &Config) -> TreeStats {
+    let mut stats = TreeStats::default();
+    if lists_flat(config) && root.info.is_dir {
+        tally_entry(&root.info, &mut stats);
+        for entry in tree_entries(&root.children) {
+            if is_listed_flat(entry, &root.info.path, config) {
+                tally_entry(entry, &mut stats);
+            }
+        }
+    } else {
+        tally_tree(root, &mut stats);
+    }
+    stats
+}
+
+/// Counts what is left in the tree after filtering, including the root itself.
+fn tally_tree(node: &TreeNode, stats: &mut TreeStats) {
+    tally_entry(&node.info, stats);
+    for child in &node.children {
+        tally_tree(child, stats);
+    }
+}
+
+fn tally_entry(info: &FileInfo, stats: &mut TreeStats) {
+    if info.is_dir {
+        stats.directories += 1;
+    } else {
+        stats.files += 1;
+        stats.total_size += info.size;
+    }
+    match info.file_type {
+        FileKind::Symlink => stats.symlinks += 1,
+        FileKind::Socket => stats.sockets += 1,
+        FileKind::Fifo => stats.fifos += 1,
+        FileKind::CharDevice | FileKind::BlockDevice => stats.devices += 1,
+        _ => {},
+    }
+    stats.broken_links += info.broken_link as usize;
+    stats.errors += info.unreadable as usize;
+}
+
+/// Flattens the tree in pre-order, so each directory is immediately followed by its contents.
+fn flatten_tree(nodes: Vec<TreeNode>) -> Vec<FileInfo> {
+    let mut entries = Vec::new();
+    for node in nodes {
+        entries.push(node.info);
+        entries.extend(flatten_tree(node.children));
+    }
+    entries
+}
+
+/// Lists the entries of a borrowed tree in the same pre-order as `flatten_tree`.
+fn tree_entries(nodes: &[TreeNode]) -> Vec<&FileInfo> {
+    let mut entries = Vec::new();
+    for node in nodes {
+        entries.push(&node.info);
+        entries.extend(tree_entries(&node.children));
+    }
+    entries
+}
+
+/// With `--format`, every flat mode prints one formatted line per entry.
+fn print_entries_formatted(entries: &[FileInfo], config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    if let Some(format) = &config.format {
+        for entry in entries {
+            writeln!(stdout, "{}", render_format(format, entry, config))?;
+        }
+    }
+    Ok(())
+}
+
+fn print_entries_oneline(entries: &[FileInfo], config: &Config) -> io::Result<()> {
+    for entry in entries {
+        print_entry_oneline(entry, config)?;
+    }
+    Ok(())
+}
+
+fn print_entry_oneline(entry: &FileInfo, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let formatted_name = format_file_name(&display_name(entry, config), config);
+    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
+    let icon = get_icon(&entry.path, config);
+    let color = get_color_for_scale(entry, config);
+    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
+    let annotations = entry_annotations(entry);
+    
+    write!(stdout, "{}{}{}{}{}", color, icon, hyperlinked_name, type_indicator, annotations)?;
+    
+    write!(stdout, "{}", size_suffix(entry, config))?;
+    
+    writeln!(stdout, "\x1B[0m")
+}
+
+fn print_entries_long(entries: &[FileInfo], config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    
+    // Calculate column widths
+    let max_size_width = entries.iter().map(|e| format_entry_size(e.size, config).len()).max().unwrap_or(0);
+    let max_name_width = entries.iter().map(|e| display_name(e, config).len()).max().unwrap_or(0);
+
+    let extra_columns = long_extra_columns(entries, config);
+    let extra_width: usize = extra_columns.iter().map(|column| column.width + 1).sum();
+
+    // Print header
+    write!(stdout, "{:<10} {:>width$} {:<20} ",
+        "Type",
+        "Size",
+        "Modified",
+        width = max_size_width
+    )?;
+    for column in &extra_columns {
+        write!(stdout, "{:<width$} ", column.header, width = column.width)?;
+    }
+    writeln!(stdout, "Name")?;
+    writeln!(stdout, "{}", "-".repeat(10 + 1 + max_size_width + 1 + 20 + 1 + extra_width + max_name_width))?;
+
+    for (index, entry) in entries.iter().enumerate() {
+        let extra: String = extra_columns.iter()
+            .map(|column| format!("{:<width$} ", column.values[index], width = column.width))
+            .collect();
+        print_entry_long(entry, config, max_size_width, &extra)?;
+    }
+
+    Ok(())
+}
+
+/// Runs a `--column` command for every entry, with `{}` replaced by the entry's path, and
+/// returns the first line each one prints. Commands run on the worker threads; an entry
+/// reachable through several paths is only run once.
+fn run_column_command(command: &str, entries: &[FileInfo], config: &Config) -> Vec<String> {
+    let keys: Vec<PathBuf> = entries.iter()
+        .map(|entry| fs::canonicalize(&entry.path).unwrap_or_else(|_| entry.path.clone()))
+        .collect();
+
+    let mut unique: Vec<&PathBuf> = keys.iter().collect::<HashSet<_>>().into_iter().collect();
+    unique.sort();
+    let outputs = parallel_map(&unique, worker_threads(config), |path| column_command_output(command, path));
+    let cache: HashMap<&PathBuf, String> = unique.iter().copied().zip(outputs).collect();
+
+    keys.iter().map(|key| cache[key].clone()).collect()
+}
+
+fn column_command_output(command: &str, path: &Path) -> String {
+    let output = shell_command(command, path).stdin(process::Stdio::null()).stderr(process::Stdio::null()).output();
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or("").trim().to_string()
+        },
+        _ => "?".to_string(),
+    }
+}
+
+/// Builds a shell invocation of `command` with `{}` standing for `path`. On Unix the path is
+/// passed as a positional argument so it never needs quoting.
+#[cfg(unix)]
+fn shell_command(command: &str, path: &Path) -> process::Command {
+    let mut shell = process::Command::new("sh");
+    shell.arg("-c").arg(command.replace("{}", "\"$1\"")).arg("sh").arg(path);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str, path: &Path) -> process::Command {
+    let mut shell = process::Command::new("cmd");
+    shell.arg("/C").arg(command.replace("{}", &format!("\"{}\"", path.display())));
+    shell
+}
+
+/// Runs the `--exec` command for the given entries and returns how many invocations failed.
+/// With `--dry-run` the commands are only printed.
+fn run_exec(exec: &ExecAction, entries: &[FileInfo], config: &Config) -> io::Result<usize> {
+    let paths: Vec<String> = entries.iter().map(|entry| entry.path.to_string_lossy().into_owned()).collect();
+
+    let invocations: Vec<Vec<String>> = if exec.batch {
+        if paths.is_empty() {
+            return Ok(0);
+        }
+        let mut argv = Vec::new();
+        for arg in &exec.command {
+            if arg == "{}" {
+                argv.extend(paths.iter().cloned());
+            } else {
+                argv.push(arg.clone());
+            }
+        }
+        if !exec.command.iter().any(|arg| arg == "{}") {
+            argv.extend(paths.iter().cloned());
+        }
+        vec![argv]
+    } else {
+        paths.iter()
+            .map(|path| exec.command.iter().map(|arg| arg.replace("{}", path)).collect())
+            .collect()
+    };
+
+    let mut failures = 0;
+    for argv in invocations {
+        if config.dry_run {
+            println!("{}", argv.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" "));
+            continue;
+        }
+
+        match process::Command::new(&argv[0]).args(&argv[1..]).status() {
+            Ok(status) if status.success() => {},
+            Ok(_) => failures += 1,
+            Err(e) => {
+                eprintln!("{}: {}", argv[0], e);
+                failures += 1;
+            },
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Quotes `arg` for a POSIX shell when it contains anything beyond plain word characters.
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// An optional long-view column, inserted between the modification time and the name.
+struct LongColumn {
+    header: String,
+    values: Vec<String>,
+    width: usize,
+}
+
+fn long_column(header: &str, values: Vec<String>) -> LongColumn {
+    let width = values.iter().map(|value| value.chars().count()).chain([header.len()]).max().unwrap_or(0);
+    LongColumn { header: header.to_string(), values, width }
+}
+
+fn long_extra_columns(entries: &[FileInfo], config: &Config) -> Vec<LongColumn> {
+    let mut columns = Vec::new();
+    if config.show_owner {
+        // Windows reads each owner from the file's security descriptor, so look them up in parallel.
+        columns.push(long_column("Owner", parallel_map(entries, io_concurrency(config), owner_label)));
+    }
+    if config.show_filesystem {
+        columns.push(long_column("Filesystem", filesystem_column(entries)));
+    }
+    if config.show_flags {
+        columns.push(long_column("Flags", entries.iter().map(|entry| format_file_flags(entry.flags)).collect()));
+    }
+    columns.extend(config.columns.iter()
+        .map(|column| long_column(&column.name, run_column_command(&column.command, entries, config))));
+    columns
+}
+
+// chflags(1) names, in the order `ls -lo` prints them.
+const FILE_FLAG_NAMES: [(u32, &str); 13] = [
+    (0x0001_0000, "arch"),
+    (0x0000_8000, "hidden"),
+    (0x0000_0001, "nodump"),
+    (0x0000_0008, "opaque"),
+    (0x0000_0020, "compressed"),
+    (0x0004_0000, "sappnd"),
+    (0x0002_0000, "schg"),
+    (0x0010_0000, "sunlnk"),
+    (0x0008_0000, "restricted"),
+    (0x0000_0004, "uappnd"),
+    (0x0000_0002, "uchg"),
+    (0x0000_0010, "uunlnk"),
+    (0x0000_0040, "tracked"),
+];
+
+/// Renders file flags as a comma-separated list like `uchg,nodump`, or `-` when none are set.
+fn format_file_flags(flags: u32) -> String {
+    let names: Vec<&str> = FILE_FLAG_NAMES.iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() { "-".to_string() } else { names.join(",") }
+}
+
+fn print_entry_long(entry: &FileInfo, config: &Config, size_width: usize, extra: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let formatted_name = format_file_name(&display_name(entry, config), config);
+    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
+    let icon = get_icon(&entry.path, config);
+    let color = get_color_for_scale(entry, config);
+    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
+    let annotations = entry_annotations(entry);
+    let size = format_entry_size(entry.size, config);
+    let mod_time: DateTime<Local> = entry.mod_time.into();
+
+    writeln!(stdout, "{}{:<10} {:>width$} {:<20} {}{}{}{}{}{}\x1B[0m",
+        color,
+        get_file_type_str(&entry.file_type),
+        size,
+        mod_time.format("%Y-%m-%d %H:%M:%S"),
+        extra,
+        icon,
+        hyperlinked_name,
+        type_indicator,
+        annotations,
+        size_suffix(entry, config),
+        width = size_width
+    )
+}
+
+fn print_entries_grid(entries: &[FileInfo], config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let term_width = config.screen_width.unwrap_or_else(|| term_size::dimensions().map(|(w, _)| w).unwrap_or(80));
+    
+    let max_entry_width = entries.iter()
+        .map(|e| {
+            let formatted_name = format_file_name(&display_name(e, config), config);
+            let icon = get_icon(&e.path, config);
+            let type_indicator = get_type_indicator(&e.file_type, config.classify);
+            let annotations = entry_annotations(e);
+            let size_str = size_suffix(e, config);
+            icon.len() + formatted_name.len() + type_indicator.len() + annotations.len() + size_str.len()
+        })
+        .max()
+        .unwrap_or(0) + 2;  // +2 for spacing between entries
+
+    let columns = cmp::max(term_width / max_entry_width, 1);
+    let rows = entries.len().div_ceil(columns);
+
+    for row in 0..rows {
+        for col in 0..columns {
+            let index = if config.sort_across {
+                row * columns + col
+            } else {
+                col * rows + row
+            };
+
+            if index < entries.len() {
+                let entry = &entries[index];
+                print_entry_grid(entry, config, max_entry_width)?;
+            }
+        }
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
+
+fn print_entry_grid(entry: &FileInfo, config: &Config, width: usize) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let formatted_name = format_file_name(&display_name(entry, config), config);
+    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
+    let icon = get_icon(&entry.path, config);
+    let color = get_color_for_scale(entry, config);
+    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
+    let annotations = entry_annotations(entry);
+    
+    let size_str = size_suffix(entry, config);
+    
+    let entry_str = format!("{}{}{}{}{}\x1B[0m", icon, hyperlinked_name, type_indicator, annotations, size_str);
+    
+    write!(stdout, "{}{:<width$}", color, entry_str, width = width)
+}
+
+fn print_tree_root(root: &FileInfo, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "{}", get_display_path(&root.path, config).display())?;
+    writeln!(stdout, "{}", entry_annotations(root))
+}
+
+fn print_tree(nodes: &[TreeNode], prefix: &str, config: &Config) -> io::Result<()> {
+    for (index, node) in nodes.iter().enumerate() {
+        let is_last = index == nodes.len() - 1;
+        let connector = config.charset.connector(is_last);
+        print_tree_entry(&node.info, &format!("{}{}", prefix, connector), config)?;
+
+        if !node.children.is_empty() {
+            let child_prefix = format!("{}{}", prefix, config.charset.indent(is_last));
+            print_tree(&node.children, &child_prefix, config)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_tree_entry(entry: &FileInfo, prefix: &str, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    if let Some(format) = &config.format {
+        return writeln!(stdout, "{}{}", prefix, render_format(format, entry, config));
+    }
+
+    let formatted_name = format_file_name(&display_name(entry, config), config);
+    let hyperlinked_name = format_hyperlink(&entry.path, &formatted_name, config);
+    let icon = get_icon(&entry.path, config);
+    let color = get_color_for_scale(entry, config);
+    let type_indicator = get_type_indicator(&entry.file_type, config.classify);
+    let annotations = entry_annotations(entry);
+
+    write!(stdout, "{}", prefix)?;
+    write!(stdout, "{}{}{}{}{}\x1B[0m", color, icon, hyperlinked_name, type_indicator, annotations)?;
+
+    write!(stdout, "{}", size_suffix(entry, config))?;
+
+    writeln!(stdout)
+}
+
+fn sort_nodes(nodes: &mut [TreeNode], config: &Config) {
+    if !is_unsorted(config) {
+        nodes.sort_by(|a, b| compare_entries(&a.info, &b.info, config));
+    }
+}
+
+/// With `--sort none` and no directory grouping, sorting is skipped entirely.
+fn is_unsorted(config: &Config) -> bool {
+    matches!(config.sort_by, SortBy::None) && config.dir_order == DirOrder::Mixed
+}
+
+fn compare_entries(a: &FileInfo, b: &FileInfo, config: &Config) -> cmp::Ordering {
+    if a.is_dir != b.is_dir {
+        match config.dir_order {
+            DirOrder::First => return b.is_dir.cmp(&a.is_dir),
+            DirOrder::Last => return a.is_dir.cmp(&b.is_dir),
+            DirOrder::Mixed => {},
+        }
+    }
+
+    let ordering = match config.sort_by {
+        SortBy::Name => a.path.file_name().cmp(&b.path.file_name()),
+        SortBy::Size => b.size.cmp(&a.size),
+        SortBy::ModTime => b.mod_time.cmp(&a.mod_time),
+        // Names without an extension come first, as with `ls -X`.
+        SortBy::Extension => a.path.extension().cmp(&b.path.extension())
+            .then_with(|| a.path.file_name().cmp(&b.path.file_name())),
+        SortBy::None => cmp::Ordering::Equal,
+        SortBy::Version => compare_natural(
+            &a.path.file_name().unwrap_or_default().to_string_lossy(),
+            &b.path.file_name().unwrap_or_default().to_string_lossy(),
+        ),
+    };
+
+    if config.reverse_sort { ordering.reverse() } else { ordering }
+}
+
+/// Orders runs of digits by their numeric value, so `file2` sorts before `file10` and
+/// `v1.9.0` before `v1.10.0`.
+fn compare_natural(a: &str, b: &str) -> cmp::Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return cmp::Ordering::Equal,
+            (None, Some(_)) => return cmp::Ordering::Less,
+            (Some(_), None) => return cmp::Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let (a_digits, a_rest) = a.split_at(a_len);
+                let (b_digits, b_rest) = b.split_at(b_len);
+
+                let a_value = trim_leading_zeros(a_digits);
+                let b_value = trim_leading_zeros(b_digits);
+                let ordering = a_value.len().cmp(&b_value.len())
+                    .then_with(|| a_value.cmp(b_value))
+                    // Equal values: fewer leading zeros first.
+                    .then_with(|| a_len.cmp(&b_len));
+                if ordering != cmp::Ordering::Equal {
+                    return ordering;
+                }
+
+                a = a_rest;
+                b = b_rest;
+            },
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            },
+        }
+    }
+}
+
+fn trim_leading_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[zeros..]
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("."))
+        .unwrap_or(false)
+}
+
+/// Whether any component of `path` below `root` is hidden.
+fn is_hidden_below(path: &Path, root: &Path) -> bool {
+    path.strip_prefix(root)
+        .map(|relative| relative.components().any(|component| is_hidden(Path::new(component.as_os_str()))))
+        .unwrap_or(false)
+}
+
+fn get_color_for_scale(entry: &FileInfo, config: &Config) -> String {
+    // Entries without metadata have no age or size to scale.
+    let color_scale = if entry.virtual_fs { None } else { config.color_scale };
+    match color_scale {
+        Some(ColorScale::Age) => scale_color(age_position(entry, config), config),
+        Some(ColorScale::Size) => scale_color(size_position(entry, config), config),
+        Some(ColorScale::All) => {
+            // Hue follows the age, brightness the size, so small files dim whatever their age.
+            let age = age_position(entry, config);
+            let (r, g, b) = hue_to_rgb(scale_hue(age));
+            let brightness = 0.4 + 0.6 * size_position(entry, config);
+            let dim = |channel: u8| (channel as f32 * brightness) as u8;
+            format!("\x1B[38;2;{};{};{}m", dim(r), dim(g), dim(b))
+        },
+        None if use_color(config) => EntryClass::of(entry).color().to_string(),
+        None => String::new(),
+    }
+}
+
+// The fixed scale's steps from green to red in the 256-color palette.
+const FIXED_SCALE_COLORS: [u8; 4] = [46, 226, 208, 196];
+
+/// Colors a position on a scale, from 0 (green) to 1 (red).
+fn scale_color(position: f32, config: &Config) -> String {
+    match config.color_scale_mode {
+        ColorScaleMode::Fixed => {
+            let step = (position * (FIXED_SCALE_COLORS.len() - 1) as f32).round() as usize;
+            format!("\x1B[38;5;{}m", FIXED_SCALE_COLORS[step.min(FIXED_SCALE_COLORS.len() - 1)])
+        },
+        ColorScaleMode::Gradient | ColorScaleMode::Relative => {
+            let (r, g, b) = hue_to_rgb(scale_hue(position));
+            format!("\x1B[38;2;{};{};{}m", r, g, b)
+        },
+    }
+}
+
+/// 120 (green) to 0 (red).
+fn scale_hue(position: f32) -> f32 {
+    (1.0 - position) * 120.0
+}
+
+/// Where an entry falls on the age scale: a day, a week and a month in fixed mode, and
+/// anything up to a year in gradient mode.
+fn age_position(entry: &FileInfo, config: &Config) -> f32 {
+    let age = entry_age(entry, config);
+    const DAY: u64 = 60 * 60 * 24;
+    match config.color_scale_mode {
+        ColorScaleMode::Fixed => fixed_step(age, [DAY, DAY * 7, DAY * 30]),
+        ColorScaleMode::Gradient => (age as f32 / (DAY * 365) as f32).min(1.0),
+        ColorScaleMode::Relative => relative_position(age as f64, config.scale_range.map(|range| range.age), |age| age),
+    }
+}
+
+/// Where an entry falls on the size scale: 1 KB, 1 MB and 100 MB in fixed mode, and
+/// anything up to 1 GB in gradient mode.
+fn size_position(entry: &FileInfo, config: &Config) -> f32 {
+    match config.color_scale_mode {
+        ColorScaleMode::Fixed => fixed_step(entry.size, [1 << 10, 1 << 20, 100 << 20]),
+        ColorScaleMode::Gradient => (entry.size as f32 / (1u64 << 30) as f32).min(1.0),
+        // Sizes span orders of magnitude, so they are spread logarithmically.
+        ColorScaleMode::Relative => {
+            relative_position(entry.size as f64, config.scale_range.map(|range| range.size), |size| size.ln_1p())
+        },
+    }
+}
+
+/// Seconds since the timestamp chosen with `--color-scale-time`, falling back to the
+/// modification time where the filesystem doesn't record it.
+fn entry_age(entry: &FileInfo, config: &Config) -> u64 {
+    let time = match config.color_scale_time {
+        ScaleTime::Modified => None,
+        ScaleTime::Accessed => entry.accessed,
+        ScaleTime::Created => entry.created,
+    };
+    SystemTime::now().duration_since(time.unwrap_or(entry.mod_time)).unwrap_or_default().as_secs()
+}
+
+/// Where `value` falls between the smallest and largest in the listing after `scale`.
+fn relative_position(value: f64, range: Option<(u64, u64)>, scale: fn(f64) -> f64) -> f32 {
+    let Some((min, max)) = range else {
+        return 0.0;
+    };
+    let (min, max) = (scale(min as f64), scale(max as f64));
+    if max <= min {
+        return 0.0;
+    }
+    ((scale(value) - min) / (max - min)).clamp(0.0, 1.0) as f32
+}
+
+/// The range of ages and sizes over every listed entry.
+fn scale_range(nodes: &[TreeNode], config: &Config) -> Option<ScaleRange> {
+    let entries = tree_entries(nodes);
+    let ages = entries.iter().map(|entry| entry_age(entry, config));
+    let sizes = entries.iter().map(|entry| entry.size);
+    Some(ScaleRange {
+        age: (ages.clone().min()?, ages.max()?),
+        size: (sizes.clone().min()?, sizes.max()?),
+    })
+}
+
+/// The position of the first threshold `value` stays under, spread evenly over the scale.
+fn fixed_step(value: u64, thresholds: [u64; 3]) -> f32 {
+    let step = thresholds.iter().position(|&threshold| value < threshold).unwrap_or(thresholds.len());
+    step as f32 / thresholds.len() as f32
+}
+
+fn hue_to_rgb(hue: f32) -> (u8, u8, u8) {
+    let c = 1.0;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = 0.0;
+
+    let (r, g, b) = match hue {
+        h if h < 60.0 => (c, x, 0.0),
+        h if h < 120.0 => (x, c, 0.0),
+        h if h < 180.0 => (0.0, c, x),
+        h if h < 240.0 => (0.0, x, c),
+        h if h < 300.0 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    (
+        ((r + m) * 255.0) as u8,
+        ((g + m) * 255.0) as u8,
+        ((b + m) * 255.0) as u8
+    )
+}
+
+fn get_icon(path: &Path, config: &Config) -> &'static str {
+    match config.icons {
+        IconOption::Always => get_icon_for_file(path),
+        IconOption::Auto => {
+            if atty::is(atty::Stream::Stdout) {
+                get_icon_for_file(path)
+            } else {
+                ""
+            }
+        },
+        IconOption::Never => "",
+    }
+}
+
+fn get_icon_for_file(path: &Path) -> &'static str {
+    if path.is_dir() {
+        "📁 "
+    } else {
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("txt") => "📄 ",
+            Some("rs") => "🦀 ",
+            Some("py") => "🐍 ",
+            Some("js") => "🟨 ",
+            Some("html") => "🌐 ",
+            Some("css") => "🎨 ",
+            Some("json") => "🔧 ",
+            Some("md") => "📝 ",
+            Some("png") | Some("jpg") | Some("jpeg") | Some("gif") => "🖼️ ",
+            Some("mp3") | Some("wav") | Some("ogg") => "🎵 ",
+            Some("mp4") | Some("avi") | Some("mkv") => "🎥 ",
+            Some("pdf") => "📚 ",
+            Some("zip") | Some("tar") | Some("gz") => "🗜️ ",
+            Some("exe") => "⚙️ ",
+            _ => "📄 ",
+        }
+    }
+}
+
+fn format_file_name(name: &str, config: &Config) -> String {
+    if config.quote_names && name.contains(' ') {
+        format!("\"{}\"", name)
+    } else {
+        name.to_string()
+    }
+}
+
+
+fn format_hyperlink(path: &Path, name: &str, config: &Config) -> String {
+    if config.hyperlink {
+        let full_path = if path.is_absolute() {
+            path.to_string_lossy().to_string()
+        } else {
+            env::current_dir().unwrap().join(path).to_string_lossy().to_string()
+        };
+        format!("\x1B]8;;file://{}\x1B\\{}\x1B]8;;\x1B\\", full_path, name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn get_display_path(path: &Path, config: &Config) -> PathBuf {
+    match config.absolute_path {
+        AbsolutePathOption::On => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+        AbsolutePathOption::Follow => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+        AbsolutePathOption::Off => path.to_path_buf(),
+    }
+}
+
+/// The name shown for an entry: its file name, or its full path when `--absolute` is set.
+fn display_name(entry: &FileInfo, config: &Config) -> String {
+    if let Some(label) = entry.label {
+        return label.to_string();
+    }
+    match config.absolute_path {
+        AbsolutePathOption::Off if matches!(config.display_mode, DisplayMode::Flat) || config.recent.is_some() => {
+            // Paths relative to the root; a file given as the root is shown as given.
+            match entry.path.strip_prefix(&config.root_path) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative.to_string_lossy().into_owned(),
+                _ => entry.path.to_string_lossy().into_owned(),
+            }
+        },
+        AbsolutePathOption::Off => entry.path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        _ => get_display_path(&entry.path, config).to_string_lossy().into_owned(),
+    }
+}
+
+/// Bracketed notes printed after an entry's name, shared by every display mode.
+fn entry_annotations(entry: &FileInfo) -> String {
+    let notes = entry.notes.iter().map(|note| format!(" [{}]", note));
+    entry.link_target.iter().cloned().chain(notes).collect()
+}
+
+/// A parsed `--where` expression.
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Number { field: NumberField, op: CompareOp, value: u64 },
+    Text { field: TextField, op: CompareOp, value: String },
+    Matches { field: TextField, regex: Regex, negate: bool },
+    Time { op: CompareOp, value: SystemTime },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NumberField {
+    Size,
+    Depth,
+    // Seconds since the last modification.
+    Age,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TextField {
+    Name,
+    Path,
+    Ext,
+    Type,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+const QUERY_OPERATORS: [&str; 11] = ["&&", "||", "==", "!=", "<=", ">=", "!~", "<", ">", "~", "!"];
+
+fn tokenize_query(query: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = Vec::new();
+    let mut rest = query.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::LParen } else { Token::RParen });
+            rest = &rest[1..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..].find(c).ok_or_else(|| format!("Unterminated string in --where: {}", rest))?;
+            tokens.push(Token::Str(rest[1..=end].to_string()));
+            rest = &rest[end + 2..];
+        } else if let Some(op) = QUERY_OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(format!("Unexpected character '{}' in --where expression", c).into());
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Parses `--where` expressions such as `size > 10MB && ext == "log" && depth <= 3`.
+/// `&&` binds tighter than `||`; `!` negates and parentheses group.
+fn parse_query(query: &str) -> Result<Expr, Box<dyn Error>> {
+    let tokens = tokenize_query(query)?;
+    let mut pos = 0;
+    let expr = parse_or(&tokens, &mut pos)?;
+    if pos < tokens.len() {
+        return Err(format!("Unexpected {:?} in --where expression", tokens[pos]).into());
+    }
+    Ok(expr)
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Expr, Box<dyn Error>> {
+    let mut expr = parse_and(tokens, pos)?;
+    while tokens.get(*pos) == Some(&Token::Op("||")) {
+        *pos += 1;
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Expr, Box<dyn Error>> {
+    let mut expr = parse_unary(tokens, pos)?;
+    while tokens.get(*pos) == Some(&Token::Op("&&")) {
+        *pos += 1;
+        expr = Expr::And(Box::new(expr), Box::new(parse_unary(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr, Box<dyn Error>> {
+    match tokens.get(*pos) {
+        Some(Token::Op("!")) => {
+            *pos += 1;
+            Ok(Expr::Not(Box::new(parse_unary(tokens, pos)?)))
+        },
+        Some(Token::LParen) => {
+            *pos += 1;
+            let expr = parse_or(tokens, pos)?;
+            if tokens.get(*pos) != Some(&Token::RParen) {
+                return Err("Missing ')' in --where expression".into());
+            }
+            *pos += 1;
+            Ok(expr)
+        },
+        _ => parse_comparison(tokens, pos),
+    }
+}
+
+fn parse_comparison(tokens: &[Token], pos: &mut usize) -> Result<Expr, Box<dyn Error>> {
+    let field = match tokens.get(*pos) {
+        Some(Token::Word(field)) => field.as_str(),
+        Some(token) => return Err(format!("Expected a field name in --where, found {:?}", token).into()),
+        None => return Err("Incomplete --where expression".into()),
+    };
+    let op = match tokens.get(*pos + 1) {
+        Some(Token::Op(op)) => *op,
+        _ => return Err(format!("Expected a comparison after '{}' in --where", field).into()),
+    };
+    let value = match tokens.get(*pos + 2) {
+        Some(Token::Word(value)) | Some(Token::Str(value)) => value.as_str(),
+        _ => return Err(format!("Expected a value after '{} {}' in --where", field, op).into()),
+    };
+    *pos += 3;
+
+    if op == "~" || op == "!~" {
+        return Ok(Expr::Matches { field: parse_text_field(field)?, regex: Regex::new(value)?, negate: op == "!~" });
+    }
+
+    let op = match op {
+        "==" => CompareOp::Eq,
+        "!=" => CompareOp::Ne,
+        "<" => CompareOp::Lt,
+        "<=" => CompareOp::Le,
+        ">" => CompareOp::Gt,
+        ">=" => CompareOp::Ge,
+        _ => return Err(format!("'{}' is not a comparison operator", op).into()),
+    };
+
+    Ok(match field {
+        "size" => Expr::Number { field: NumberField::Size, op, value: parse_size(value)? },
+        "depth" => Expr::Number { field: NumberField::Depth, op, value: value.parse().map_err(|_| format!("Invalid depth '{}'", value))? },
+        "age" => Expr::Number { field: NumberField::Age, op, value: parse_duration(value)?.as_secs() },
+        "mtime" => Expr::Time { op, value: parse_date(value)? },
+        _ => {
+            let field = parse_text_field(field)?;
+            if !matches!(op, CompareOp::Eq | CompareOp::Ne) {
+                return Err("Text fields can only be compared with ==, !=, ~ and !~".into());
+            }
+            Expr::Text { field, op, value: value.to_string() }
+        },
+    })
+}
+
+fn parse_text_field(field: &str) -> Result<TextField, Box<dyn Error>> {
+    Ok(match field {
+        "name" => TextField::Name,
+        "path" => TextField::Path,
+        "ext" => TextField::Ext,
+        "type" => TextField::Type,
+        _ => return Err(format!(
+            "Unknown field '{}' in --where (expected size, depth, age, mtime, name, path, ext or type)",
+            field
+        ).into()),
+    })
+}
+
+/// Combines a filter with the ones already given, all of which must hold.
+fn add_filter(config: &mut Config, expr: Expr) {
+    config.where_expr = Some(match config.where_expr.take() {
+        Some(existing) => Expr::And(Box::new(existing), Box::new(expr)),
+        None => expr,
+    });
+}
+
+/// Splits find's `+N` (more than), `-N` (less than) and `N` (exactly) forms.
+fn split_find_sign(value: &str) -> (Option<CompareOp>, &str) {
+    if let Some(rest) = value.strip_prefix('+') {
+        (Some(CompareOp::Gt), rest)
+    } else if let Some(rest) = value.strip_prefix('-') {
+        (Some(CompareOp::Lt), rest)
+    } else {
+        (None, value)
+    }
+}
+
+/// `--size +10M`, `--size -1K` or `--size 0`.
+fn parse_size_predicate(value: &str) -> Result<Expr, Box<dyn Error>> {
+    let (op, size) = split_find_sign(value);
+    Ok(Expr::Number { field: NumberField::Size, op: op.unwrap_or(CompareOp::Eq), value: parse_size(size)? })
+}
+
+/// `--mtime -7` (modified within the last 7 days), `--mtime +30` (more than 30 days ago) or
+/// `--mtime 1` (between one and two days ago). Other units can be given, e.g. `-12h`.
+fn parse_mtime_predicate(value: &str) -> Result<Expr, Box<dyn Error>> {
+    let (op, amount) = split_find_sign(value);
+    let amount = if amount.ends_with(|c: char| c.is_ascii_digit()) { format!("{}d", amount) } else { amount.to_string() };
+    let duration = parse_duration(&amount)?;
+    let age = |op, value: std::time::Duration| Expr::Number { field: NumberField::Age, op, value: value.as_secs() };
+
+    Ok(match op {
+        Some(op) => age(op, duration),
+        None => {
+            // Like find, an exact amount covers the whole following unit.
+            let unit = parse_duration(&format!("1{}", amount.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.')))?;
+            let end = duration.checked_add(unit).ok_or_else(|| format!("Invalid duration '{}'", amount))?;
+            Expr::And(Box::new(age(CompareOp::Ge, duration)), Box::new(age(CompareOp::Lt, end)))
+        },
+    })
+}
+
+/// `--depth 3`, `--depth 2..4`, `--depth 2..` or `--depth ..4`.
+fn parse_depth_predicate(value: &str) -> Result<Expr, Box<dyn Error>> {
+    let parse = |depth: &str| depth.parse::<u64>().map_err(|_| format!("Invalid depth '{}' in --depth {}", depth, value));
+    let depth = |op, value| Expr::Number { field: NumberField::Depth, op, value };
+
+    match value.split_once("..") {
+        Some(("", "")) => Err(format!("--depth range '{}' needs at least one bound", value).into()),
+        Some((min, "")) => Ok(depth(CompareOp::Ge, parse(min)?)),
+        Some(("", max)) => Ok(depth(CompareOp::Le, parse(max)?)),
+        Some((min, max)) => Ok(Expr::And(
+            Box::new(depth(CompareOp::Ge, parse(min)?)),
+            Box::new(depth(CompareOp::Le, parse(max)?)),
+        )),
+        None => Ok(depth(CompareOp::Eq, parse(value)?)),
+    }
+}
+
+fn eval_query(expr: &Expr, entry: &FileInfo) -> bool {
+    match expr {
+        Expr::And(a, b) => eval_query(a, entry) && eval_query(b, entry),
+        Expr::Or(a, b) => eval_query(a, entry) || eval_query(b, entry),
+        Expr::Not(a) => !eval_query(a, entry),
+        Expr::Number { field, op, value } => {
+            let actual = match field {
+                NumberField::Size => entry.size,
+                NumberField::Depth => entry.depth as u64,
+                NumberField::Age => SystemTime::now().duration_since(entry.mod_time).unwrap_or_default().as_secs(),
+            };
+            compare(actual.cmp(value), *op)
+        },
+        Expr::Text { field, op, value } => (text_field(*field, entry) == *value) == (*op == CompareOp::Eq),
+        Expr::Matches { field, regex, negate } => regex.is_match(&text_field(*field, entry)) != *negate,
+        Expr::Time { op, value } => compare(entry.mod_time.cmp(value), *op),
+    }
+}
+
+fn text_field(field: TextField, entry: &FileInfo) -> String {
+    match field {
+        TextField::Name => entry.path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        TextField::Path => entry.path.to_string_lossy().into_owned(),
+        TextField::Ext => entry.path.extension().unwrap_or_default().to_string_lossy().into_owned(),
+        TextField::Type => entry_kind(entry).to_string(),
+    }
+}
+
+/// The type name used by `--where`: `file`, `dir`, `symlink` or `other`.
+fn entry_kind(entry: &FileInfo) -> &'static str {
+    if entry.is_dir { "dir" }
+    else if entry.file_type.is_symlink() { "symlink" }
+    else if entry.file_type.is_file() { "file" }
+    else { "other" }
+}
+
+/// The kinds of entry `--type` selects, by fd's one-letter codes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TypeFilter {
+    File,
+    Directory,
+    Symlink,
+    Executable,
+    // Empty files and directories with nothing in them.
+    Empty,
+}
+
+impl TypeFilter {
+    fn matches(self, entry: &FileInfo) -> bool {
+        match self {
+            TypeFilter::File => entry.file_type.is_file(),
+            TypeFilter::Directory => entry.is_dir,
+            TypeFilter::Symlink => entry.file_type.is_symlink(),
+            TypeFilter::Executable => entry.file_type.is_file() && entry.mode & 0o111 != 0,
+            TypeFilter::Empty if entry.is_dir => fs::read_dir(&entry.path).is_ok_and(|mut dir| dir.next().is_none()),
+            TypeFilter::Empty => entry.file_type.is_file() && entry.size == 0,
+        }
+    }
+}
+
+/// Parses `--type` codes, either whole words or letters, separated by commas: `f,l`, `fl`
+/// or `file,symlink`.
+fn parse_type_filters(value: &str) -> Result<Vec<TypeFilter>, Box<dyn Error>> {
+    let mut filters = Vec::new();
+    for code in value.split(',') {
+        let filter = match code {
+            "file" => TypeFilter::File,
+            "dir" | "directory" => TypeFilter::Directory,
+            "symlink" => TypeFilter::Symlink,
+            "executable" => TypeFilter::Executable,
+            "empty" => TypeFilter::Empty,
+            letters if !letters.is_empty() => {
+                for letter in letters.chars() {
+                    filters.push(match letter {
+                        'f' => TypeFilter::File,
+                        'd' => TypeFilter::Directory,
+                        'l' => TypeFilter::Symlink,
+                        'x' => TypeFilter::Executable,
+                        'e' => TypeFilter::Empty,
+                        _ => return Err(format!(
+                            "Unknown type '{}' for --type (expected f, d, l, x or e)", code
+                        ).into()),
+                    });
+                }
+                continue;
+            }
+            _ => return Err("--type requires a value".into()),
+        };
+        filters.push(filter);
+    }
+    Ok(filters)
+}
+
+fn compare(ordering: cmp::Ordering, op: CompareOp) -> bool {
+    match op {
+        CompareOp::Eq => ordering.is_eq(),
+        CompareOp::Ne => ordering.is_ne(),
+        CompareOp::Lt => ordering.is_lt(),
+        CompareOp::Le => ordering.is_le(),
+        CompareOp::Gt => ordering.is_gt(),
+        CompareOp::Ge => ordering.is_ge(),
+    }
+}
+
+/// Parses sizes like `512`, `10K`, `10MB` or `1.5GiB`; units are powers of 1024 to match
+/// how sizes are displayed.
+fn parse_size(text: &str) -> Result<u64, Box<dyn Error>> {
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size '{}'", text))?;
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        "P" | "PB" | "PIB" => 1 << 50,
+        _ => return Err(format!("Invalid size unit '{}' in '{}' (expected B, K, M, G, T or P)", unit, text).into()),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses durations like `90`, `30s`, `15m`, `12h`, `7d`, `2w` or `1y`; a bare number is
+/// in seconds.
+fn parse_duration(text: &str) -> Result<std::time::Duration, Box<dyn Error>> {
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid duration '{}'", text))?;
+    let seconds: f64 = match unit {
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 60.0 * 60.0,
+        "d" => 60.0 * 60.0 * 24.0,
+        "w" => 60.0 * 60.0 * 24.0 * 7.0,
+        "y" => 60.0 * 60.0 * 24.0 * 365.0,
+        _ => return Err(format!("Invalid duration unit '{}' in '{}' (expected s, m, h, d, w or y)", unit, text).into()),
+    };
+    std::time::Duration::try_from_secs_f64(number * seconds).map_err(|_| format!("Invalid duration '{}'", text).into())
+}
+
+/// Parses either a date as `parse_date` does or a duration counted back from now, so
+/// `--older-than 2w` is the same as the date two weeks ago.
+fn parse_time_point(text: &str) -> Result<SystemTime, Box<dyn Error>> {
+    if text.len() >= 10 && text.as_bytes()[4] == b'-' {
+        return parse_date(text);
+    }
+    let duration = parse_duration(text).map_err(|_| {
+        format!("Invalid time '{}' (expected a date like 2024-01-31 or a duration like 2w)", text)
+    })?;
+    Ok(SystemTime::now().checked_sub(duration).unwrap_or(SystemTime::UNIX_EPOCH))
+}
+
+/// Parses a local date (`2024-01-31`) or date and time (`2024-01-31 14:00:00`, also with a `T`).
+fn parse_date(text: &str) -> Result<SystemTime, Box<dyn Error>> {
+    let date_time = chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S"))
+        .or_else(|_| chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|date| date.and_time(chrono::NaiveTime::MIN)))
+        .map_err(|_| format!("Invalid date '{}' (expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS)", text))?;
+    let local = date_time.and_local_timezone(Local).earliest()
+        .ok_or_else(|| format!("Date '{}' does not exist in the local timezone", text))?;
+    Ok(local.into())
+}
+
+/// An entry property that can be referenced from `--format`.
+#[derive(Debug, Clone, Copy)]
+enum FormatField {
+    Name,
+    Path,
+    Size,
+    Bytes,
+    Mtime,
+    Perms,
+    Type,
+    Depth,
+    Ext,
+    Indicator,
+    Indent,
+    RelPath,
+    Parent,
+    Target,
+    Blocks,
+    Mode,
+    TypeChar,
+    User,
+    Group,
+    Uid,
+    Gid,
+}
+
+#[derive(Debug)]
+enum FormatPiece<F> {
+    Literal(String),
+    // The spec is a strftime format for time fields and `[<>^]width` for everything else.
+    Field { field: F, spec: String },
+}
+
+/// A run-wide value available in the header and footer sections of `--template`.
+#[derive(Debug, Clone, Copy)]
+enum SummaryField {
+    Root,
+    Directories,
+    Files,
+    TotalSize,
+    TotalBytes,
+    Symlinks,
+    BrokenLinks,
+    Sockets,
+    Fifos,
+    Devices,
+    Errors,
+}
+
+#[derive(Debug)]
+struct Template {
+    header: Vec<FormatPiece<SummaryField>>,
+    entry: Vec<FormatPiece<FormatField>>,
+    footer: Vec<FormatPiece<SummaryField>>,
+}
+
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Splits text into literals and `{name:spec}` placeholders, where `{{` and `}}` stand for
+/// literal braces.
+fn parse_placeholders<F>(
+    text: &str,
+    parse_field: impl Fn(&str, &str) -> Result<F, Box<dyn Error>>,
+) -> Result<Vec<FormatPiece<F>>, Box<dyn Error>> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            },
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(format!("Unclosed '{{' in format string: {}", text).into()),
+                    }
+                }
+
+                let (name, spec) = placeholder.split_once(':').unwrap_or((&placeholder, ""));
+                let field = parse_field(name, spec)?;
+
+                if !literal.is_empty() {
+                    pieces.push(FormatPiece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(FormatPiece::Field { field, spec: spec.to_string() });
+            },
+            '}' => return Err(format!("Unmatched '}}' in format string: {}", text).into()),
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        pieces.push(FormatPiece::Literal(literal));
+    }
+
+    Ok(pieces)
+}
+
+/// Parses a format string like `{perms} {size:>8} {mtime:%Y-%m-%d} {path}`.
+fn parse_format(format: &str) -> Result<Vec<FormatPiece<FormatField>>, Box<dyn Error>> {
+    parse_placeholders(format, |name, spec| {
+        let field = parse_format_field(name)?;
+        if !matches!(field, FormatField::Mtime) && !is_alignment_spec(spec) {
+            return Err(format!("Invalid alignment '{}' for {{{}}}, expected e.g. '>8'", spec, name).into());
+        }
+        Ok(field)
+    })
+}
+
+/// Translates a `find -printf` format like `%p %s %TY-%Tm-%Td\n` into format pieces.
+/// Each entry already ends its own line, so a trailing `\n` is dropped.
+fn parse_printf(format: &str) -> Result<Vec<FormatPiece<FormatField>>, Box<dyn Error>> {
+    let format = format.strip_suffix("\\n").unwrap_or(format);
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => literal.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some(other) => return Err(format!("Unknown escape '\\{}' in --printf format", other).into()),
+                None => return Err("--printf format ends with a lone '\\'".into()),
+            }),
+            '%' if chars.peek() == Some(&'%') => {
+                chars.next();
+                literal.push('%');
+            },
+            '%' => {
+                // An optional printf-style width, right-aligned unless it starts with '-'.
+                let left = chars.next_if_eq(&'-').is_some();
+                let mut width = String::new();
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    width.push(digit);
+                }
+                let spec = if width.is_empty() { String::new() } else { format!("{}{}", if left { '<' } else { '>' }, width) };
+
+                let directive = chars.next().ok_or("--printf format ends with a lone '%'")?;
+                let (field, spec) = match directive {
+                    'p' => (FormatField::Path, spec),
+                    'P' => (FormatField::RelPath, spec),
+                    'f' => (FormatField::Name, spec),
+                    'h' => (FormatField::Parent, spec),
+                    'l' => (FormatField::Target, spec),
+                    's' => (FormatField::Bytes, spec),
+                    'k' => (FormatField::Blocks, spec),
+                    'd' => (FormatField::Depth, spec),
+                    'm' => (FormatField::Mode, spec),
+                    'M' => (FormatField::Perms, spec),
+                    'y' => (FormatField::TypeChar, spec),
+                    'u' => (FormatField::User, spec),
+                    'g' => (FormatField::Group, spec),
+                    'U' => (FormatField::Uid, spec),
+                    'G' => (FormatField::Gid, spec),
+                    't' | 'T' if !spec.is_empty() => {
+                        return Err(format!("Widths are not supported for %{} in --printf", directive).into());
+                    },
+                    't' => (FormatField::Mtime, "%a %b %e %H:%M:%S %Y".to_string()),
+                    'T' => (FormatField::Mtime, match chars.next() {
+                        Some('@') => "%s".to_string(),
+                        Some('+') => "%Y-%m-%d+%H:%M:%S".to_string(),
+                        Some(c) if c.is_ascii_alphabetic() => format!("%{}", c),
+                        _ => return Err("%T in --printf must be followed by a time field letter, '@' or '+'".into()),
+                    }),
+                    other => return Err(format!("Unsupported --printf directive '%{}'", other).into()),
+                };
+
+                if !literal.is_empty() {
+                    pieces.push(FormatPiece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(FormatPiece::Field { field, spec });
+            },
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        pieces.push(FormatPiece::Literal(literal));
+    }
+
+    Ok(pieces)
+}
+
+fn parse_summary_format(text: &str) -> Result<Vec<FormatPiece<SummaryField>>, Box<dyn Error>> {
+    parse_placeholders(text, |name, spec| {
+        let field = match name {
+            "root" => SummaryField::Root,
+            "directories" => SummaryField::Directories,
+            "files" => SummaryField::Files,
+            "total_size" => SummaryField::TotalSize,
+            "total_bytes" => SummaryField::TotalBytes,
+            "symlinks" => SummaryField::Symlinks,
+            "broken_links" => SummaryField::BrokenLinks,
+            "sockets" => SummaryField::Sockets,
+            "fifos" => SummaryField::Fifos,
+            "devices" => SummaryField::Devices,
+            "errors" => SummaryField::Errors,
+            _ => return Err(format!(
+                "Unknown template field '{}' (header and footer accept root, directories, files, total_size, total_bytes, symlinks, broken_links, sockets, fifos, devices or errors)",
+                name
+            ).into()),
+        };
+        if !is_alignment_spec(spec) {
+            return Err(format!("Invalid alignment '{}' for {{{}}}, expected e.g. '>8'", spec, name).into());
+        }
+        Ok(field)
+    })
+}
+
+/// Loads a template file made of `{% header %}`, `{% entry %}` and `{% footer %}` sections.
+/// The entry section is repeated for every entry and accepts the `--format` fields; text
+/// before the first marker belongs to the header.
+fn load_template(path: &str) -> Result<Template, Box<dyn Error>> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read template {}: {}", path, e))?;
+
+    let mut sections = [String::new(), String::new(), String::new()];
+    let mut current = 0;
+    for line in contents.split_inclusive('\n') {
+        let marker = line.trim().strip_prefix("{%").and_then(|rest| rest.strip_suffix("%}")).map(str::trim);
+        match marker {
+            Some("header") => current = 0,
+            Some("entry") => current = 1,
+            Some("footer") => current = 2,
+            Some(other) => return Err(format!("Unknown template section '{}' in {}", other, path).into()),
+            None => sections[current].push_str(line),
+        }
+    }
+
+    let [header, entry, footer] = sections;
+    Ok(Template {
+        header: parse_summary_format(&header)?,
+        entry: parse_format(&entry)?,
+        footer: parse_summary_format(&footer)?,
+    })
+}
+
+fn parse_format_field(name: &str) -> Result<FormatField, Box<dyn Error>> {
+    Ok(match name {
+        "name" => FormatField::Name,
+        "path" => FormatField::Path,
+        "size" => FormatField::Size,
+        "bytes" => FormatField::Bytes,
+        "mtime" => FormatField::Mtime,
+        "perms" => FormatField::Perms,
+        "type" => FormatField::Type,
+        "depth" => FormatField::Depth,
+        "ext" => FormatField::Ext,
+        "indicator" => FormatField::Indicator,
+        "indent" => FormatField::Indent,
+        "relpath" => FormatField::RelPath,
+        "parent" => FormatField::Parent,
+        "target" => FormatField::Target,
+        "blocks" => FormatField::Blocks,
+        "mode" => FormatField::Mode,
+        "typechar" => FormatField::TypeChar,
+        "user" => FormatField::User,
+        "group" => FormatField::Group,
+        "uid" => FormatField::Uid,
+        "gid" => FormatField::Gid,
+        _ => return Err(format!(
+            "Unknown format field '{}' (expected name, path, relpath, parent, target, size, bytes, blocks, mtime, \
+             perms, mode, type, typechar, user, group, uid, gid, depth, indent, ext or indicator)",
+            name
+        ).into()),
+    })
+}
+
+fn is_alignment_spec(spec: &str) -> bool {
+    let width = spec.strip_prefix(['<', '>', '^']).unwrap_or(spec);
+    width.chars().all(|c| c.is_ascii_digit())
+}
+
+fn render_format(pieces: &[FormatPiece<FormatField>], entry: &FileInfo, config: &Config) -> String {
+    let mut line = String::new();
+    for piece in pieces {
+        match piece {
+            FormatPiece::Literal(text) => line.push_str(text),
+            FormatPiece::Field { field: FormatField::Mtime, spec } => {
+                let mod_time: DateTime<Local> = entry.mod_time.into();
+                let spec = if spec.is_empty() { DEFAULT_TIME_FORMAT } else { spec };
+                line.push_str(&mod_time.format(spec).to_string());
+            },
+            FormatPiece::Field { field, spec } => {
+                line.push_str(&align(&format_field(*field, entry, config), spec));
+            },
+        }
+    }
+    line
+}
+
+fn format_field(field: FormatField, entry: &FileInfo, config: &Config) -> String {
+    match field {
+        FormatField::Name => display_name(entry, config),
+        FormatField::Path => get_display_path(&entry.path, config).display().to_string(),
+        FormatField::Size => format_entry_size(entry.size, config),
+        FormatField::Bytes => entry.size.to_string(),
+        FormatField::Mtime => {
+            let mod_time: DateTime<Local> = entry.mod_time.into();
+            mod_time.format(DEFAULT_TIME_FORMAT).to_string()
+        },
+        FormatField::Perms => format_permissions(entry),
+        FormatField::Type => get_file_type_str(&entry.file_type).to_string(),
+        FormatField::Depth => entry.depth.to_string(),
+        FormatField::Ext => entry.path.extension().unwrap_or_default().to_string_lossy().into_owned(),
+        FormatField::Indicator => get_type_indicator(&entry.file_type, config.classify).to_string(),
+        FormatField::Indent => "  ".repeat(entry.depth.saturating_sub(1)),
+        FormatField::RelPath => entry.path.strip_prefix(&config.root_path).unwrap_or(&entry.path).display().to_string(),
+        FormatField::Parent => entry.path.parent().unwrap_or(Path::new("")).display().to_string(),
+        FormatField::Target => fs::read_link(&entry.path).map(|target| target.display().to_string()).unwrap_or_default(),
+        FormatField::Blocks => entry.disk_size.div_ceil(1024).to_string(),
+        FormatField::Mode => format!("{:o}", entry.mode & 0o7777),
+        FormatField::TypeChar => match type_char(&entry.file_type) {
+            '-' => 'f',
+            c => c,
+        }.to_string(),
+        FormatField::User => entry.owner.map(|(uid, _)| user_name(uid)).unwrap_or_default(),
+        FormatField::Group => entry.owner.map(|(_, gid)| group_name(gid)).unwrap_or_default(),
+        FormatField::Uid => entry.owner.map(|(uid, _)| uid.to_string()).unwrap_or_default(),
+        FormatField::Gid => entry.owner.map(|(_, gid)| gid.to_string()).unwrap_or_default(),
+    }
+}
+
+fn render_summary_format(pieces: &[FormatPiece<SummaryField>], root: &FileInfo, stats: &TreeStats, config: &Config) -> String {
+    let mut text = String::new();
+    for piece in pieces {
+        match piece {
+            FormatPiece::Literal(literal) => text.push_str(literal),
+            FormatPiece::Field { field, spec } => {
+                let value = match field {
+                    SummaryField::Root => get_display_path(&root.path, config).display().to_string(),
+                    SummaryField::Directories => stats.directories.to_string(),
+                    SummaryField::Files => stats.files.to_string(),
+                    SummaryField::TotalSize => format_size(stats.total_size),
+                    SummaryField::TotalBytes => stats.total_size.to_string(),
+                    SummaryField::Symlinks => stats.symlinks.to_string(),
+                    SummaryField::BrokenLinks => stats.broken_links.to_string(),
+                    SummaryField::Sockets => stats.sockets.to_string(),
+                    SummaryField::Fifos => stats.fifos.to_string(),
+                    SummaryField::Devices => stats.devices.to_string(),
+                    SummaryField::Errors => stats.errors.to_string(),
+                };
+                text.push_str(&align(&value, spec));
+            },
+        }
+    }
+    text
+}
+
+/// Renders the whole report from `--template`, replacing the regular listing and summary.
+fn print_template(template: &Template, root: TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let header = render_summary_format(&template.header, &root.info, stats, config);
+    let footer = render_summary_format(&template.footer, &root.info, stats, config);
+
+    write!(stdout, "{}", header)?;
+    for entry in flatten_root(root, config) {
+        write!(stdout, "{}", render_format(&template.entry, &entry, config))?;
+    }
+    write!(stdout, "{}", footer)
+}
+
+const HTML_STYLE: &str = r#"
+body { font-family: ui-monospace, SFMono-Regular, Menlo, Consolas, monospace; margin: 2em; color: #24292f; }
+h1 { font-size: 1.3em; }
+#search { width: 100%; max-width: 30em; padding: 0.4em; margin-bottom: 1em; font: inherit; }
+.tree, .tree ul { list-style: none; margin: 0; padding-left: 1.4em; }
+.tree { padding-left: 0; }
+.tree li { margin: 0.1em 0; }
+summary { cursor: pointer; }
+.dir > details > summary { font-weight: bold; }
+.badge { font-weight: normal; font-size: 0.8em; padding: 0 0.5em; border-radius: 1em; background: #ddf4ff; color: #0969da; }
+.size { font-size: 0.8em; color: #57606a; }
+.tree a { color: inherit; text-decoration: none; }
+.tree a:hover { text-decoration: underline; }
+.dir > details > summary { color: #0969da; }
+.symlink { color: #1b7c83; }
+.capability { color: #ffffff; background: #cf222e; }
+.exec { color: #1a7f37; }
+.archive { color: #cf222e; }
+.image { color: #8250df; }
+.report { margin-top: 1.5em; color: #57606a; }
+"#;
+
+// Shows entries whose name contains the search text, plus the directories leading to them,
+// which are opened so every match is visible.
+const HTML_SCRIPT: &str = r#"
+const search = document.getElementById('search');
+search.addEventListener('input', () => {
+  const query = search.value.trim().toLowerCase();
+  const visible = new Set();
+  for (const item of Array.from(document.querySelectorAll('.tree li')).reverse()) {
+    const children = item.querySelectorAll(':scope > details > ul > li');
+    const childMatches = Array.from(children).some(child => visible.has(child));
+    const show = !query || childMatches || item.dataset.name.toLowerCase().includes(query);
+    if (show) visible.add(item);
+    item.hidden = !show;
+    const details = item.querySelector(':scope > details');
+    if (details && query) details.open = childMatches;
+  }
+});
+"#;
+
+/// Renders the tree as a self-contained HTML page: directories collapse with
+/// `<details>`, carry a badge with the size of their contents, and a search box
+/// filters entries by name.
+fn print_html(root: &TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let title = html_escape(&get_display_path(&root.info.path, config).display().to_string());
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n", title, HTML_STYLE));
+    html.push_str(&format!("<h1>{}</h1>\n", title));
+    html.push_str("<input id=\"search\" type=\"search\" placeholder=\"Filter by name\" autofocus>\n");
+    html.push_str("<ul class=\"tree\">\n");
+    for child in &root.children {
+        write_html_node(child, &root.info.path, config, &mut html);
+    }
+    html.push_str("</ul>\n");
+    html.push_str(&format!(
+        "<p class=\"report\">{} directories, {} files, {} total</p>\n",
+        stats.directories, stats.files, format_size(stats.total_size)
+    ));
+    html.push_str(&format!("<script>{}</script>\n</body>\n</html>\n", HTML_SCRIPT));
+
+    io::stdout().lock().write_all(html.as_bytes())
+}
+
+fn write_html_node(node: &TreeNode, root: &Path, config: &Config, html: &mut String) {
+    let info = &node.info;
+    let name = html_escape(&display_name(info, config));
+    let annotations = html_escape(&entry_annotations(info));
+    let class = match EntryClass::of(info).css_class() {
+        "file" => "file".to_string(),
+        kind => format!("file {}", kind),
+    };
+    let icon = match get_icon(&info.path, config).trim_end() {
+        "" => String::new(),
+        icon => format!("<span class=\"icon\">{}</span> ", icon),
+    };
+    let label = match &config.base_href {
+        Some(base) => {
+            let relative = info.path.strip_prefix(root).unwrap_or(&info.path);
+            let slash = if info.is_dir { "/" } else { "" };
+            format!("<a href=\"{}/{}{}\">{}</a>", html_escape(base), url_encode_path(relative), slash, name)
+        }
+        None => name.clone(),
+    };
+
+    if info.is_dir {
+        // Only the root's own directories start expanded.
+        let open = if info.depth <= 1 { " open" } else { "" };
+        html.push_str(&format!(
+            "<li class=\"dir\" data-name=\"{}\"><details{}><summary>{}{}/{} <span class=\"badge\">{}</span></summary>\n<ul>\n",
+            name, open, icon, label, annotations, format_size(subtree_size(node))
+        ));
+        for child in &node.children {
+            write_html_node(child, root, config, html);
+        }
+        html.push_str("</ul>\n</details></li>\n");
+    } else {
+        html.push_str(&format!(
+            "<li class=\"{}\" data-name=\"{}\">{}{}{} <span class=\"size\">{}</span></li>\n",
+            class, name, icon, label, annotations, format_size(info.size)
+        ));
+    }
+}
+
+/// Percent-encodes a relative path for use in a URL, keeping its `/` separators.
+fn url_encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for (index, component) in path.components().enumerate() {
+        if index > 0 {
+            encoded.push('/');
+        }
+        for byte in component.as_os_str().to_string_lossy().bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+    }
+    encoded
+}
+
+/// Prints the tree as an Org-mode outline: one heading per entry, nested by depth, with
+/// its size and modification time in a property drawer. The root heading carries the
+/// summary counts.
+fn print_org(root: &TreeNode, stats: &TreeStats, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let title = get_display_path(&root.info.path, config).display().to_string();
+    writeln!(stdout, "#+TITLE: {}", title)?;
+    writeln!(stdout, "* {}", title)?;
+    writeln!(stdout, ":PROPERTIES:")?;
+    writeln!(stdout, ":DIRECTORIES: {}", stats.directories)?;
+    writeln!(stdout, ":FILES: {}", stats.files)?;
+    writeln!(stdout, ":TOTAL_SIZE: {}", format_size(stats.total_size))?;
+    writeln!(stdout, ":END:")?;
+    for child in &root.children {
+        write_org_node(child, config, &mut stdout)?;
+    }
+    Ok(())
+}
+
+fn write_org_node(node: &TreeNode, config: &Config, out: &mut impl Write) -> io::Result<()> {
+    let info = &node.info;
+    let mod_time: DateTime<Local> = info.mod_time.into();
+    let size = if info.is_dir { subtree_size(node) } else { info.size };
+
+    writeln!(out, "{} {}{}{}",
+        "*".repeat(info.depth + 1),
+        display_name(info, config),
+        if info.is_dir { "/" } else { "" },
+        entry_annotations(info)
+    )?;
+    writeln!(out, ":PROPERTIES:")?;
+    writeln!(out, ":TYPE: {}", entry_kind(info))?;
+    writeln!(out, ":SIZE: {}", format_size(size))?;
+    writeln!(out, ":BYTES: {}", size)?;
+    writeln!(out, ":MODIFIED: {}", mod_time.format("[%Y-%m-%d %a %H:%M]"))?;
+    writeln!(out, ":END:")?;
+    for child in &node.children {
+        write_org_node(child, config, out)?;
+    }
+    Ok(())
+}
+
+/// Prints a POSIX shell script that recreates the directory skeleton below the current
+/// directory: empty files, directories and symlinks, plus their permissions with
+/// --export-perms. Directory permissions are applied last, deepest first, so read-only
+/// directories do not block their own contents.
+fn print_export_script(root: &TreeNode, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "#!/bin/sh")?;
+    writeln!(stdout, "# Recreates the structure of {}", get_display_path(&root.info.path, config).display())?;
+    writeln!(stdout, "set -e")?;
+
+    let mut directory_modes = Vec::new();
+    for entry in tree_entries(&root.children) {
+        let relative = entry.path.strip_prefix(&root.info.path).unwrap_or(&entry.path);
+        let quoted = shell_quote(&relative.to_string_lossy());
+        if entry.file_type.is_symlink() {
+            let target = fs::read_link(&entry.path).unwrap_or_default();
+            writeln!(stdout, "ln -s -- {} {}", shell_quote(&target.to_string_lossy()), quoted)?;
+            continue;
+        }
+        if entry.is_dir {
+            writeln!(stdout, "mkdir -p -- {}", quoted)?;
+            directory_modes.push((entry.mode & 0o7777, quoted));
+        } else if entry.file_type.is_file() {
+            writeln!(stdout, "touch -- {}", quoted)?;
+            if config.export_perms {
+                writeln!(stdout, "chmod {:o} -- {}", entry.mode & 0o7777, quoted)?;
+            }
+        } else {
+            writeln!(stdout, "# skipped special file {}", quoted)?;
+        }
+    }
+
+    if config.export_perms {
+        for (mode, quoted) in directory_modes.iter().rev() {
+            writeln!(stdout, "chmod {:o} -- {}", mode, quoted)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the tree as a PlantUML work breakdown structure or mind map. Directories are
+/// boxed nodes and files boxless leaves.
+fn print_plantuml(root: &TreeNode, style: PlantUmlStyle, config: &Config) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let (start, end) = match style {
+        PlantUmlStyle::Wbs => ("@startwbs", "@endwbs"),
+        PlantUmlStyle::MindMap => ("@startmindmap", "@endmindmap"),
+    };
+    writeln!(stdout, "{}", start)?;
+    writeln!(stdout, "* {}", get_display_path(&root.info.path, config).display())?;
+    for child in &root.children {
+        write_plantuml_node(child, config, &mut stdout)?;
+    }
+    writeln!(stdout, "{}", end)
+}
+
+fn write_plantuml_node(node: &TreeNode, config: &Config, out: &mut impl Write) -> io::Result<()> {
+    let info = &node.info;
+    let marker = "*".repeat(info.depth + 1);
+    if info.is_dir {
+        writeln!(out, "{} {}/", marker, display_name(info, config))?;
+    } else {
+        writeln!(out, "{}_ {}", marker, display_name(info, config))?;
+    }
+    for child in &node.children {
+        write_plantuml_node(child, config, out)?;
+    }
+    Ok(())
+}
+
+/// Prints the tree as a Graphviz graph for `dot -Tsvg`: every directory is a cluster
+/// labelled with its name, nested like the tree, holding its files as note-shaped nodes.
+fn print_dot(root: &TreeNode, config: &Config) -> io::Result<()> {
+    let mut dot = String::from("digraph tree {\n  node [shape=note, fontname=\"monospace\"];\n  graph [fontname=\"monospace\"];\n");
+    let mut next_id = 0;
+    let label = get_display_path(&root.info.path, config).to_string_lossy().into_owned();
+    write_dot_cluster(root, &label, 1, config, &mut next_id, &mut dot);
+    dot.push_str("}\n");
+    io::stdout().lock().write_all(dot.as_bytes())
+}
+
+fn write_dot_cluster(node: &TreeNode, label: &str, level: usize, config: &Config, next_id: &mut usize, dot: &mut String) {
+    let indent = "  ".repeat(level);
+    *next_id += 1;
+    dot.push_str(&format!("{}subgraph cluster_{} {{\n{}  label=\"{}/\";\n", indent, next_id, indent, dot_escape(label)));
+    if node.children.is_empty() {
+        // Graphviz leaves out clusters without nodes.
+        *next_id += 1;
+        dot.push_str(&format!("{}  n{} [label=\"\", shape=point, style=invis];\n", indent, next_id));
+    }
+    for child in &node.children {
+        let name = display_name(&child.info, config);
+        if child.info.is_dir {
+            write_dot_cluster(child, &name, level + 1, config, next_id, dot);
+        } else {
+            *next_id += 1;
+            dot.push_str(&format!("{}  n{} [label=\"{}\"];\n", indent, next_id, dot_escape(&name)));
+        }
+    }
+    dot.push_str(&format!("{}}}\n", indent));
+}
+
+/// Prints the tree as a Mermaid flowchart for GitHub and GitLab Markdown: one node per
+/// entry, linked from its directory, with directories styled apart.
+fn print_mermaid(root: &TreeNode, config: &Config) -> io::Result<()> {
+    let mut mermaid = String::from("graph TD\n  classDef dir fill:#ddf4ff,stroke:#0969da,font-weight:bold\n");
+    let label = format!("{}/", get_display_path(&root.info.path, config).to_string_lossy());
+    mermaid.push_str(&format!("  n0[\"{}\"]:::dir\n", mermaid_label(&label, config)));
+    let mut next_id = 0;
+    write_mermaid_nodes(&root.children, 0, config, &mut next_id, &mut mermaid);
+    io::stdout().lock().write_all(mermaid.as_bytes())
+}
+
+fn write_mermaid_nodes(nodes: &[TreeNode], parent: usize, config: &Config, next_id: &mut usize, mermaid: &mut String) {
+    for node in nodes {
+        *next_id += 1;
+        let id = *next_id;
+        let (slash, class) = if node.info.is_dir { ("/", ":::dir") } else { ("", "") };
+        let label = format!("{}{}", display_name(&node.info, config), slash);
+        mermaid.push_str(&format!("  n{} --> n{}[\"{}\"]{}\n", parent, id, mermaid_label(&label, config), class));
+        write_mermaid_nodes(&node.children, id, config, next_id, mermaid);
+    }
+}
+
+/// Shortens a label to `--mermaid-label-width` characters and escapes what Mermaid would
+/// otherwise read as markup.
+fn mermaid_label(label: &str, config: &Config) -> String {
+    let width = config.mermaid_label_width;
+    let label: String = if width > 0 && label.chars().count() > width {
+        label.chars().take(width.saturating_sub(1)).chain(['…']).collect()
+    } else {
+        label.to_string()
+    };
+    label.replace('#', "#35;").replace('"', "#quot;").replace('<', "#lt;").replace('>', "#gt;")
+}
+
+/// Escapes text for a double-quoted DOT string.
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// One box of the SVG layout.
+struct SvgRow {
+    depth: usize,
+    label: String,
+    size: u64,
+    is_dir: bool,
+    parent: Option<usize>,
+}
+
+const SVG_ROW_HEIGHT: usize = 28;
+const SVG_INDENT: usize = 28;
+const SVG_CHAR_WIDTH: usize = 8;
+const SVG_PADDING: usize = 16;
+
+/// Draws the tree as an SVG graphic, to a file or to stdout for `-`: one box per entry,
+/// indented by depth and joined to its directory by elbow lines, shaded from green to red
+/// by size relative to the largest entry.
+fn write_svg(root: &TreeNode, svg_path: &str, config: &Config) -> io::Result<()> {
+    let mut rows = Vec::new();
+    svg_rows(root, None, config, &mut rows);
+
+    let box_height = SVG_ROW_HEIGHT - 8;
+    let box_x = |row: &SvgRow| SVG_PADDING + row.depth * SVG_INDENT;
+    let box_y = |index: usize| SVG_PADDING + index * SVG_ROW_HEIGHT;
+    let box_width = |row: &SvgRow| row.label.chars().count() * SVG_CHAR_WIDTH + 16;
+
+    let largest = rows.iter().skip(1).map(|row| row.size).max().unwrap_or(0).max(1);
+    let width = rows.iter().map(|row| box_x(row) + box_width(row)).max().unwrap_or(0) + SVG_PADDING;
+    let height = rows.len() * SVG_ROW_HEIGHT + 2 * SVG_PADDING;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"monospace\" font-size=\"13\">\n",
+        width, height
+    );
+    for (index, row) in rows.iter().enumerate() {
+        if let Some(parent) = row.parent {
+            let x = box_x(&rows[parent]) + SVG_INDENT / 2;
+            let y = box_y(index) + box_height / 2;
+            svg.push_str(&format!(
+                "<path d=\"M{} {} V{} H{}\" fill=\"none\" stroke=\"#8c959f\"/>\n",
+                x, box_y(parent) + box_height, y, box_x(row)
+            ));
+        }
+    }
+    for (index, row) in rows.iter().enumerate() {
+        // Log scale, so small files are not all the same color.
+        let fraction = ((row.size as f64).ln_1p() / (largest as f64).ln_1p()).min(1.0);
+        let (r, g, b) = hue_to_rgb(120.0 * (1.0 - fraction as f32));
+        let pastel = |channel: u8| (channel as u16 + 2 * 255) / 3;
+        svg.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"rgb({},{},{})\" stroke=\"{}\"/>\n",
+            box_x(row), box_y(index), box_width(row), box_height, pastel(r), pastel(g), pastel(b),
+            if row.is_dir { "#0969da" } else { "#8c959f" }
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\"{}>{}</text>\n",
+            box_x(row) + 8, box_y(index) + box_height / 2 + 5,
+            if row.is_dir { " font-weight=\"bold\"" } else { "" },
+            html_escape(&row.label)
+        ));
+    }
+    svg.push_str("</svg>\n");
+
+    if svg_path == "-" {
+        io::stdout().lock().write_all(svg.as_bytes())
+    } else {
+        fs::write(svg_path, svg)
+    }
+}
+
+fn svg_rows(node: &TreeNode, parent: Option<usize>, config: &Config, rows: &mut Vec<SvgRow>) {
+    let size = subtree_size(node);
+    let name = if parent.is_none() {
+        get_display_path(&node.info.path, config).display().to_string()
+    } else {
+        display_name(&node.info, config)
+    };
+    let index = rows.len();
+    rows.push(SvgRow {
+        depth: node.info.depth,
+        label: format!("{}{} ({})", name, if node.info.is_dir && parent.is_some() { "/" } else { "" }, format_size(size)),
+        size,
+        is_dir: node.info.is_dir,
+        parent,
+    });
+    for child in &node.children {
+        svg_rows(child, Some(index), config, rows);
+    }
+}
+
+/// Total size of the files listed below a directory, or a file's own size.
+fn subtree_size(node: &TreeNode) -> u64 {
+    if node.info.is_dir {
+        node.children.iter().map(subtree_size).sum()
+    } else {
+        node.info.size
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Pads `value` according to a `[<>^]width` spec; text is left-aligned by default.
+fn align(value: &str, spec: &str) -> String {
+    let (alignment, width) = match spec.chars().next() {
+        Some(c @ ('<' | '>' | '^')) => (c, &spec[1..]),
+        _ => ('<', spec),
+    };
+    let width: usize = width.parse().unwrap_or(0);
+    match alignment {
+        '>' => format!("{:>width$}", value, width = width),
+        '^' => format!("{:^width$}", value, width = width),
+        _ => format!("{:<width$}", value, width = width),
+    }
+}
+
+/// Renders the permission bits `ls -l` style, e.g. `drwxr-xr-x`.
+fn format_permissions(entry: &FileInfo) -> String {
+    let mut perms = String::with_capacity(10);
+    perms.push(type_char(&entry.file_type));
+
+    let mode = entry.mode;
+    let special = [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')];
+    for (shift, (special_bit, special_char)) in [6, 3, 0].into_iter().zip(special) {
+        let bits = (mode >> shift) & 0o7;
+        perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        perms.push(match (bits & 0o1 != 0, mode & special_bit != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    if entry.has_acl {
+        perms.push('+');
+    }
+
+    perms
+}
+
+/// The type letter `ls -l` shows in front of the permissions.
+fn type_char(file_type: &FileKind) -> char {
+    match file_type {
+        FileKind::Directory => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::Fifo => 'p',
+        FileKind::Socket => 's',
+        FileKind::CharDevice => 'c',
+        FileKind::BlockDevice => 'b',
+        FileKind::File | FileKind::Other => '-',
+    }
+}
+
+fn get_type_indicator(file_type: &FileKind, classify: Classify) -> &'static str {
+    match classify {
+        Classify::Always => match file_type {
+            FileKind::Directory => "/",
+            FileKind::Symlink => "@",
+            FileKind::Socket => "=",
+            FileKind::Fifo => "|",
+            _ => "",
+        },
+        Classify::Auto => match file_type {
+            FileKind::Directory => "/",
+            FileKind::Symlink => "@",
+            _ => "",
+        },
+        Classify::Never => "",
+    }
+}
+
+fn get_file_type_str(file_type: &FileKind) -> &'static str {
+    if file_type.is_dir() { "Directory" }
+    else if file_type.is_symlink() { "Symlink" }
+    else if file_type.is_file() { "File" }
+    else { "Other" }
+}
+
+/// The bracketed size printed after a name with --show-size, followed by its --bars graph.
+fn size_suffix(entry: &FileInfo, config: &Config) -> String {
+    if !config.show_size {
+        return String::new();
+    }
+    match entry.bar {
+        Some(fraction) => format!(" [{}] {}", entry_size_label(entry, config), size_bar(fraction)),
+        None => format!(" [{}]", entry_size_label(entry, config)),
+    }
+}
+
+const BAR_WIDTH: usize = 10;
+
+/// A fixed-width bar filled in eighths of a cell, e.g. `│█████▍    │`.
+fn size_bar(fraction: f64) -> String {
+    const PARTIAL: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+    let eighths = (fraction.clamp(0.0, 1.0) * (BAR_WIDTH * 8) as f64).round() as usize;
+    let mut bar = "█".repeat(eighths / 8);
+    if !eighths.is_multiple_of(8) {
+        bar.push(PARTIAL[eighths % 8]);
+    }
+    let filled = bar.chars().count();
+    format!("│{}{}│", bar, " ".repeat(BAR_WIDTH - filled))
+}
+
+/// The size shown in brackets after a name, with its share of the parent for --percent.
+/// Sparse files are marked with what they actually take up on disk.
+fn entry_size_label(entry: &FileInfo, config: &Config) -> String {
+    let size = if is_sparse(entry) {
+        format!("~{} (sparse, {} on disk)", format_entry_size(entry.size, config), format_entry_size(entry.disk_size, config))
+    } else {
+        format_entry_size(entry.size, config)
+    };
+    match entry.share {
+        Some(share) => format!("{} ({:.0}%)", size, share * 100.0),
+        None => size,
+    }
+}
+
+// Allocation granularity of common filesystems; smaller gaps are usually tail packing or
+// inline data rather than holes.
+const SPARSE_SLACK: u64 = 4096;
+
+/// A regular file with at least a block's worth of holes, such as a VM image.
+fn is_sparse(entry: &FileInfo) -> bool {
+    entry.file_type.is_file() && entry.disk_size + SPARSE_SLACK <= entry.size
+}
+
+/// Formats an entry's size for display: exact bytes with `-s`, human readable otherwise.
+fn format_entry_size(size: u64, config: &Config) -> String {
+    if config.raw_sizes {
+        size.to_string()
+    } else {
+        format_size(size)
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
+    let mut size = size as f64;
+    let mut unit_index = 0;
+
+    while size >= 1024.0 && unit_index < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit_index += 1;
+    }
+
+    format!("{:.2} {}", size, UNITS[unit_index])
+}
+
+/// The bytes of `path` as the OS has them, so names that are not UTF-8 survive.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn device_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Identifies the underlying file independently of the path used to reach it.
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Matches the kernel's SYMLOOP_MAX on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Follows a symlink one hop at a time, rendering e.g. ` -> b -> c (final)` and noting
+/// where resolution stops when a target is missing, unreadable or part of a loop.
+fn symlink_chain(path: &Path) -> String {
+    let mut chain = String::new();
+    let mut current = path.to_path_buf();
+    let mut seen = HashSet::from([current.clone()]);
+
+    for _ in 0..MAX_SYMLINK_HOPS {
+        let target = match fs::read_link(&current) {
+            Ok(target) => target,
+            Err(e) => return format!("{} (unreadable: {})", chain, e),
+        };
+        chain.push_str(&format!(" -> {}", target.display()));
+
+        let next = current.parent().unwrap_or(Path::new("")).join(&target);
+        match fs::symlink_metadata(&next) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                if !seen.insert(next.clone()) {
+                    return chain + " (loop)";
+                }
+                current = next;
+            },
+            Ok(_) => return chain + " (final)",
+            Err(_) => return chain + " (missing)",
+        }
+    }
+
+    chain + " (too many levels)"
+}
+
+fn mount_label(path: &Path, state: &WalkState) -> String {
+    let mount = path.canonicalize().ok().and_then(|path| state.mounts.get(&path));
+    match mount {
+        Some(mount) => format!("mounted: {} {}", mount.fs_type, mount.source),
+        None => "mounted".to_string(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_mount_table() -> HashMap<PathBuf, MountEntry> {
+    let mut mounts = HashMap::new();
+    if let Ok(contents) = fs::read_to_string("/proc/mounts") {
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() >= 3 {
+                // Later entries shadow earlier ones mounted on the same point.
+                mounts.insert(PathBuf::from(unescape_mount_field(fields[1])), MountEntry {
+                    source: unescape_mount_field(fields[0]),
+                    fs_type: fields[2].to_string(),
+                });
+            }
+        }
+    }
+    mounts
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_mount_table() -> HashMap<PathBuf, MountEntry> {
+    HashMap::new()
+}
+
+#[cfg(unix)]
+fn volume_space(path: &Path) -> Option<VolumeSpace> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stats` is a valid statvfs buffer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    let block_size = stats.f_frsize as u64;
+    let total = stats.f_blocks as u64 * block_size;
+    // Pseudo filesystems such as /proc report no blocks at all.
+    (total > 0).then(|| VolumeSpace {
+        total,
+        used: (stats.f_blocks as u64).saturating_sub(stats.f_bfree as u64) * block_size,
+        available: stats.f_bavail as u64 * block_size,
+    })
+}
+
+#[cfg(windows)]
+fn volume_space(path: &Path) -> Option<VolumeSpace> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(path: *const u16, available: *mut u64, total: *mut u64, free: *mut u64) -> i32;
+    }
+
+    let wide_path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let (mut available, mut total, mut free) = (0, 0, 0);
+    // SAFETY: the path is NUL-terminated and the out pointers are valid for the call.
+    if unsafe { GetDiskFreeSpaceExW(wide_path.as_ptr(), &mut available, &mut total, &mut free) } == 0 || total == 0 {
+        return None;
+    }
+    Some(VolumeSpace { total, used: total - free, available })
+}
+
+#[cfg(not(any(unix, windows)))]
+fn volume_space(_path: &Path) -> Option<VolumeSpace> {
+    None
+}
+
+/// The filesystem each entry resides on, e.g. `ext4 /dev/sda1`. Only directories can be
+/// mount points, so other entries share the lookup made for their parent.
+fn filesystem_column(entries: &[FileInfo]) -> Vec<String> {
+    let mut by_parent: HashMap<PathBuf, String> = HashMap::new();
+    entries.iter()
+        .map(|entry| {
+            let label = match entry.path.parent().filter(|_| !entry.is_dir) {
+                Some(parent) => by_parent.entry(parent.to_path_buf()).or_insert_with(|| filesystem_label(parent)).clone(),
+                None => filesystem_label(&entry.path),
+            };
+            if label.is_empty() { "?".to_string() } else { label }
+        })
+        .collect()
+}
+
+/// Finds the mount covering `path` in /proc/mounts. Unlike device numbers this also works
+/// for btrfs subvolumes, which get a device number of their own.
+#[cfg(target_os = "linux")]
+fn filesystem_label(path: &Path) -> String {
+    static MOUNTS: OnceLock<HashMap<PathBuf, MountEntry>> = OnceLock::new();
+    let mounts = MOUNTS.get_or_init(read_mount_table);
+    let Ok(path) = fs::canonicalize(if path.as_os_str().is_empty() { Path::new(".") } else { path }) else {
+        return String::new();
+    };
+    path.ancestors()
+        .find_map(|ancestor| mounts.get(ancestor))
+        .map(|mount| format!("{} {}", mount.fs_type, mount.source))
+        .unwrap_or_default()
+}
+
+#[cfg(target_os = "macos")]
+fn filesystem_label(path: &Path) -> String {
+    use std::ffi::CStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return String::new();
+    };
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stats` is a valid statfs buffer; on success
+    // the kernel fills both names with NUL-terminated strings.
+    unsafe {
+        if libc::statfs(c_path.as_ptr(), &mut stats) != 0 {
+            return String::new();
+        }
+        format!("{} {}",
+            CStr::from_ptr(stats.f_fstypename.as_ptr()).to_string_lossy(),
+            CStr::from_ptr(stats.f_mntfromname.as_ptr()).to_string_lossy())
+    }
+}
+
+/// The volume's filesystem and mount path, e.g. `NTFS C:\`.
+#[cfg(windows)]
+fn filesystem_label(path: &Path) -> String {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetVolumePathNameW(path: *const u16, volume: *mut u16, len: u32) -> i32;
+        fn GetVolumeInformationW(
+            root: *const u16, name: *mut u16, name_len: u32, serial: *mut u32, max_component: *mut u32,
+            flags: *mut u32, fs_name: *mut u16, fs_name_len: u32,
+        ) -> i32;
+    }
+
+    let wide_path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut volume = [0u16; 261];
+    let mut fs_name = [0u16; 261];
+    // SAFETY: the path is NUL-terminated and the buffer lengths passed match the buffers.
+    unsafe {
+        if GetVolumePathNameW(wide_path.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) == 0 {
+            return String::new();
+        }
+        if GetVolumeInformationW(volume.as_ptr(), ptr::null_mut(), 0, ptr::null_mut(), ptr::null_mut(),
+            ptr::null_mut(), fs_name.as_mut_ptr(), fs_name.len() as u32) == 0 {
+            return String::new();
+        }
+    }
+    let until_nul = |buffer: &[u16]| String::from_utf16_lossy(&buffer[..buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len())]);
+    format!("{} {}", until_nul(&fs_name), until_nul(&volume))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn filesystem_label(_path: &Path) -> String {
+    String::new()
+}
+
+/// Decodes the octal escapes (`\040` for a space) used in /proc/mounts fields.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            decoded.push((bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0'));
+            i += 4;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+/// Without Unix permission bits, approximate them from the read-only flag.
+#[cfg(not(unix))]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o666 }
+}
+
+/// Bytes allocated on disk.
+#[cfg(unix)]
+fn disk_usage(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn disk_usage(metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// A run of a file's data on disk. The physical offset is unknown for data that is inline,
+/// not yet allocated, or on a filesystem without a fixed location for it.
+struct Extent {
+    physical: Option<u64>,
+    length: u64,
+    shared: bool,
+}
+
+// Extents fetched per FIEMAP call.
+#[cfg(target_os = "linux")]
+const FIEMAP_BATCH: usize = 64;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// Reads a file's extents with the FIEMAP ioctl (btrfs, XFS, ext4, ...).
+#[cfg(target_os = "linux")]
+fn file_extents(path: &Path) -> io::Result<Vec<Extent>> {
+    use std::os::unix::io::AsRawFd;
+    // _IOWR('f', 11, struct fiemap) with the generic ioctl number layout.
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+    let file = fs::File::open(path)?;
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: 0,
+            mapped_extents: 0,
+            extent_count: FIEMAP_BATCH as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); FIEMAP_BATCH],
+        };
+        // SAFETY: `map` is a correctly laid out `struct fiemap` with room for `extent_count`
+        // extents, and it outlives the call.
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mapped = &map.extents[..map.mapped_extents as usize];
+        for extent in mapped {
+            let located = extent.flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) == 0;
+            extents.push(Extent {
+                physical: Some(extent.physical).filter(|_| located),
+                length: extent.length,
+                shared: extent.flags & FIEMAP_EXTENT_SHARED != 0,
+            });
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => start = last.logical + last.length,
+            _ => return Ok(extents),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn file_extents(_path: &Path) -> io::Result<Vec<Extent>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "extent maps are only read on Linux"))
+}
+
+/// The `f_type` magic number statfs reports for the filesystem holding `path`.
+#[cfg(target_os = "linux")]
+fn filesystem_magic(path: &Path) -> Option<i64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stats` is a valid statfs buffer.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    Some(stats.f_type as i64)
+}
+
+/// Whether `path` is on a kernel pseudo filesystem such as /proc or /sys, whose sizes are
+/// made up and where reading some files blocks.
+#[cfg(target_os = "linux")]
+fn is_virtual_fs(path: &Path) -> bool {
+    const VIRTUAL_FS_MAGICS: [i64; 12] = [
+        0x9fa0,      // proc
+        0x6265_6572, // sysfs
+        0x6462_6720, // debugfs
+        0x7472_6163, // tracefs
+        0x0027_e0eb, // cgroup
+        0x6367_7270, // cgroup2
+        0x7363_6673, // securityfs
+        0xcafe_4a11, // bpf
+        0x6165_676c, // pstore
+        0x6265_6570, // configfs
+        0xde5e_81e4, // efivarfs
+        0x1cd1,      // devpts
+    ];
+    filesystem_magic(path).is_some_and(|magic| VIRTUAL_FS_MAGICS.contains(&magic))
+}
+
+#[cfg(target_os = "macos")]
+fn is_virtual_fs(path: &Path) -> bool {
+    filesystem_label(path).starts_with("devfs ")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_virtual_fs(_path: &Path) -> bool {
+    false
+}
+
+/// Labels a directory that is the root of a btrfs subvolume, telling snapshots (which
+/// record the subvolume they were taken from) apart from plain subvolumes.
+#[cfg(target_os = "linux")]
+fn btrfs_subvolume(path: &Path, metadata: &fs::Metadata) -> Option<&'static str> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    // Every subvolume root has the first free object id as its inode number.
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+    const BTRFS_SUPER_MAGIC: i64 = 0x9123_683E;
+    // _IOR(BTRFS_IOCTL_MAGIC, 60, struct btrfs_ioctl_get_subvol_info_args)
+    const BTRFS_IOC_GET_SUBVOL_INFO: libc::c_ulong = 0x81F8_943C;
+
+    if metadata.ino() != BTRFS_FIRST_FREE_OBJECTID || filesystem_magic(path)? != BTRFS_SUPER_MAGIC {
+        return None;
+    }
+
+    #[repr(C)]
+    struct SubvolInfo {
+        tree_id: u64,
+        name: [u8; 256],
+        parent_id: u64,
+        dir_id: u64,
+        generation: u64,
+        flags: u64,
+        uuid: [u8; 16],
+        parent_uuid: [u8; 16],
+        received_uuid: [u8; 16],
+        transids: [u64; 4],
+        // ctime, otime, stime and rtime as (seconds, nanoseconds) pairs padded to 16 bytes.
+        times: [[u64; 2]; 4],
+        reserved: [u64; 8],
+    }
+    const _: () = assert!(std::mem::size_of::<SubvolInfo>() == 504);
+    let directory = fs::File::open(path).ok()?;
+    let mut info: SubvolInfo = unsafe { std::mem::zeroed() };
+    // SAFETY: `info` matches the kernel's 504-byte argument struct and outlives the call.
+    if unsafe { libc::ioctl(directory.as_raw_fd(), BTRFS_IOC_GET_SUBVOL_INFO as _, &mut info) } != 0 {
+        // Kernels before 4.18 lack the unprivileged ioctl; the inode number still tells.
+        return Some("btrfs subvolume");
+    }
+    Some(if info.parent_uuid.iter().any(|&byte| byte != 0) { "btrfs snapshot" } else { "btrfs subvolume" })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn btrfs_subvolume(_path: &Path, _metadata: &fs::Metadata) -> Option<&'static str> {
+    None
+}
+
+/// Whether the filesystem stores the file compressed: the `c` attribute on btrfs and
+/// ext4, `UF_COMPRESSED` on macOS, or the compressed attribute on NTFS.
+#[cfg(target_os = "linux")]
+fn is_compressed(path: &Path, _metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    const FS_COMPR_FL: libc::c_int = 0x4;
+
+    let file = match fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW).open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut flags: libc::c_int = 0;
+    // SAFETY: FS_IOC_GETFLAGS stores the inode flags as an int through the pointer, which
+    // outlives the call, and the descriptor stays open until `file` is dropped.
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    result == 0 && flags & FS_COMPR_FL != 0
+}
+
+#[cfg(target_os = "macos")]
+fn is_compressed(_path: &Path, metadata: &fs::Metadata) -> bool {
+    use std::os::macos::fs::MetadataExt;
+    const UF_COMPRESSED: u32 = 0x20;
+    metadata.st_flags() & UF_COMPRESSED != 0
+}
+
+#[cfg(windows)]
+fn is_compressed(_path: &Path, metadata: &fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_COMPRESSED: u32 = 0x800;
+    metadata.file_attributes() & FILE_ATTRIBUTE_COMPRESSED != 0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn is_compressed(_path: &Path, _metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn file_owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(target_os = "macos")]
+fn file_flags(metadata: &fs::Metadata) -> u32 {
+    use std::os::macos::fs::MetadataExt;
+    metadata.st_flags()
+}
+
+#[cfg(target_os = "freebsd")]
+fn file_flags(metadata: &fs::Metadata) -> u32 {
+    use std::os::freebsd::fs::MetadataExt;
+    metadata.st_flags()
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+fn file_flags(_metadata: &fs::Metadata) -> u32 {
+    0
+}
+
+#[cfg(not(unix))]
+fn file_owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// The owner shown in the long view: `user:group` on Unix, `DOMAIN\name` on Windows.
+#[cfg(unix)]
+fn owner_label(entry: &FileInfo) -> String {
+    match entry.owner {
+        Some((uid, gid)) => format!("{}:{}", user_name(uid), group_name(gid)),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(windows)]
+fn owner_label(entry: &FileInfo) -> String {
+    windows_owner(&entry.path).unwrap_or_else(|| "?".to_string())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn owner_label(_entry: &FileInfo) -> String {
+    "-".to_string()
+}
+
+#[cfg(windows)]
+#[link(name = "advapi32")]
+extern "system" {
+    fn GetNamedSecurityInfoW(
+        name: *const u16, object_type: u32, info: u32, owner: *mut *mut std::ffi::c_void,
+        group: *mut *mut std::ffi::c_void, dacl: *mut *mut std::ffi::c_void, sacl: *mut *mut std::ffi::c_void,
+        descriptor: *mut *mut std::ffi::c_void,
+    ) -> u32;
+    fn LookupAccountSidW(
+        system: *const u16, sid: *mut std::ffi::c_void, name: *mut u16, name_len: *mut u32,
+        domain: *mut u16, domain_len: *mut u32, sid_use: *mut u32,
+    ) -> i32;
+    fn ConvertSidToStringSidW(sid: *mut std::ffi::c_void, string: *mut *mut u16) -> i32;
+    fn GetAce(acl: *mut std::ffi::c_void, index: u32, ace: *mut *mut std::ffi::c_void) -> i32;
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn LocalFree(memory: *mut std::ffi::c_void) -> *mut std::ffi::c_void;
+}
+
+/// Reads parts of a file's security descriptor, returning it (to be freed with LocalFree)
+/// along with the owner and DACL pointers into it that were asked for.
+#[cfg(windows)]
+fn windows_security_info(path: &Path, info: u32) -> Option<(*mut std::ffi::c_void, *mut std::ffi::c_void, *mut std::ffi::c_void)> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    const SE_FILE_OBJECT: u32 = 1;
+
+    let wide_path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let (mut owner, mut dacl, mut descriptor) = (ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+    // SAFETY: the path is NUL-terminated and the out pointers are valid for the call.
+    let status = unsafe {
+        GetNamedSecurityInfoW(wide_path.as_ptr(), SE_FILE_OBJECT, info, &mut owner, ptr::null_mut(),
+            &mut dacl, ptr::null_mut(), &mut descriptor)
+    };
+    (status == 0).then_some((descriptor, owner, dacl))
+}
+
+/// Resolves a SID to an account name, falling back to the SID string (`S-1-5-...`) for
+/// accounts that no longer exist.
+///
+/// # Safety
+///
+/// `sid` must point to a valid SID.
+#[cfg(windows)]
+unsafe fn windows_account_name(sid: *mut std::ffi::c_void) -> String {
+    let mut name = [0u16; 256];
+    let mut domain = [0u16; 256];
+    let (mut name_len, mut domain_len, mut sid_use) = (name.len() as u32, domain.len() as u32, 0);
+    let resolved = LookupAccountSidW(std::ptr::null(), sid, name.as_mut_ptr(), &mut name_len,
+        domain.as_mut_ptr(), &mut domain_len, &mut sid_use) != 0;
+    if resolved {
+        let name = String::from_utf16_lossy(&name[..name_len as usize]);
+        let domain = String::from_utf16_lossy(&domain[..domain_len as usize]);
+        return if domain.is_empty() { name } else { format!("{}\\{}", domain, name) };
+    }
+
+    let mut sid_string: *mut u16 = std::ptr::null_mut();
+    if ConvertSidToStringSidW(sid, &mut sid_string) == 0 {
+        return "?".to_string();
+    }
+    let len = (0..).take_while(|&i| *sid_string.add(i) != 0).count();
+    let label = String::from_utf16_lossy(std::slice::from_raw_parts(sid_string, len));
+    LocalFree(sid_string.cast());
+    label
+}
+
+/// The account that owns a file, from its security descriptor.
+#[cfg(windows)]
+fn windows_owner(path: &Path) -> Option<String> {
+    const OWNER_SECURITY_INFORMATION: u32 = 0x1;
+    let (descriptor, owner, _) = windows_security_info(path, OWNER_SECURITY_INFORMATION)?;
+    // SAFETY: `owner` points into the descriptor, which is freed only once it is resolved.
+    unsafe {
+        let name = windows_account_name(owner);
+        LocalFree(descriptor);
+        Some(name)
+    }
+}
+
+/// The entries of a file's ACL beyond what its permission bits show, or `None` when it has
+/// none. On Linux these are the named users and groups and the mask of a POSIX ACL, plus
+/// a directory's default ACL; on Windows the entries set on the file rather than inherited.
+#[cfg(target_os = "linux")]
+fn extended_acl(path: &Path) -> Option<Vec<String>> {
+    // The kernel drops the access ACL attribute whenever it is equivalent to the mode bits.
+    let access = posix_acl_entries(path, "system.posix_acl_access");
+    let default = posix_acl_entries(path, "system.posix_acl_default");
+    if access.is_none() && default.is_none() {
+        return None;
+    }
+
+    let mut summary: Vec<String> = access.unwrap_or_default().into_iter()
+        .filter(|(tag, _)| !matches!(tag.as_str(), "user::" | "group::" | "other::"))
+        .map(|(tag, perms)| format!("{}{}", tag, perms))
+        .collect();
+    summary.extend(default.unwrap_or_default().into_iter().map(|(tag, perms)| format!("default:{}{}", tag, perms)));
+    Some(summary)
+}
+
+/// Reads an extended attribute of `path` itself (not a symlink's target).
+#[cfg(target_os = "linux")]
+fn read_xattr(path: &Path, attribute: &str) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let c_attribute = std::ffi::CString::new(attribute).ok()?;
+    let mut value = vec![0u8; 512];
+    // SAFETY: both strings are NUL-terminated and the buffer length passed matches `value`.
+    let len = unsafe {
+        libc::lgetxattr(c_path.as_ptr(), c_attribute.as_ptr(), value.as_mut_ptr().cast(), value.len())
+    };
+    if len >= 0 {
+        value.truncate(len as usize);
+        return Some(value);
+    }
+    if io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+        return None;
+    }
+
+    // Too big for the buffer; ask for the size and read again.
+    // SAFETY: as above, a null buffer of length zero only queries the size.
+    let needed = unsafe { libc::lgetxattr(c_path.as_ptr(), c_attribute.as_ptr(), std::ptr::null_mut(), 0) };
+    value.resize(usize::try_from(needed).ok()?, 0);
+    // SAFETY: as above.
+    let len = unsafe {
+        libc::lgetxattr(c_path.as_ptr(), c_attribute.as_ptr(), value.as_mut_ptr().cast(), value.len())
+    };
+    value.truncate(usize::try_from(len).ok()?);
+    Some(value)
+}
+
+// Capability names by bit number, as listed in capabilities(7).
+#[cfg(target_os = "linux")]
+const CAPABILITY_NAMES: [&str; 41] = [
+    "chown", "dac_override", "dac_read_search", "fowner", "fsetid", "kill", "setgid", "setuid",
+    "setpcap", "linux_immutable", "net_bind_service", "net_broadcast", "net_admin", "net_raw",
+    "ipc_lock", "ipc_owner", "sys_module", "sys_rawio", "sys_chroot", "sys_ptrace", "sys_pacct",
+    "sys_admin", "sys_boot", "sys_nice", "sys_resource", "sys_time", "sys_tty_config", "mknod",
+    "lease", "audit_write", "audit_control", "setfcap", "mac_override", "mac_admin", "syslog",
+    "wake_alarm", "block_suspend", "audit_read", "perfmon", "bpf", "checkpoint_restore",
+];
+
+/// Renders the file capabilities attached to a binary the way `getcap` did before libcap
+/// 2.30, e.g. `cap_net_bind_service+ep` or `cap_net_admin,cap_net_raw+eip`.
+#[cfg(target_os = "linux")]
+fn file_capabilities(path: &Path) -> Option<String> {
+    const VFS_CAP_REVISION_MASK: u32 = 0xFF00_0000;
+    const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+    const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x1;
+
+    // struct vfs_cap_data: a revision and flags word, then (permitted, inheritable) pairs
+    // holding the low and, from revision 2 on, high 32 capability bits.
+    let value = read_xattr(path, "security.capability")?;
+    let words: Vec<u32> = value.chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    let magic = *words.first()?;
+    let pairs = if magic & VFS_CAP_REVISION_MASK == VFS_CAP_REVISION_1 { 1 } else { 2 };
+    let (mut permitted, mut inheritable) = (0u64, 0u64);
+    for pair in 0..pairs {
+        permitted |= u64::from(*words.get(1 + 2 * pair)?) << (32 * pair);
+        inheritable |= u64::from(*words.get(2 + 2 * pair)?) << (32 * pair);
+    }
+    let effective = magic & VFS_CAP_FLAGS_EFFECTIVE != 0;
+
+    // Capabilities with the same sets are listed together.
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for bit in 0..64 {
+        let (in_permitted, in_inheritable) = (permitted >> bit & 1 != 0, inheritable >> bit & 1 != 0);
+        if !in_permitted && !in_inheritable {
+            continue;
+        }
+        let sets: String = [(effective && in_permitted, 'e'), (in_inheritable, 'i'), (in_permitted, 'p')].iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, c)| *c)
+            .collect();
+        let name = CAPABILITY_NAMES.get(bit).map(|name| format!("cap_{}", name)).unwrap_or_else(|| bit.to_string());
+        match groups.iter_mut().find(|(group_sets, _)| *group_sets == sets) {
+            Some((_, names)) => names.push(name),
+            None => groups.push((sets, vec![name])),
+        }
+    }
+    let groups: Vec<String> = groups.into_iter().map(|(sets, names)| format!("{}+{}", names.join(","), sets)).collect();
+    (!groups.is_empty()).then(|| groups.join(" "))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn file_capabilities(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Decodes a POSIX ACL extended attribute into `setfacl`-style `(user:alice:, rw-)` pairs.
+#[cfg(target_os = "linux")]
+fn posix_acl_entries(path: &Path, attribute: &str) -> Option<Vec<(String, String)>> {
+    const ACL_USER_OBJ: u16 = 0x01;
+    const ACL_USER: u16 = 0x02;
+    const ACL_GROUP_OBJ: u16 = 0x04;
+    const ACL_GROUP: u16 = 0x08;
+    const ACL_MASK: u16 = 0x10;
+    const ACL_OTHER: u16 = 0x20;
+
+    let value = read_xattr(path, attribute)?;
+    // A 4-byte version header, then 8-byte entries of tag, permissions and id.
+    let entries = value.get(4..)?.chunks_exact(8).map(|entry| {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u16::from_le_bytes([entry[2], entry[3]]);
+        let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+        let tag = match tag {
+            ACL_USER_OBJ => "user::".to_string(),
+            ACL_USER => format!("user:{}:", user_name(id)),
+            ACL_GROUP_OBJ => "group::".to_string(),
+            ACL_GROUP => format!("group:{}:", group_name(id)),
+            ACL_MASK => "mask::".to_string(),
+            ACL_OTHER => "other::".to_string(),
+            _ => format!("{:#x}::", tag),
+        };
+        let perms: String = [(4, 'r'), (2, 'w'), (1, 'x')].iter()
+            .map(|&(bit, c)| if perm & bit != 0 { c } else { '-' })
+            .collect();
+        (tag, perms)
+    });
+    Some(entries.collect())
+}
+
+#[cfg(windows)]
+fn extended_acl(path: &Path) -> Option<Vec<String>> {
+    const DACL_SECURITY_INFORMATION: u32 = 0x4;
+    const ACCESS_ALLOWED_ACE_TYPE: u8 = 0;
+    const ACCESS_DENIED_ACE_TYPE: u8 = 1;
+    const INHERITED_ACE: u8 = 0x10;
+
+    let (descriptor, _, dacl) = windows_security_info(path, DACL_SECURITY_INFORMATION)?;
+    let mut entries = Vec::new();
+    // SAFETY: `dacl` points into the descriptor, which is freed only after the loop. An ACL
+    // starts with a revision byte, a padding byte and its size and entry count as u16s; each
+    // ACE starts with its type, flags and size, then the access mask and SID.
+    unsafe {
+        let ace_count = if dacl.is_null() { 0 } else { *dacl.cast::<u16>().add(2) };
+        for index in 0..u32::from(ace_count) {
+            let mut ace = std::ptr::null_mut();
+            if GetAce(dacl, index, &mut ace) == 0 {
+                continue;
+            }
+            let ace_type = *ace.cast::<u8>();
+            let ace_flags = *ace.cast::<u8>().add(1);
+            if ace_flags & INHERITED_ACE != 0 {
+                continue;
+            }
+            let kind = match ace_type {
+                ACCESS_ALLOWED_ACE_TYPE => "allow",
+                ACCESS_DENIED_ACE_TYPE => "deny",
+                _ => continue,
+            };
+            let mask = *ace.cast::<u8>().add(4).cast::<u32>();
+            let account = windows_account_name(ace.cast::<u8>().add(8).cast());
+            entries.push(format!("{} {}:{}", kind, account, windows_access_label(mask)));
+        }
+        LocalFree(descriptor);
+    }
+    (!entries.is_empty()).then_some(entries)
+}
+
+/// Names an access mask the way `icacls` does for the common combinations.
+#[cfg(windows)]
+fn windows_access_label(mask: u32) -> String {
+    match mask {
+        0x001F_01FF => "F".to_string(),
+        0x0013_01BF => "M".to_string(),
+        0x0012_00A9 => "RX".to_string(),
+        0x0012_0089 => "R".to_string(),
+        0x0010_0116 => "W".to_string(),
+        _ => format!("{:#x}", mask),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn extended_acl(_path: &Path) -> Option<Vec<String>> {
+    None
+}
+
+/// The user name for a uid, or the uid itself when it has no entry.
+fn user_name(uid: u32) -> String {
+    static USERS: OnceLock<HashMap<u32, String>> = OnceLock::new();
+    USERS.get_or_init(|| read_id_names("/etc/passwd"))
+        .get(&uid).cloned().unwrap_or_else(|| uid.to_string())
+}
+
+/// The group name for a gid, or the gid itself when it has no entry.
+fn group_name(gid: u32) -> String {
+    static GROUPS: OnceLock<HashMap<u32, String>> = OnceLock::new();
+    GROUPS.get_or_init(|| read_id_names("/etc/group"))
+        .get(&gid).cloned().unwrap_or_else(|| gid.to_string())
+}
+
+/// The uid of a user name, for `--user`; numbers are taken as uids.
+fn user_id(user: &str) -> Result<u32, Box<dyn Error>> {
+    user.parse().ok()
+        .or_else(|| read_id_names("/etc/passwd").into_iter().find(|(_, name)| name == user).map(|(uid, _)| uid))
+        .ok_or_else(|| format!("Unknown user '{}' for --user", user).into())
+}
+
+/// The gid of a group name, for `--group`; numbers are taken as gids.
+fn group_id(group: &str) -> Result<u32, Box<dyn Error>> {
+    group.parse().ok()
+        .or_else(|| read_id_names("/etc/group").into_iter().find(|(_, name)| name == group).map(|(gid, _)| gid))
+        .ok_or_else(|| format!("Unknown group '{}' for --group", group).into())
+}
+
+/// Reads the `name:password:id:...` lines of /etc/passwd or /etc/group.
+fn read_id_names(path: &str) -> HashMap<u32, String> {
+    fs::read_to_string(path).unwrap_or_default().lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+#[allow(dead_code)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = fs::metadata(path) {
+        return metadata.permissions().mode() & 0o111 != 0;
+    }
+    false
+}
+
+#[cfg(not(unix))]
+#[allow(dead_code)]
+fn is_executable(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_hex(chunks: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finish().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn parse_duration_rejects_out_of_range_values() {
+        assert_eq!(parse_duration("90m").unwrap(), std::time::Duration::from_secs(5400));
+        assert_eq!(parse_duration("999999999999999999999999y").unwrap_err().to_string(), "Invalid duration '999999999999999999999999y'");
+        assert!(parse_duration("x").is_err());
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("tree").chain(list.iter().copied()).map(String::from).collect()
+    }
+
+    #[test]
+    fn time_filters_reject_huge_amounts() {
+        for flag in ["--older-than", "--newer-than"] {
+            let error = parse_args(&args(&[flag, "99999999999999999999999y"])).unwrap_err();
+            assert!(error.to_string().starts_with("Invalid time"), "{}", error);
+        }
+        assert!(parse_args(&args(&["--changed-within", "99999999999999999999999y"])).is_err());
+        assert!(parse_args(&args(&["--mtime", "+9999999999999999999999"])).is_err());
+        assert!(parse_args(&args(&["--mtime", "18446744073709551615s"])).is_err());
+    }
+
+    #[test]
+    fn parse_time_point_accepts_dates_and_durations() {
+        let week_ago = parse_time_point("1w").unwrap();
+        let elapsed = SystemTime::now().duration_since(week_ago).unwrap().as_secs();
+        assert!((7 * 24 * 3600..7 * 24 * 3600 + 60).contains(&elapsed));
+        assert_eq!(parse_time_point("2024-01-31").unwrap(), parse_date("2024-01-31").unwrap());
+        assert!(parse_time_point("2w3").is_err());
+    }
+
+    fn layered(file: &str, env: &[(&str, &str)], command_line: &[&str]) -> Result<Config, Box<dyn Error>> {
+        let config_file = parse_config_file(file)?;
+        let env_options = env_args(|variable| {
+            env.iter().find(|(name, _)| *name == variable).map(|(_, value)| value.to_string())
+        })?;
+        parse_args(&layered_args(&config_file, env_options, &args(command_line))?)
+    }
+
+    #[test]
+    fn command_line_beats_environment_beats_config_file() {
+        let file = "level = 1\n";
+        let env = [("RTREE_DEFAULT_DEPTH", "2")];
+        assert_eq!(layered(file, &[], &[]).unwrap().max_depth, Some(1));
+        assert_eq!(layered(file, &env, &[]).unwrap().max_depth, Some(2));
+        assert_eq!(layered(file, &env, &["-L", "3"]).unwrap().max_depth, Some(3));
+        assert_eq!(layered("", &env, &["--level=3"]).unwrap().max_depth, Some(3));
+    }
+
+    #[test]
+    fn profiles_apply_where_they_are_selected() {
+        let file = "level = 1\n[profile.deep]\nlevel = 4\n[profile.audit]\nprofile = \"deep\"\nall = true\n";
+        let config = layered(file, &[("RTREE_DEFAULT_DEPTH", "2")], &["--profile", "audit"]).unwrap();
+        // `audit` extends `deep`, and comes from the command line, so it beats the environment.
+        assert_eq!(config.max_depth, Some(4));
+        assert!(config.show_hidden);
+        // Options after the profile still override it.
+        assert_eq!(layered(file, &[], &["--profile=audit", "-L", "2"]).unwrap().max_depth, Some(2));
+    }
+
+    #[test]
+    fn profile_cycles_are_rejected() {
+        let file = "[profile.a]\nprofile = \"b\"\n[profile.b]\nprofile = \"a\"\n";
+        let error = layered(file, &[], &["--profile", "a"]).unwrap_err();
+        assert_eq!(error.to_string(), "Profile 'a' selects itself: a -> b -> a");
+
+        let profiles = parse_config_file(file).unwrap().profiles;
+        let mut active = vec!["b".to_string()];
+        assert!(expand_profiles(&args(&["--profile", "b"])[1..], &profiles, &mut active).is_err());
+        assert!(expand_profiles(&args(&["--profile", "nope"])[1..], &profiles, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn invalid_layers_name_their_source() {
+        let error = layered("", &[("RTREE_COLORS", "sometimes")], &[]).unwrap_err();
+        assert!(error.to_string().starts_with("RTREE_COLORS: "), "{}", error);
+        let error = parse_config_file("level = \"x\"\n").err().unwrap();
+        assert!(error.starts_with("line 1: "), "{}", error);
+        // Checking these would run them.
+        for key in ["help", "version", "generate-man", "generate_man", "no-config"] {
+            let error = parse_config_file(&format!("{} = true\n", key)).err().unwrap();
+            assert!(error.ends_with("cannot be set in the config file"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn json_strings_unescape() {
+        let value = parse_json(r#""a\"b\\c\/d\n\t\u00e9\u20ac""#).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c/d\n\t\u{e9}\u{20ac}"));
+        assert!(parse_json(r#""\u12""#).is_err());
+        assert!(parse_json(r#""\uzzzz""#).is_err());
+        assert_eq!(parse_json(r#""\ud83d\uDE00!""#).unwrap().as_str(), Some("\u{1f600}!"));
+        assert!(parse_json(r#""\ud83d""#).is_err());
+        assert!(parse_json(r#""\ud83d\u0041""#).is_err());
+        assert!(parse_json(r#""\ude00""#).is_err());
+        assert!(parse_json(r#""open"#).is_err());
+    }
+
+    #[test]
+    fn json_arrays_and_objects_nest() {
+        let value = parse_json(r#" {"a": [1, [2, [true, null]], {"b": []}], "c": {}} "#).unwrap();
+        let JsonValue::Array(items) = value.get("a").unwrap() else { panic!("{:?}", value) };
+        assert_eq!(items.len(), 3);
+        let JsonValue::Array(inner) = &items[1] else { panic!("{:?}", items[1]) };
+        assert_eq!(inner[0].as_u64(), Some(2));
+        assert!(matches!(&inner[1], JsonValue::Array(deepest) if deepest[0].as_bool() == Some(true) && matches!(deepest[1], JsonValue::Null)));
+        assert!(matches!(items[2].get("b"), Some(JsonValue::Array(empty)) if empty.is_empty()));
+        assert!(matches!(value.get("c"), Some(JsonValue::Object(members)) if members.is_empty()));
+        assert!(value.get("missing").is_none());
+    }
+
+    #[test]
+    fn json_numbers_parse() {
+        assert_eq!(parse_json("0").unwrap().as_u64(), Some(0));
+        assert_eq!(parse_json("1700000000").unwrap().as_u64(), Some(1_700_000_000));
+        assert!(matches!(parse_json("-2.5e3").unwrap(), JsonValue::Number(number) if number == -2500.0));
+        assert_eq!(parse_json("-1").unwrap().as_u64(), None);
+        assert!(parse_json("1.2.3").is_err());
+        assert!(parse_json("-").is_err());
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        for text in ["", "{", "[1,", "[1 2]", "{\"a\" 1}", "{\"a\":1,}", "{a:1}", "tru", "nul", "[] []", "@"] {
+            assert!(parse_json(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn huge_json_mtimes_do_not_panic() {
+        let config = parse_args(&args(&[])).unwrap();
+        for mtime in ["1e300", "-5", "1.5"] {
+            let value = parse_json(&format!(r#"{{"name":"a","type":"file","mtime":{}}}"#, mtime)).unwrap();
+            json_tree_node(&value, None, 1, &config).unwrap();
+        }
+    }
+
+    #[test]
+    fn json_round_trip_keeps_the_summary() {
+        let dir = env::temp_dir().join(format!("rtree-json-round-trip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink("sub/file.txt", dir.join("good")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("dangling")).unwrap();
+
+        let root_path = dir.to_string_lossy().into_owned();
+        let config = parse_args(&args(&[&root_path])).unwrap();
+        let (scanned, _, _) = build_tree(&dir, &config).unwrap();
+        let mut json = String::new();
+        write_json_node(&scanned, &root_path, 1, &mut json);
+        let saved = dir.with_extension("json");
+        fs::write(&saved, format!("{{\"root\": {}}}", json)).unwrap();
+
+        let config = parse_args(&args(&["--from-json", &saved.to_string_lossy()])).unwrap();
+        let loaded = load_json_tree(&saved.to_string_lossy(), &config).unwrap();
+        let (mut before, mut after) = (TreeStats::default(), TreeStats::default());
+        tally_tree(&scanned, &mut before);
+        tally_tree(&loaded, &mut after);
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&saved);
+
+        assert_eq!(summary_fields(&after), summary_fields(&before));
+        assert_eq!(after.broken_links, 1);
+        assert_eq!(kind_breakdown(&after), kind_breakdown(&before));
+    }
+
+    #[test]
+    fn flat_json_is_filtered_and_sorted_on_load() {
+        let saved = env::temp_dir().join(format!("rtree-flat-json-{}.json", std::process::id()));
+        fs::write(&saved, r#"{"entries": [
+            {"name":"a","path":"fx/a","type":"directory","depth":1},
+            {"name":"b","path":"fx/a/b","type":"directory","depth":2},
+            {"name":"x.log","path":"fx/a/b/x.log","type":"file","depth":3},
+            {"name":"y.txt","path":"fx/a/y.txt","type":"file","depth":2},
+            {"name":"c","path":"fx/c","type":"directory","depth":1},
+            {"name":"z.log","path":"fx/c/z.log","type":"file","depth":2}
+        ]}"#).unwrap();
+        fn outline(nodes: &[TreeNode]) -> String {
+            nodes.iter().map(|node| match node.children.is_empty() {
+                true => file_name_lossy(&node.info),
+                false => format!("{}({})", file_name_lossy(&node.info), outline(&node.children)),
+            }).collect::<Vec<_>>().join(" ")
+        }
+        let load = |options: &[&str]| {
+            let mut list = vec!["--from-json", saved.to_str().unwrap()];
+            list.extend(options);
+            let config = parse_args(&args(&list)).unwrap();
+            let root = load_json_tree(&saved.to_string_lossy(), &config).unwrap();
+            (root.info.path.to_string_lossy().into_owned(), outline(&root.children))
+        };
+
+        assert_eq!(load(&[]), ("fx".to_string(), "a(b(x.log) y.txt) c(z.log)".to_string()));
+        assert_eq!(load(&["-L", "1", "-P", "*.log"]).1, "a c");
+        assert_eq!(load(&["-P", "*.log", "-r"]).1, "c(z.log) a(b(x.log))");
+        assert_eq!(load(&["-I", "b"]).1, "a(y.txt) c(z.log)");
+        let _ = fs::remove_file(&saved);
+    }
+
+    fn flat_stats(options: &[&str]) -> (usize, usize, usize) {
+        let dir = env::temp_dir().join(format!("rtree-flat-stats-{}-{}", std::process::id(), options.join("_").replace(['-', '.'], "")));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::write(dir.join("top.txt"), "").unwrap();
+        fs::write(dir.join("a/b/deep.txt"), "").unwrap();
+
+        let root_path = dir.to_string_lossy().into_owned();
+        let mut list = options.to_vec();
+        list.push(&root_path);
+        let config = parse_args(&args(&list)).unwrap();
+        let (mut root, _, _) = build_tree(&dir, &config).unwrap();
+        prune_unmatched(&mut root.children, &dir, &config);
+        let stats = listing_stats(&root, &config);
+        let listed = flatten_root(root, &config).len();
+        let _ = fs::remove_dir_all(&dir);
+        (listed, stats.directories, stats.files)
+    }
+
+    #[test]
+    fn flat_summaries_count_the_listed_entries() {
+        // The root, then a, a/b, a/b/c and d.
+        assert_eq!(flat_stats(&["--flat"]), (6, 5, 2));
+        assert_eq!(flat_stats(&["--flat", "--min-depth", "3"]), (2, 2, 1));
+        assert_eq!(flat_stats(&["--flat", "--depth", "2..3"]), (3, 3, 1));
+        assert_eq!(flat_stats(&["--flat", "--type", "d", "--min-depth", "2"]), (2, 3, 0));
+        // Tree mode still counts the directories that place the matches.
+        assert_eq!(flat_stats(&["--min-depth", "3"]).1, 4);
+    }
+
+    #[test]
+    fn du_sizes_count_below_the_depth_limit() {
+        let dir = env::temp_dir().join(format!("rtree-du-depth-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/deep.txt"), "0123456789").unwrap();
+        fs::write(dir.join("a/top.txt"), "01234").unwrap();
+
+        let root_path = dir.to_string_lossy().into_owned();
+        // The size of `a` and how many entries are left below it.
+        let listed = |options: &[&str]| {
+            let mut list = vec!["--du", "-s"];
+            list.extend(options);
+            list.push(&root_path);
+            let config = parse_args(&args(&list)).unwrap();
+            let (mut root, _, _) = build_tree(&dir, &config).unwrap();
+            aggregate_sizes(&mut root, &config);
+            if let Some(max_depth) = config.max_depth {
+                cut_to_depth(&mut root.children, max_depth);
+            }
+            (root.children[0].info.size, tree_entries(&root.children[0].children).len())
+        };
+        let (full, entries) = listed(&[]);
+        assert_eq!(entries, 3);
+        assert_eq!(listed(&["-L", "1"]), (full, 0));
+        assert_eq!(listed(&["-L", "2"]), (full, 2));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn du_format_totals_ignore_the_depth_limit() {
+        let dir = env::temp_dir().join(format!("rtree-du-format-depth-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/deep.txt"), "0123456789").unwrap();
+
+        let root_path = dir.to_string_lossy().into_owned();
+        let lines = |options: &[&str]| {
+            let mut list = vec!["--du-format", "-s"];
+            list.extend(options);
+            list.push(&root_path);
+            let config = parse_args(&args(&list)).unwrap();
+            let (root, _, _) = build_tree(&dir, &config).unwrap();
+            let mut out = Vec::new();
+            print_du_format(&root, &config, &mut out).unwrap();
+            String::from_utf8(out).unwrap().lines().map(str::to_string).collect::<Vec<_>>()
+        };
+        let (full, limited) = (lines(&[]), lines(&["-L", "1"]));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(full.len(), 4);
+        // The lines for a and the root, with the same totals.
+        assert_eq!(limited, full[2..]);
+    }
+
+    #[test]
+    fn json_compat_tree_matches_gnu_tree() {
+        let dir = env::temp_dir().join(format!("rtree-json-compat-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file.txt"), "hello").unwrap();
+        fs::write(dir.join("top.txt"), "").unwrap();
+
+        let root_path = dir.to_string_lossy().into_owned();
+        let document = |options: &[&str]| {
+            let mut list = vec!["--json-compat", "tree"];
+            list.extend(options);
+            list.push(&root_path);
+            let config = parse_args(&args(&list)).unwrap();
+            let (mut root, _, _) = build_tree(&dir, &config).unwrap();
+            if config.dirs_only {
+                keep_dirs(&mut root.children);
+            }
+            let mut out = Vec::new();
+            print_tree_json(&root, &listing_stats(&root, &config), &config, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let (listing, dirs_only) = (document(&[]), document(&["-d"]));
+        let _ = fs::remove_dir_all(&dir);
+
+        let name = json_string(&root_path);
+        assert_eq!(listing, format!(concat!(
+            "[\n",
+            "  {{\"type\":\"directory\",\"name\":{},\"contents\":[\n",
+            "    {{\"type\":\"directory\",\"name\":\"sub\",\"contents\":[\n",
+            "      {{\"type\":\"file\",\"name\":\"file.txt\"}}\n",
+            "    ]}},\n",
+            "    {{\"type\":\"file\",\"name\":\"top.txt\"}}\n",
+            "  ]}}\n",
+            ",\n",
+            "  {{\"type\":\"report\",\"directories\":1,\"files\":2}}\n",
+            "]\n",
+        ), name));
+        assert_eq!(dirs_only, format!(concat!(
+            "[\n",
+            "  {{\"type\":\"directory\",\"name\":{},\"contents\":[\n",
+            "    {{\"type\":\"directory\",\"name\":\"sub\",\"contents\":[\n",
+            "    ]}}\n",
+            "  ]}}\n",
+            ",\n",
+            "  {{\"type\":\"report\",\"directories\":1}}\n",
+            "]\n",
+        ), name));
+    }
+
+    #[test]
+    fn capabilities_are_highlighted() {
+        let mut info = stored_file_info(PathBuf::from("ping"), FileKind::File, 0, SystemTime::UNIX_EPOCH, 0o755);
+        assert_eq!(EntryClass::of(&info), EntryClass::Executable);
+        info.has_capabilities = true;
+        assert_eq!(EntryClass::of(&info), EntryClass::Capability);
+        assert_ne!(EntryClass::Capability.color(), EntryClass::Executable.color());
+    }
+
+    /// A `--where` expression with its grouping spelled out.
+    fn query_shape(query: &str) -> String {
+        fn shape(expr: &Expr) -> String {
+            match expr {
+                Expr::And(left, right) => format!("({} && {})", shape(left), shape(right)),
+                Expr::Or(left, right) => format!("({} || {})", shape(left), shape(right)),
+                Expr::Not(expr) => format!("!{}", shape(expr)),
+                Expr::Number { field, op, value } => format!("{:?} {:?} {}", field, op, value),
+                Expr::Text { field, op, value } => format!("{:?} {:?} {:?}", field, op, value),
+                Expr::Matches { field, regex, negate } => format!("{:?} {} {:?}", field, if *negate { "!~" } else { "~" }, regex.as_str()),
+                Expr::Time { op, .. } => format!("Mtime {:?}", op),
+            }
+        }
+        shape(&parse_query(query).unwrap())
+    }
+
+    #[test]
+    fn queries_parse_with_and_above_or() {
+        for (query, shape) in [
+            ("depth == 1 || depth == 2 && depth == 3", "(Depth Eq 1 || (Depth Eq 2 && Depth Eq 3))"),
+            ("depth == 1 && depth == 2 || depth == 3", "((Depth Eq 1 && Depth Eq 2) || Depth Eq 3)"),
+            ("(depth == 1 || depth == 2) && depth == 3", "((Depth Eq 1 || Depth Eq 2) && Depth Eq 3)"),
+            ("depth<1||depth>=2", "(Depth Lt 1 || Depth Ge 2)"),
+            ("!depth <= 2 && size > 10", "(!Depth Le 2 && Size Gt 10)"),
+            ("!(depth <= 2 && size > 10)", "!(Depth Le 2 && Size Gt 10)"),
+            ("!!ext != log", "!!Ext Ne \"log\""),
+            (r#"name == "a && b""#, r#"Name Eq "a && b""#),
+            ("path == 'it\"s'", r#"Path Eq "it\"s""#),
+            (r#"name ~ "^a" || path !~ '\.log$'"#, r#"(Name ~ "^a" || Path !~ "\\.log$")"#),
+            (r#"type == dir && mtime > "2024-01-01""#, "(Type Eq \"dir\" && Mtime Gt)"),
+        ] {
+            assert_eq!(query_shape(query), shape, "{}", query);
+        }
+    }
+
+    #[test]
+    fn query_errors_say_what_is_wrong() {
+        for (query, error) in [
+            ("", "Incomplete --where expression"),
+            ("size", "Expected a comparison after 'size' in --where"),
+            ("size >", "Expected a value after 'size >' in --where"),
+            ("size ! 1", "'!' is not a comparison operator"),
+            ("&& size > 1", "Expected a field name in --where, found Op(\"&&\")"),
+            ("size > 1 size > 2", "Unexpected Word(\"size\") in --where expression"),
+            ("(size > 1", "Missing ')' in --where expression"),
+            ("size > 1)", "Unexpected RParen in --where expression"),
+            ("size $ 1", "Unexpected character '$' in --where expression"),
+            (r#"name == "open"#, r#"Unterminated string in --where: "open"#),
+            ("owner == me", "Unknown field 'owner' in --where (expected size, depth, age, mtime, name, path, ext or type)"),
+            ("name < a", "Text fields can only be compared with ==, !=, ~ and !~"),
+            ("depth == x", "Invalid depth 'x'"),
+        ] {
+            assert_eq!(parse_query(query).err().map(|e| e.to_string()).as_deref(), Some(error), "{}", query);
+        }
+    }
+
+    #[test]
+    fn completions_needs_a_known_shell() {
+        assert!(completion_script(&args(&["completions", "fish"])).is_some_and(|script| script.contains("complete -c tree")));
+        for list in [&["completions"][..], &["completions", "-L", "1"], &["completions", "sh"], &["-a", "completions", "bash"]] {
+            assert!(completion_script(&args(list)).is_none(), "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn every_long_switch_can_be_turned_off() {
+        // The default mode, and the option main reads before anything else.
+        let exempt = ["--tree", "--no-config"];
+        for option in documented_options().filter(|option| matches!(option.action, OptionAction::Switch(_))) {
+            for name in option.spellings().filter(|name| name.starts_with("--") && !exempt.contains(name)) {
+                let negated = match name.strip_prefix("--no-") {
+                    Some(rest) => format!("--{}", rest),
+                    None => format!("--no-{}", name.trim_start_matches("--")),
+                };
+                assert!(parse_args(&args(&[name, &negated])).is_ok(), "{} cannot be undone with {}", name, negated);
+            }
+        }
+
+        assert!(parse_args(&args(&["--json", "--no-json"])).unwrap().json.is_none());
+        assert!(parse_args(&args(&["--only-empty", "--no-only-empty"])).unwrap().types.is_empty());
+        assert!(matches!(parse_args(&args(&["--flat", "--no-flat"])).unwrap().display_mode, DisplayMode::Tree));
+        assert!(matches!(parse_args(&args(&["--long", "--no-grid"])).unwrap().display_mode, DisplayMode::Long));
+    }
+
+    #[test]
+    fn follow_is_long_only() {
+        assert!(parse_args(&args(&["--follow"])).unwrap().follow);
+        let config = parse_args(&args(&["-L", "2"])).unwrap();
+        assert_eq!(config.max_depth, Some(2));
+        assert!(!config.follow);
+        assert!(README.contains("\n- `--follow`: ") && !README.contains("-L, --follow"));
+    }
+
+    #[test]
+    fn unreadable_entries_are_counted_and_skipped() {
+        let dir = env::temp_dir().join(format!("rtree-unreadable-entries-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file.txt"), "").unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("dangling")).unwrap();
+
+        // --dereference reads the dangling link's target, which fails.
+        let root_path = dir.to_string_lossy().into_owned();
+        let config = parse_args(&args(&["--dereference", &root_path])).unwrap();
+        let (root, _, unreadable) = build_tree(&dir, &config).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(unreadable, 1);
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(file_name_lossy(&root.children[0].info), "file.txt");
+    }
+
+    #[test]
+    fn readme_lists_the_option_table() {
+        let section = README.split("\n## Options\n").nth(1).and_then(|rest| rest.split("\n## ").next()).unwrap();
+        assert_eq!(section, options_markdown(), "docs/README.md's Options section differs from OPTION_SECTIONS");
+    }
+
+    #[test]
+    fn option_spellings_are_unique() {
+        let mut seen = HashSet::new();
+        for name in documented_options().flat_map(OptionSpec::spellings) {
+            assert!(seen.insert(name), "{} is defined twice", name);
+            assert!(name.starts_with("--") || name.chars().count() == 2, "{} is not an option name", name);
+        }
+    }
+
+    #[test]
+    fn options_parse_through_the_table() {
+        let config = parse_args(&args(&["-aL2", "--sort=size", "--colour", "never", "-P", "*.rs", "dir"])).unwrap();
+        assert!(config.show_hidden && config.show_dot_entries);
+        assert_eq!(config.max_depth, Some(2));
+        assert!(matches!(config.sort_by, SortBy::Size));
+        assert!(matches!(config.color, ColorOption::Never));
+        assert!(config.glob.is_some());
+        assert_eq!(config.root_path, "dir");
+
+        // GNU tree's -h wins over -s in either order.
+        assert!(!parse_args(&args(&["-h", "-s"])).unwrap().raw_sizes);
+        assert!(parse_args(&args(&["-s"])).unwrap().raw_sizes);
+
+        let config = parse_args(&args(&["--exec", "echo", "{}", "+", "-d"])).unwrap();
+        assert!(config.exec.is_some_and(|exec| exec.batch && exec.command == ["echo", "{}"]));
+        assert!(config.dirs_only);
+
+        for (list, error) in [
+            (&["--level"][..], "--level requires a value"),
+            (&["--all=yes"][..], "--all does not take a value"),
+            (&["--exec", "echo"][..], "--exec must be terminated by ';' or '+'"),
+            (&["--bogus"][..], "Unknown option '--bogus' (see tree --help)"),
+            (&["--no-level"][..], "Unknown option '--no-level' (see tree --help)"),
+            (&["--config"][..], "Unknown option '--config' (see tree --help)"),
+        ] {
+            assert_eq!(parse_args(&args(list)).err().map(|e| e.to_string()).as_deref(), Some(error), "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn help_drops_only_code_span_delimiters() {
+        assert_eq!(plain_text("`a` or `` `-- ``"), "a or `--");
+        assert!(help_text().contains("ascii draws |-- and `--\n"));
+        assert!(man_page().contains("\\fB`\\-\\-\\fR"));
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(sha256_hex(&[b""]), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(&[b"abc"]), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // 56 bytes: the padding no longer fits and spills into a second block.
+        assert_eq!(
+            sha256_hex(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"]),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256_hex(&[b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"]),
+            "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"
+        );
+    }
+
+    #[test]
+    fn sha256_incremental_updates_match_one_shot() {
+        let million = vec![b'a'; 1_000_000];
+        let one_shot = sha256_hex(&[&million]);
+        assert_eq!(one_shot, "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+        let pieces: Vec<&[u8]> = million.chunks(63).collect();
+        assert_eq!(sha256_hex(&pieces), one_shot);
+    }
+}